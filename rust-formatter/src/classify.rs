@@ -0,0 +1,158 @@
+// Rough photo / screenshot / document split, used to pick an encoder for
+// "AUTO" output and exposed through `classify_image`.
+//
+// Screenshots are drawn rather than captured: a handful of flat colors,
+// neighbors that repeat exactly and hard edges between them. Photos spread
+// over many shades with soft gradients and sensor noise. Documents are paper:
+// almost colorless, with one light shade covering most of the page.
+
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgb};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Long edge sampled. Nearest-neighbor keeps the source's own colors, where
+// averaging would invent in-between shades along every edge
+const SAMPLE_EDGE: u32 = 256;
+// Bits dropped per channel before counting palette colors, so JPEG noise
+// around a flat fill stays in one bucket
+const PALETTE_SHIFT: u8 = 3;
+// Most common buckets whose coverage measures palette flatness
+const PALETTE_SIZE: usize = 16;
+// Luma step between neighbors that counts as a hard edge
+const HARD_EDGE: i32 = 32;
+// Channel spread below which a pixel counts as colorless, and the luma
+// above which it counts as paper
+const GRAY_SPREAD: u8 = 24;
+const PAPER_LUMA: u8 = 160;
+// Shares of colorless and paper pixels a document needs
+const DOCUMENT_GRAY_SHARE: f32 = 0.9;
+const DOCUMENT_PAPER_SHARE: f32 = 0.5;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    Photo,
+    Screenshot,
+    Document,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct Classification {
+    pub kind: ImageKind,
+    // 0.5 on the fence, 1.0 when every signal agrees
+    pub confidence: f32,
+    // Share of pixels in the PALETTE_SIZE most common color buckets
+    pub palette_share: f32,
+    // Share of neighboring pixel pairs with exactly the same color
+    pub flat_share: f32,
+    // Share of the color changes between neighbors that are hard edges
+    pub hard_edge_share: f32,
+}
+
+pub fn classify(img: &DynamicImage) -> Classification {
+    let sample = if img.width().max(img.height()) > SAMPLE_EDGE {
+        img.resize(SAMPLE_EDGE, SAMPLE_EDGE, FilterType::Nearest).to_rgb8()
+    } else {
+        img.to_rgb8()
+    };
+    let (w, h) = sample.dimensions();
+    let total = (w * h).max(1) as f32;
+
+    let mut buckets: HashMap<[u8; 3], u32> = HashMap::new();
+    let (mut gray, mut paper) = (0u32, 0u32);
+    for pixel in sample.pixels() {
+        *buckets.entry(pixel.0.map(|c| c >> PALETTE_SHIFT)).or_insert(0) += 1;
+        let (max, min) = (pixel.0.iter().max().copied().unwrap_or(0), pixel.0.iter().min().copied().unwrap_or(0));
+        if max - min < GRAY_SPREAD {
+            gray += 1;
+            if luma(pixel) >= PAPER_LUMA as i32 {
+                paper += 1;
+            }
+        }
+    }
+    let mut counts: Vec<u32> = buckets.into_values().collect();
+    counts.sort_unstable_by(|a, b| b.cmp(a));
+    let palette_share = counts.iter().take(PALETTE_SIZE).sum::<u32>() as f32 / total;
+
+    let (mut pairs, mut same, mut changed, mut hard) = (0u32, 0u32, 0u32, 0u32);
+    for (x, y, pixel) in sample.enumerate_pixels() {
+        let neighbors = [(x + 1 < w).then(|| sample.get_pixel(x + 1, y)), (y + 1 < h).then(|| sample.get_pixel(x, y + 1))];
+        for neighbor in neighbors.into_iter().flatten() {
+            pairs += 1;
+            if neighbor == pixel {
+                same += 1;
+            } else {
+                changed += 1;
+                if (luma(neighbor) - luma(pixel)).abs() >= HARD_EDGE {
+                    hard += 1;
+                }
+            }
+        }
+    }
+    let flat_share = same as f32 / pairs.max(1) as f32;
+    let hard_edge_share = hard as f32 / changed.max(1) as f32;
+
+    let (gray_share, paper_share) = (gray as f32 / total, paper as f32 / total);
+    let (kind, confidence) = if gray_share >= DOCUMENT_GRAY_SHARE && paper_share >= DOCUMENT_PAPER_SHARE {
+        let margin = ((gray_share - DOCUMENT_GRAY_SHARE) / (1.0 - DOCUMENT_GRAY_SHARE))
+            .min((paper_share - DOCUMENT_PAPER_SHARE) / (1.0 - DOCUMENT_PAPER_SHARE));
+        (ImageKind::Document, 0.5 + 0.5 * margin)
+    } else {
+        // Each signal runs from 0 (photo-like) to 1 (screenshot-like)
+        let score = (palette_share + flat_share + hard_edge_share) / 3.0;
+        let kind = if score >= 0.5 { ImageKind::Screenshot } else { ImageKind::Photo };
+        (kind, 0.5 + (score - 0.5).abs())
+    };
+    Classification { kind, confidence: confidence.clamp(0.5, 1.0), palette_share, flat_share, hard_edge_share }
+}
+
+fn luma(pixel: &Rgb<u8>) -> i32 {
+    (pixel[0] as i32 * 299 + pixel[1] as i32 * 587 + pixel[2] as i32 * 114) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::photo;
+    use image::RgbImage;
+
+    // App-style mockup: blue title bar, colored buttons and dark text rows
+    // on a pale panel
+    fn screenshot() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(360, 640, |x, y| match (x, y) {
+            (_, 0..=59) => Rgb([33, 99, 230]),
+            (20..=339, 100..=139) | (20..=339, 560..=599) => Rgb([46, 180, 90]),
+            (20..=339, 180..=519) if (y - 180) % 30 < 8 && x % 12 < 9 => Rgb([40, 40, 40]),
+            _ => Rgb([236, 240, 250]),
+        }))
+    }
+
+    // Black text rows on a white page
+    fn document() -> DynamicImage {
+        DynamicImage::ImageLuma8(image::GrayImage::from_fn(600, 800, |x, y| {
+            image::Luma([if (60..540).contains(&x) && y % 40 < 10 && x % 14 < 10 { 20 } else { 250 }])
+        }))
+    }
+
+    #[test]
+    fn photos_screenshots_and_documents_are_told_apart() {
+        let photo = classify(&photo(400, 300));
+        assert_eq!(photo.kind, ImageKind::Photo);
+        assert!(photo.flat_share < 0.2, "{:?}", photo);
+
+        let screenshot = classify(&screenshot());
+        assert_eq!(screenshot.kind, ImageKind::Screenshot);
+        assert!(screenshot.palette_share > 0.99 && screenshot.flat_share > 0.8, "{:?}", screenshot);
+        assert!(screenshot.confidence > 0.8, "{:?}", screenshot);
+
+        assert_eq!(classify(&document()).kind, ImageKind::Document);
+    }
+
+    #[test]
+    fn kinds_serialize_in_lowercase() {
+        let json = serde_json::to_value(classify(&screenshot())).unwrap();
+        assert_eq!(json["kind"], "screenshot");
+        assert!(json["confidence"].as_f64().is_some_and(|confidence| (0.5..=1.0).contains(&confidence)));
+    }
+}
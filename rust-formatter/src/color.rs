@@ -0,0 +1,242 @@
+// Just enough color management for phone photos shot in Display P3.
+//
+// Output never carries an ICC profile, so viewers read it as sRGB; left alone,
+// P3 pixels would look oversaturated. The embedded profile is classified by its
+// colorant primaries and P3 is converted with the standard linear matrix. Other
+// profiles are only reported so the UI can warn.
+
+use image::{DynamicImage, RgbImage, RgbaImage};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColorSpace {
+    Srgb,
+    DisplayP3,
+    Other,
+}
+
+impl ColorSpace {
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::DisplayP3 => "display-p3",
+            ColorSpace::Other => "other",
+        }
+    }
+}
+
+// Red and green colorants (rXYZ, gXYZ) after D50 adaptation, as written by
+// the standard profiles
+const SRGB_COLORANTS: [[f32; 3]; 2] = [[0.4361, 0.2225, 0.0139], [0.3851, 0.7169, 0.0971]];
+const P3_COLORANTS: [[f32; 3]; 2] = [[0.5151, 0.2412, -0.0011], [0.2920, 0.6922, 0.0419]];
+const COLORANT_TOLERANCE: f32 = 0.01;
+
+// Steps in the linear-to-sRGB lookup, fine enough to stay within one level
+const ENCODE_STEPS: usize = 4096;
+
+// Linear Display P3 to linear sRGB; both use D65 and the sRGB transfer curve
+const P3_TO_SRGB: [[f32; 3]; 3] = [
+    [1.224_940_2, -0.224_940_4, 0.0],
+    [-0.042_056_955, 1.042_057_1, 0.0],
+    [-0.019_637_555, -0.078_636_05, 1.098_273_6],
+];
+
+// Untagged images are taken to be sRGB, like every browser does
+pub fn detect(data: &[u8]) -> ColorSpace {
+    match icc_profile(data) {
+        Some(profile) => classify(&profile),
+        None => ColorSpace::Srgb,
+    }
+}
+
+fn classify(profile: &[u8]) -> ColorSpace {
+    let (Some(red), Some(green)) = (xyz_tag(profile, b"rXYZ"), xyz_tag(profile, b"gXYZ")) else {
+        return ColorSpace::Other;
+    };
+    let matches = |expected: &[[f32; 3]; 2]| {
+        [red, green]
+            .iter()
+            .zip(expected)
+            .all(|(actual, expected)| actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < COLORANT_TOLERANCE))
+    };
+    if matches(&SRGB_COLORANTS) {
+        ColorSpace::Srgb
+    } else if matches(&P3_COLORANTS) {
+        ColorSpace::DisplayP3
+    } else {
+        ColorSpace::Other
+    }
+}
+
+// An XYZType tag from the profile's tag table
+fn xyz_tag(profile: &[u8], signature: &[u8; 4]) -> Option<[f32; 3]> {
+    let be32 = |at: usize| profile.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let count = be32(128)? as usize;
+    (0..count.min(256)).find_map(|i| {
+        let entry = 132 + i * 12;
+        if profile.get(entry..entry + 4)? != signature {
+            return None;
+        }
+        let offset = be32(entry + 4)? as usize;
+        if profile.get(offset..offset + 4)? != b"XYZ " {
+            return None;
+        }
+        let fixed = |at: usize| be32(at).map(|v| v as i32 as f32 / 65536.0);
+        Some([fixed(offset + 8)?, fixed(offset + 12)?, fixed(offset + 16)?])
+    })
+}
+
+// The embedded ICC profile of a JPEG (APP2 chunks) or PNG (iCCP)
+pub fn icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_icc_profile(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        png_icc_profile(data)
+    } else {
+        None
+    }
+}
+
+fn jpeg_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(u8, &[u8])> = Vec::new();
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        // Profiles precede the scan data
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let body = data.get(pos + 4..pos + 2 + length)?;
+        if marker == 0xE2 && body.starts_with(b"ICC_PROFILE\0") && body.len() > 14 {
+            chunks.push((body[12], &body[14..]));
+        }
+        pos += 2 + length;
+    }
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|(sequence, _)| *sequence);
+    Some(chunks.into_iter().flat_map(|(_, chunk)| chunk.iter().copied()).collect())
+}
+
+fn png_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let mut pos = 8;
+    while let Some(header) = data.get(pos..pos + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..8] {
+            b"iCCP" => {
+                let body = data.get(pos + 8..pos + 8 + length)?;
+                // Profile name, a NUL, the compression method, then zlib data
+                let name_end = body.iter().position(|&b| b == 0)?;
+                return miniz_oxide::inflate::decompress_to_vec_zlib(body.get(name_end + 2..)?).ok();
+            }
+            b"IDAT" => return None,
+            _ => pos += 12 + length,
+        }
+    }
+    None
+}
+
+// Re-express Display P3 pixels in sRGB, clipping colors outside its gamut.
+// Grayscale images have no gamut to convert
+pub fn p3_to_srgb(img: &DynamicImage) -> DynamicImage {
+    let decode: Vec<f32> = (0..=255).map(|v| srgb_to_linear(v as f32 / 255.0)).collect();
+    let last = (ENCODE_STEPS - 1) as f32;
+    let encode: Vec<u8> = (0..ENCODE_STEPS)
+        .map(|i| (linear_to_srgb(i as f32 / last) * 255.0).round() as u8)
+        .collect();
+    let convert = |pixel: &mut [u8]| {
+        let linear = [decode[pixel[0] as usize], decode[pixel[1] as usize], decode[pixel[2] as usize]];
+        for (channel, row) in pixel.iter_mut().zip(&P3_TO_SRGB) {
+            let value = row[0] * linear[0] + row[1] * linear[1] + row[2] * linear[2];
+            *channel = encode[(value.clamp(0.0, 1.0) * last).round() as usize];
+        }
+    };
+
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLuma16(_) => img.clone(),
+        DynamicImage::ImageRgba8(_) | DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgba16(_) => {
+            let mut buf: RgbaImage = img.to_rgba8();
+            buf.pixels_mut().for_each(|p| convert(&mut p.0[..3]));
+            DynamicImage::ImageRgba8(buf)
+        }
+        _ => {
+            let mut buf: RgbImage = img.to_rgb8();
+            buf.pixels_mut().for_each(|p| convert(&mut p.0));
+            DynamicImage::ImageRgb8(buf)
+        }
+    }
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{exam, jpeg, run, spec};
+    use image::Rgb;
+
+    // ICC profile holding only the red and green colorant tags
+    fn profile(colorants: &[[f32; 3]; 2]) -> Vec<u8> {
+        let mut profile = vec![0u8; 128];
+        profile.extend_from_slice(&2u32.to_be_bytes());
+        for (i, signature) in [b"rXYZ", b"gXYZ"].iter().enumerate() {
+            profile.extend_from_slice(*signature);
+            profile.extend_from_slice(&(156 + i as u32 * 20).to_be_bytes());
+            profile.extend_from_slice(&20u32.to_be_bytes());
+        }
+        for xyz in colorants {
+            profile.extend_from_slice(b"XYZ \0\0\0\0");
+            for value in xyz {
+                profile.extend_from_slice(&((value * 65536.0).round() as i32).to_be_bytes());
+            }
+        }
+        let size = profile.len() as u32;
+        profile[..4].copy_from_slice(&size.to_be_bytes());
+        profile
+    }
+
+    // `jpeg` with `profile` in a single APP2 chunk right after SOI
+    fn tagged(jpeg: Vec<u8>, profile: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, 0xE2];
+        segment.extend_from_slice(&((profile.len() + 16) as u16).to_be_bytes());
+        segment.extend_from_slice(b"ICC_PROFILE\0\x01\x01");
+        segment.extend_from_slice(profile);
+        [&jpeg[..2], &segment, &jpeg[2..]].concat()
+    }
+
+    #[test]
+    fn p3_uploads_are_converted_to_srgb() {
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([200, 60, 40])));
+        let upload = tagged(jpeg(&red, 100), &profile(&P3_COLORANTS));
+        assert_eq!(detect(&upload), ColorSpace::DisplayP3);
+        assert_eq!(detect(&tagged(jpeg(&red, 100), &profile(&SRGB_COLORANTS))), ColorSpace::Srgb);
+        assert_eq!(detect(&jpeg(&red, 100)), ColorSpace::Srgb);
+
+        // P3 (200, 60, 40) through the linear P3-to-sRGB matrix
+        let converted = p3_to_srgb(&red).to_rgb8();
+        let expected = [217, 42, 23];
+        assert!(converted.get_pixel(0, 0).0.iter().zip(expected).all(|(&c, e)| c.abs_diff(e) <= 1), "{:?}", converted.get_pixel(0, 0));
+
+        let output = run(&exam(spec("PNG", 32, 32, 200)), &upload, "photo").unwrap();
+        assert_eq!(output.color_space, "display-p3");
+        // No profile goes out, so viewers read the pixels as the sRGB they now are
+        assert_eq!(icc_profile(&output.data), None);
+        let pixel = image::load_from_memory(&output.data).unwrap().to_rgb8().get_pixel(16, 16).0;
+        assert!(pixel.iter().zip(expected).all(|(&c, e)| c.abs_diff(e) <= 3), "{:?}", pixel);
+    }
+}
@@ -0,0 +1,3339 @@
+// The formatting pipeline as plain Rust: bytes and specs in, an encoded
+// `FormatOutput` out. `DocumentFormatter` is only the JS adapter over it, so
+// the same code runs natively in tests or on a server. Progress goes to a
+// plain Rust callback; the only browser API used is the clock in `now_ms`.
+
+use crate::{classify, color, enhance, face, metadata, png_optimize};
+#[cfg(feature = "watermark")]
+use crate::watermark;
+use crate::{
+    BorderSpec, DocumentFormat, ExamConfig, ExamFormats, FormatError, FormatOverrides, ProcessingOptions,
+    WatermarkSpec, DEFAULT_MAX_MEGAPIXELS, console_debug, console_log,
+};
+use crate::color::ColorSpace;
+use crate::face::{CropWindow, FaceBox};
+use color_quant::NeuQuant;
+use image::{ColorType, DynamicImage, GenericImageView, GrayImage, RgbImage, Rgba, RgbaImage};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+// Share of the face size added on each side of the area `roi_optimize` keeps sharp
+const ROI_FACE_MARGIN: f32 = 0.25;
+
+// Quality range searched when compressing lossy output
+const MIN_JPEG_QUALITY: u8 = 10;
+const MAX_JPEG_QUALITY: u8 = 95;
+// AVIF encodes are slow in WASM, so its quality search moves in coarser steps
+const AVIF_QUALITY_STRIDE: u8 = 10;
+// When the lowest quality is still too large, shrink by this factor per try,
+// giving up once the short side would drop below the minimum
+const DOWNSCALE_STEP: f64 = 0.9;
+const MIN_DOWNSCALE_SIDE: u32 = 16;
+
+// Progress reports: a stage ("decoding", "resizing", "encoding",
+// "compressing", "done"), percent done and, while the size search runs, the
+// quality being tried
+pub type ProgressFn = dyn Fn(&str, f32, Option<u8>);
+
+// Quality of the original thumbnails handed back for previews
+const THUMBNAIL_QUALITY: u8 = 60;
+
+#[derive(Serialize)]
+pub struct FormatOutput {
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub byte_size: u32,
+    pub format: String,
+    pub quality_used: u8,
+    pub was_compressed: bool,
+    pub face_box: Option<FaceBox>,
+    // Area kept sharp by `roi_optimize`, in output pixels
+    pub roi_box: Option<FaceBox>,
+    pub upscaled: bool,
+    // Shrunk below the spec's dimensions to get under the size cap
+    pub downscaled: bool,
+    // Encodes made by the size search, and whether it was cut off
+    pub compress_iterations: u32,
+    pub hit_iteration_limit: bool,
+    pub timings: Timings,
+    pub sha256: Option<String>,
+    pub was_reencoded: bool,
+    // Color space of the upload. Display P3 is converted to sRGB; "other"
+    // profiles are passed through unconverted so the UI can warn
+    pub color_space: String,
+    // Filled from the exam's `filename_template`, when it has one
+    pub suggested_filename: Option<String>,
+    // Frames in the upload; above 1 for animations, 0 for PDFs
+    pub frames_detected: u32,
+    // Returned separately by `format_document_with_preview`
+    #[serde(skip)]
+    pub original_thumbnail: Option<Vec<u8>>,
+    // Achieved SSIM against the resized image, when `target_ssim` is set
+    pub ssim: Option<f32>,
+    // Every pass applied to the pixels, in the order it ran, e.g.
+    // ["exif-rotate-90", "resize 300x400 lanczos3", "jpeg q82", "dpi 300"]
+    pub transforms: Vec<String>,
+}
+
+// Upright, cleaned-up upload before it is fitted to an output size
+#[derive(Clone)]
+pub(crate) struct SourceImage {
+    image: DynamicImage,
+    face_box: Option<FaceBox>,
+    color_space: ColorSpace,
+    frames: u32,
+    original_thumbnail: Option<Vec<u8>>,
+    transforms: Vec<String>,
+}
+
+// Pipeline output just before encoding
+pub(crate) struct PreparedImage {
+    pub(crate) image: DynamicImage,
+    pub(crate) face_box: Option<FaceBox>,
+    // Area `roi_optimize` kept sharp, in output pixels
+    pub(crate) roi_box: Option<FaceBox>,
+    pub(crate) upscaled: bool,
+    pub(crate) color_space: ColorSpace,
+    pub(crate) frames: u32,
+    pub(crate) original_thumbnail: Option<Vec<u8>>,
+    pub(crate) transforms: Vec<String>,
+}
+
+// How the size search went, reported with the result
+#[derive(Default, Clone, Copy)]
+pub(crate) struct CompressStats {
+    iterations: u32,
+    hit_iteration_limit: bool,
+}
+
+impl CompressStats {
+    // Fold in a later search, so the totals cover every encode made
+    fn add(&mut self, other: CompressStats) {
+        self.iterations += other.iterations;
+        self.hit_iteration_limit |= other.hit_iteration_limit;
+    }
+}
+
+// Wall-clock milliseconds spent in each pipeline stage
+#[derive(Serialize, Default, Clone, Copy)]
+pub struct Timings {
+    pub decode_ms: f64,
+    pub resize_ms: f64,
+    pub encode_ms: f64,
+    pub compress_ms: f64,
+    pub total_ms: f64,
+}
+
+// Format `bytes` to a single spec with no exam around it. Every decodable
+// input format is accepted and only the spec's own `max_size` applies
+pub fn format(bytes: &[u8], fmt: &DocumentFormat, doc_type: &str) -> Result<FormatOutput, FormatError> {
+    format_inline(bytes, fmt, ProcessingOptions::new(doc_type, ""))
+}
+
+// `format`, reporting progress to `progress` as it goes
+pub fn format_with_progress(
+    bytes: &[u8],
+    fmt: &DocumentFormat,
+    doc_type: &str,
+    progress: Box<ProgressFn>,
+) -> Result<FormatOutput, FormatError> {
+    format_inline(bytes, fmt, ProcessingOptions { progress: Some(progress), ..ProcessingOptions::new(doc_type, "") })
+}
+
+fn format_inline(bytes: &[u8], fmt: &DocumentFormat, options: ProcessingOptions) -> Result<FormatOutput, FormatError> {
+    let doc_type = options.document_type.as_str();
+    let mut config = ExamConfig {
+        name: "custom".to_string(),
+        code: "custom".to_string(),
+        formats: ExamFormats { photo: fmt.clone(), signature: fmt.clone(), documents: fmt.clone() },
+        max_file_size: u32::MAX / 1024,
+        allowed_formats: input_formats(),
+        document_types: vec![doc_type.to_string()],
+        document_type_formats: HashMap::new(),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    };
+    let mut issues = Vec::new();
+    validate_format(&config, doc_type, fmt, &mut issues);
+    if !issues.is_empty() {
+        return Err(FormatError::ConfigValidation(issues));
+    }
+    // Sizes given in cm become pixels exactly as for a registered config
+    for format in [&mut config.formats.photo, &mut config.formats.signature, &mut config.formats.documents] {
+        apply_physical_size(format);
+    }
+    format_with(&config, bytes, &options)
+}
+
+pub(crate) fn format_with(config: &ExamConfig, file_data: &[u8], options: &ProcessingOptions) -> Result<FormatOutput, FormatError> {
+    let document_type = options.document_type.as_str();
+    let original_name = options.original_name.as_str();
+    let progress = options.progress.as_deref();
+    let started = now_ms();
+    let mut timings = Timings::default();
+    console_log!("Starting document formatting for type: {}", document_type);
+
+    // The exam-wide size limit is a hard ceiling on anything we accept
+    let hard_limit = config.max_file_size as usize * 1024;
+    if file_data.len() > hard_limit {
+        return Err(FormatError::TooLarge(format!(
+            "Input {}KB exceeds {} limit of {}KB",
+            file_data.len() / 1024,
+            config.name,
+            config.max_file_size
+        )));
+    }
+
+    // Determine which format to use based on document type
+    let format_config = resolve_format(config, document_type)?;
+    let overridden = apply_overrides(config, format_config, options.overrides.as_ref())?;
+    let format_config = &*overridden;
+    // "AUTO" keeps the upload's own format where the exam accepts it
+    let resolved = resolve_auto_format(config, format_config, file_data);
+    let format_config = &*resolved;
+
+    console_debug!("Using format config: {}x{} at {} DPI", 
+                format_config.width, format_config.height, format_config.dpi);
+
+    // Handle PDF files differently
+    if original_name.to_lowercase().ends_with(".pdf") && format_config.format == "PDF" {
+        console_log!("Processing PDF file: {}", original_name);
+        let data = process_pdf(file_data, format_config)?;
+        return Ok(FormatOutput {
+            sha256: options.include_hash.then(|| sha256_hex(&data)),
+            byte_size: data.len() as u32,
+            data,
+            width: 0,
+            height: 0,
+            format: "PDF".to_string(),
+            quality_used: 0,
+            was_compressed: false,
+            face_box: None,
+            roi_box: None,
+            upscaled: false,
+            downscaled: false,
+            compress_iterations: 0,
+            hit_iteration_limit: false,
+            timings: Timings { total_ms: now_ms() - started, ..timings },
+            was_reencoded: false,
+            color_space: "unknown".to_string(),
+            suggested_filename: suggested_filename(config, document_type, "PDF"),
+            frames_detected: 0,
+            original_thumbnail: None,
+            ssim: None,
+            transforms: Vec::new(),
+        });
+    }
+
+    // A runtime override replaces the document limit but never the exam-wide ceiling
+    let target_kb = options.target_size_kb.unwrap_or(format_config.max_size);
+    // The target is capped at the exam limit, then shrunk by the safety margin
+    let target_size = with_size_margin((target_kb as usize * 1024).min(hard_limit), format_config);
+
+    let detected_mime = check_allowed_format(config, file_data)?;
+    // Checked here and not only at decode, so a cut-off file is never passed through
+    if is_truncated(file_data) {
+        return Err(FormatError::Truncated);
+    }
+    // Re-encoding a file that already meets the spec only costs quality
+    if let Some((width, height)) = conformant_dimensions(file_data, detected_mime, format_config, options, target_size) {
+        console_log!("Input already meets the {} spec, returning it unchanged", document_type);
+        report_progress(progress, "done", 100.0, None);
+        return Ok(FormatOutput {
+            sha256: options.include_hash.then(|| sha256_hex(file_data)),
+            byte_size: file_data.len() as u32,
+            data: file_data.to_vec(),
+            width,
+            height,
+            format: format_config.format.clone(),
+            quality_used: 0,
+            was_compressed: false,
+            face_box: None,
+            roi_box: None,
+            upscaled: false,
+            downscaled: false,
+            compress_iterations: 0,
+            hit_iteration_limit: false,
+            timings: Timings { total_ms: now_ms() - started, ..timings },
+            was_reencoded: false,
+            color_space: ColorSpace::Srgb.name().to_string(),
+            suggested_filename: suggested_filename(config, document_type, &format_config.format),
+            frames_detected: 1,
+            original_thumbnail: None,
+            ssim: None,
+            transforms: vec!["passthrough".to_string()],
+        });
+    }
+
+    if format_config.format != "PNG" && is_likely_already_compressed(file_data) {
+        console_log!("Warning: input looks like an already compressed JPEG; re-encoding it again loses detail, so use the original if available");
+    }
+
+    let PreparedImage { image: mut resized_img, face_box, mut roi_box, upscaled, color_space, frames, original_thumbnail, mut transforms } =
+        prepare_image(config, format_config, file_data, options, &mut timings)?;
+
+    console_debug!("Resized image to: {}x{} ({})", resized_img.width(), resized_img.height(), format_config.fit_mode);
+    // Convert to the target format and compress
+    report_progress(progress, "encoding", 60.0, None);
+    let mut quality_used = if format_config.format == "PNG" { 0 } else { format_config.quality };
+    let mut was_compressed = false;
+    let mut compress_stats = CompressStats::default();
+    let ssim_target = format_config.target_ssim.filter(|_| {
+        let measurable = decodes_lossy_output(format_config);
+        if !measurable {
+            console_log!("Warning: SSIM cannot be measured for {} output, using quality {}", format_config.format, format_config.quality);
+        }
+        measurable
+    });
+    let mut output_buffer = match ssim_target {
+        Some(threshold) => {
+            let stage_started = now_ms();
+            let (data, quality, stats) = compress_to_target_ssim(&resized_img, target_size, format_config, threshold, progress)?;
+            timings.compress_ms = now_ms() - stage_started;
+            (quality_used, compress_stats, was_compressed) = (quality, stats, true);
+            data
+        }
+        None => {
+            let stage_started = now_ms();
+            let mut output_buffer = encode_image(&resized_img, format_config, format_config.quality)?;
+            timings.encode_ms = now_ms() - stage_started;
+
+            // Check if we need to compress further to meet size requirements
+            if output_buffer.len() > target_size {
+                console_log!("File too large ({}KB), compressing further", output_buffer.len() / 1024);
+                let stage_started = now_ms();
+                (output_buffer, quality_used, compress_stats) = compress_to_target_size(
+                    &resized_img,
+                    target_size,
+                    format_config,
+                    (quality_used, output_buffer),
+                    progress,
+                )?;
+                timings.compress_ms = now_ms() - stage_started;
+                was_compressed = true;
+            }
+            output_buffer
+        }
+    };
+
+    transforms.push(encode_transform(format_config, quality_used));
+
+    // Lossless output that cannot fit may switch to a lossy format; the
+    // result then reports that format
+    let fallback_spec;
+    let format_config = match lossy_fallback_format(config) {
+        Some(fallback) if output_buffer.len() > target_size && format_config.format == "PNG" && format_config.allow_format_fallback => {
+            console_log!("PNG is {}KB, above the {}KB limit; falling back to {}", output_buffer.len() / 1024, target_size / 1024, fallback);
+            fallback_spec = DocumentFormat { format: fallback.to_string(), ..format_config.clone() };
+            let stage_started = now_ms();
+            let initial = encode_image(&resized_img, &fallback_spec, fallback_spec.quality)?;
+            let fallback_stats;
+            (output_buffer, quality_used, fallback_stats) = if initial.len() <= target_size {
+                (initial, fallback_spec.quality, CompressStats::default())
+            } else {
+                compress_to_target_size(&resized_img, target_size, &fallback_spec, (fallback_spec.quality, initial), progress)?
+            };
+            compress_stats.add(fallback_stats);
+            timings.compress_ms += now_ms() - stage_started;
+            transforms.push(format!("fallback {}", fallback_spec.format.to_lowercase()));
+            transforms.push(encode_transform(&fallback_spec, quality_used));
+            &fallback_spec
+        }
+        _ => format_config,
+    };
+
+    // Quality alone could not reach the cap: lossy output is shrunk until
+    // it fits rather than handed back oversized
+    let mut downscaled = false;
+    if output_buffer.len() > target_size && format_config.format != "PNG" {
+        let stage_started = now_ms();
+        let full_width = resized_img.width();
+        (resized_img, output_buffer, quality_used, compress_stats) =
+            downscale_to_target_size(&resized_img, target_size, format_config, compress_stats, progress)?;
+        roi_box = roi_box.map(|area| scale_box(area, resized_img.width() as f32 / full_width as f32));
+        timings.compress_ms += now_ms() - stage_started;
+        downscaled = true;
+        transforms.push(format!("downscale {}x{}", resized_img.width(), resized_img.height()));
+        transforms.push(encode_transform(format_config, quality_used));
+    }
+    let ssim = match ssim_target {
+        Some(_) => Some(encoded_ssim(&resized_img, &output_buffer)?),
+        None => None,
+    };
+    if matches!(format_config.format.as_str(), "JPEG" | "PDF" | "PNG") {
+        transforms.push(format!("dpi {}", format_config.dpi));
+    }
+
+    // Source EXIF (GPS, device details) is dropped unless explicitly kept
+    if format_config.preserve_metadata {
+        console_log!("Carrying over source metadata");
+        output_buffer = metadata::carry_over_exif(file_data, output_buffer, &format_config.format);
+    }
+
+    if let Some(min_kb) = format_config.min_size {
+        let min_bytes = (min_kb as usize * 1024).min(target_size);
+        if output_buffer.len() < min_bytes {
+            console_log!("Output {}KB is under the {}KB minimum, padding", output_buffer.len() / 1024, min_kb);
+            output_buffer = pad_to_min_size(output_buffer, &format_config.format, min_bytes);
+        }
+    }
+
+    if output_buffer.len() > hard_limit {
+        return Err(FormatError::TooLarge(format!(
+            "Output {}KB exceeds {} limit of {}KB even after compression",
+            output_buffer.len() / 1024,
+            config.name,
+            config.max_file_size
+        )));
+    }
+    if output_buffer.len() > target_size {
+        console_log!(
+            "Warning: output {}KB is above the {}KB document limit but within the {}KB exam limit",
+            output_buffer.len() / 1024,
+            target_kb,
+            config.max_file_size
+        );
+    }
+
+    report_progress(progress, "done", 100.0, None);
+    console_log!("Document formatted successfully. Final size: {}KB", output_buffer.len() / 1024);
+    Ok(FormatOutput {
+        sha256: options.include_hash.then(|| sha256_hex(&output_buffer)),
+        byte_size: output_buffer.len() as u32,
+        data: output_buffer,
+        width: resized_img.width(),
+        height: resized_img.height(),
+        format: format_config.format.clone(),
+        quality_used,
+        was_compressed,
+        face_box,
+        roi_box,
+        upscaled,
+        downscaled,
+        compress_iterations: compress_stats.iterations,
+        hit_iteration_limit: compress_stats.hit_iteration_limit,
+        timings: Timings { total_ms: now_ms() - started, ..timings },
+        was_reencoded: true,
+        color_space: color_space.name().to_string(),
+        suggested_filename: suggested_filename(config, document_type, &format_config.format),
+        frames_detected: frames,
+        original_thumbnail,
+        ssim,
+        transforms,
+    })
+}
+
+// Decode and run every pixel pass, up to but not including encoding
+pub(crate) fn prepare_image(
+    config: &ExamConfig,
+    format_config: &DocumentFormat,
+    file_data: &[u8],
+    options: &ProcessingOptions,
+    timings: &mut Timings,
+) -> Result<PreparedImage, FormatError> {
+    let source = prepare_source(config, format_config, file_data, options, timings)?;
+    finish_image(source, format_config, options, timings)
+}
+
+// Decode and apply every pass that does not depend on the output size
+pub(crate) fn prepare_source(
+    config: &ExamConfig,
+    format_config: &DocumentFormat,
+    file_data: &[u8],
+    options: &ProcessingOptions,
+    timings: &mut Timings,
+) -> Result<SourceImage, FormatError> {
+    let document_type = options.document_type.as_str();
+    let progress = options.progress.as_deref();
+
+    // Reject formats the exam portal won't accept before spending time decoding
+    let detected_mime = check_allowed_format(config, file_data)?;
+    console_debug!("Detected input format: {}", detected_mime);
+
+    // Load and process the image
+    report_progress(progress, "decoding", 0.0, None);
+    let stage_started = now_ms();
+    let (img, frames) = if detected_mime == "image/gif" {
+        decode_gif(file_data, config.max_megapixels, config.allow_animated)?
+    } else {
+        let img = match decode_target(file_data, format_config, options) {
+            Some(min_size) => decode_image_at_least(file_data, config.max_megapixels, min_size)?,
+            None => decode_image(file_data, config.max_megapixels)?,
+        };
+        (img, 1)
+    };
+    timings.decode_ms = now_ms() - stage_started;
+    if detected_mime == "image/tiff" && is_multi_page_tiff(file_data) {
+        console_log!("Warning: multi-page TIFF, only the first page is used");
+    }
+
+    // The output carries no ICC profile, so wide-gamut pixels must already be sRGB
+    let color_space = color::detect(file_data);
+    let mut transforms = Vec::new();
+    let img = match color_space {
+        ColorSpace::DisplayP3 => {
+            console_log!("Converting Display P3 input to sRGB");
+            transforms.push("p3-to-srgb".to_string());
+            color::p3_to_srgb(&img)
+        }
+        ColorSpace::Other => {
+            console_log!("Warning: input has a non-sRGB color profile that will be dropped");
+            img
+        }
+        ColorSpace::Srgb => img,
+    };
+
+    console_debug!("Original image dimensions: {}x{}", img.width(), img.height());
+    // The density describes the stored pixel grid, so even it out before
+    // the orientation turns it
+    let img = if format_config.correct_pixel_aspect {
+        let stored = img.dimensions();
+        let squared = square_pixels(img, input_density(file_data), format_config)?;
+        if squared.dimensions() != stored {
+            transforms.push(format!("square-pixels {}x{}", squared.width(), squared.height()));
+        }
+        squared
+    } else {
+        img
+    };
+    // Phone cameras store unrotated pixels plus an orientation tag; bake it in
+    if let Some(name) = read_exif_orientation(file_data).and_then(exif_transform) {
+        transforms.push(name.to_string());
+    }
+    let img = apply_exif_orientation(img, file_data);
+    // Taken before any edits so reviewers see the upload as it was
+    let original_thumbnail = match options.preview_edge {
+        Some(max_edge) => Some(thumbnail_jpeg(&img, max_edge)?),
+        None => None,
+    };
+    let img = rotate_image(img, options.rotate_degrees, format_config)?;
+    if options.rotate_degrees.rem_euclid(360.0) != 0.0 {
+        transforms.push(format!("rotate {}", options.rotate_degrees.rem_euclid(360.0)));
+    }
+
+    // Straighten skewed scans before anything is cropped away
+    let img = if document_type != "photo" && !is_ink_document(document_type) && format_config.auto_deskew {
+        let skew = enhance::detect_skew(&img);
+        if skew.abs() >= 0.1 {
+            console_log!("Deskewing document by {:.1} degrees", skew);
+            transforms.push(format!("deskew {:.1}", -skew));
+            enhance::rotate(&img, -skew, background_color(&img, format_config)?, false)
+        } else {
+            img
+        }
+    } else {
+        img
+    };
+
+    // Passport photos want the face centered, so crop around it before resizing
+    let mut face_box = None;
+    let img = if document_type == "photo" && format_config.face_center {
+        let (cropped, detected, window) = crop_to_face(&img, format_config);
+        face_box = detected;
+        transforms.push(format!("crop {},{},{},{}", window.x, window.y, window.width, window.height));
+        cropped
+    } else {
+        img
+    };
+
+    // Whiten the paper and darken the ink at full resolution so the
+    // downscale smooths stroke edges instead of blurring a muddy scan
+    let img = if is_ink_document(document_type) && format_config.signature_cleanup {
+        console_log!("Cleaning up {} background", document_type);
+        transforms.push("signature-cleanup".to_string());
+        enhance::clean_signature(&img)
+    } else {
+        img
+    };
+
+    // Wide paper margins would otherwise shrink the strokes to a sliver
+    let img = if is_ink_document(document_type) && format_config.auto_trim {
+        match enhance::trim_to_content(&img) {
+            Some(trimmed) => {
+                console_log!("Trimmed {} from {}x{} to {}x{}", document_type, img.width(), img.height(), trimmed.width(), trimmed.height());
+                transforms.push(format!("trim {}x{}", trimmed.width(), trimmed.height()));
+                trimmed
+            }
+            None => img,
+        }
+    } else {
+        img
+    };
+
+    Ok(SourceImage { image: img, face_box, color_space, frames, original_thumbnail, transforms })
+}
+
+// Fit the shared source into the spec's box and run the output passes
+pub(crate) fn finish_image(
+    source: SourceImage,
+    format_config: &DocumentFormat,
+    options: &ProcessingOptions,
+    timings: &mut Timings,
+) -> Result<PreparedImage, FormatError> {
+    let document_type = options.document_type.as_str();
+    let progress = options.progress.as_deref();
+    let SourceImage { image: img, face_box, color_space, frames, original_thumbnail, mut transforms } = source;
+
+    // Upscaling a small source only adds blur, so flag it and optionally refuse
+    let upscaled = needs_upscale(img.dimensions(), format_config);
+    if upscaled {
+        if !format_config.allow_upscale {
+            return Err(FormatError::UpscaleBlocked {
+                width: img.width(),
+                height: img.height(),
+                target_width: format_config.width,
+                target_height: format_config.height,
+            });
+        }
+        console_log!("Warning: upscaling {}x{} source to fit target", img.width(), img.height());
+    }
+
+    // Resize the image into the target box according to the fit mode
+    report_progress(progress, "resizing", 30.0, None);
+    let stage_started = now_ms();
+    let resized_img = match &format_config.border {
+        Some(border) => resize_with_border(&img, format_config, border)?,
+        None => resize_to_fit(&img, format_config)?,
+    };
+    timings.resize_ms = now_ms() - stage_started;
+    transforms.push(format!("resize {}x{} {} {}", resized_img.width(), resized_img.height(), format_config.fit_mode, format_config.filter));
+    if let Some(border) = &format_config.border {
+        transforms.push(format!("border {}px {}", border.width_px, border.color));
+    }
+
+    // Win back the fine detail a large downscale softens
+    let resized_img = if format_config.sharpen > 0.0 {
+        console_log!("Sharpening with amount {}", format_config.sharpen);
+        transforms.push(format!("sharpen {}", format_config.sharpen));
+        enhance::sharpen(&resized_img, format_config.sharpen)
+    } else {
+        resized_img
+    };
+
+    // A slightly soft background compresses much smaller; the margin keeps
+    // hair and chin in the sharp area
+    let mut roi_box = None;
+    let resized_img = if document_type == "photo" && format_config.roi_optimize {
+        match face::detect_largest_face(&resized_img) {
+            Some(found) => {
+                let area = expand_box(found, ROI_FACE_MARGIN, resized_img.width(), resized_img.height());
+                console_log!("Softening the background around the face at ({}, {}) size {}x{}", area.x, area.y, area.width, area.height);
+                transforms.push(format!("roi-soften {},{},{},{}", area.x, area.y, area.width, area.height));
+                roi_box = Some(area);
+                enhance::soften_outside(&resized_img, (area.x, area.y, area.width, area.height))
+            }
+            None => {
+                console_log!("Warning: no face detected, encoding the photo uniformly");
+                resized_img
+            }
+        }
+    } else {
+        resized_img
+    };
+
+    // A single-channel buffer makes the encoders emit true grayscale output
+    // (one-component JFIF, grayscale PNG) rather than gray RGB pixels.
+    // `to_luma8` applies Rec. 709 luminance weights, not a channel average
+    let resized_img = if format_config.grayscale {
+        console_log!("Converting to grayscale");
+        transforms.push("grayscale".to_string());
+        DynamicImage::ImageLuma8(resized_img.to_luma8())
+    } else {
+        resized_img
+    };
+
+    // Runs after the grayscale conversion so gray documents are stretched
+    // on luminance rather than per channel
+    let resized_img = if format_config.auto_contrast {
+        console_log!("Stretching contrast");
+        transforms.push("auto-contrast".to_string());
+        enhance::auto_contrast(&resized_img)
+    } else {
+        resized_img
+    };
+
+    let resized_img = match &options.watermark {
+        Some(spec) if document_type != "photo" && !is_ink_document(document_type) => {
+            transforms.push("watermark".to_string());
+            apply_watermark(&resized_img, spec)?
+        }
+        _ => resized_img,
+    };
+
+    // Last, so no later pass touches the transparent surround
+    let resized_img = match format_config.crop_shape.as_str() {
+        "circle" | "oval" if format_config.format != "PNG" => {
+            return Err(FormatError::InvalidConfig(format!(
+                "A {} crop needs PNG output for transparency; {} has no alpha channel",
+                format_config.crop_shape, format_config.format
+            )))
+        }
+        "circle" | "oval" => {
+            console_log!("Masking to a {} crop", format_config.crop_shape);
+            transforms.push(format!("mask {}", format_config.crop_shape));
+            enhance::mask_ellipse(&resized_img, format_config.crop_shape == "circle")
+        }
+        _ => resized_img,
+    };
+
+    Ok(PreparedImage { image: resized_img, face_box, roi_box, upscaled, color_space, frames, original_thumbnail, transforms })
+}
+
+// Grow `area` by `margin` of its size on every side, within the image
+fn expand_box(area: FaceBox, margin: f32, width: u32, height: u32) -> FaceBox {
+    let grow_x = (area.width as f32 * margin).round() as u32;
+    let grow_y = (area.height as f32 * margin).round() as u32;
+    let (x, y) = (area.x.saturating_sub(grow_x), area.y.saturating_sub(grow_y));
+    FaceBox {
+        x,
+        y,
+        width: (area.x + area.width + grow_x).min(width) - x,
+        height: (area.y + area.height + grow_y).min(height) - y,
+    }
+}
+
+fn scale_box(area: FaceBox, scale: f32) -> FaceBox {
+    let scaled = |v: u32| (v as f32 * scale).round() as u32;
+    FaceBox { x: scaled(area.x), y: scaled(area.y), width: scaled(area.width), height: scaled(area.height) }
+}
+
+pub(crate) fn thumbnail_jpeg(img: &DynamicImage, max_edge: u32) -> Result<Vec<u8>, FormatError> {
+    let thumbnail = if img.width() > max_edge || img.height() > max_edge {
+        Cow::Owned(img.resize(max_edge, max_edge, image::imageops::FilterType::Triangle))
+    } else {
+        Cow::Borrowed(img)
+    };
+    encode_jpeg(&thumbnail, THUMBNAIL_QUALITY, &DocumentFormat::default())
+}
+
+pub(crate) fn check_allowed_format(config: &ExamConfig, file_data: &[u8]) -> Result<&'static str, FormatError> {
+    let mime = detect_mime_type(file_data)
+        .ok_or(FormatError::UnrecognizedFormat)?;
+
+    // There is no HEVC or AV1 decoder that builds for WASM without a native
+    // library (see the README), so explain how to get a usable file instead
+    // of failing later with a generic decode error
+    match mime {
+        "image/heic" | "image/heif" => {
+            return Err(FormatError::UnsupportedInput(
+                "HEIC/HEIF photos cannot be processed yet. Please upload a JPEG or PNG \
+                 (on iPhone: Settings > Camera > Formats > Most Compatible)"
+                    .to_string(),
+            ))
+        }
+        "image/avif" => {
+            return Err(FormatError::UnsupportedInput(
+                "AVIF photos cannot be processed yet. Please upload a JPEG or PNG".to_string(),
+            ))
+        }
+        "image/heic-sequence" | "image/heif-sequence" => {
+            return Err(FormatError::UnsupportedInput(
+                "HEIC image sequences (bursts, Live Photos) are not supported. Please export a single photo as JPEG"
+                    .to_string(),
+            ))
+        }
+        _ => {}
+    }
+
+    if !config.allowed_formats.iter().any(|allowed| allowed == mime) {
+        return Err(FormatError::FormatNotAllowed {
+            format: mime.to_string(),
+            exam: config.name.clone(),
+            allowed: config.allowed_formats.clone(),
+        });
+    }
+    Ok(mime)
+}
+
+// The re-encoded output carries no EXIF, so the tag is dropped once applied
+pub(crate) fn apply_exif_orientation(img: DynamicImage, file_data: &[u8]) -> DynamicImage {
+    let orientation = read_exif_orientation(file_data).unwrap_or(1);
+    if orientation != 1 {
+        console_debug!("Applying EXIF orientation {}", orientation);
+    }
+
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+// Quarter turns are lossless; any other angle is resampled onto a canvas
+// large enough to keep every corner, padded with the background color
+fn rotate_image(img: DynamicImage, degrees: f32, format_config: &DocumentFormat) -> Result<DynamicImage, FormatError> {
+    let degrees = degrees.rem_euclid(360.0);
+    if degrees != 0.0 {
+        console_log!("Rotating image by {} degrees", degrees);
+    }
+
+    match degrees {
+        0.0 => Ok(img),
+        90.0 => Ok(img.rotate90()),
+        180.0 => Ok(img.rotate180()),
+        270.0 => Ok(img.rotate270()),
+        d => Ok(enhance::rotate(&img, d, background_color(&img, format_config)?, true)),
+    }
+}
+
+fn crop_to_face(img: &DynamicImage, format_config: &DocumentFormat) -> (DynamicImage, Option<FaceBox>, CropWindow) {
+    let (src_w, src_h) = img.dimensions();
+    let detected = face::detect_largest_face(img);
+
+    let window = match &detected {
+        Some(face_box) => {
+            console_debug!("Detected face at ({}, {}) size {}x{}", face_box.x, face_box.y, face_box.width, face_box.height);
+            face::face_crop_window(face_box, src_w, src_h, format_config.width, format_config.height)
+        }
+        None => {
+            console_log!("Warning: no face detected, falling back to center crop");
+            face::center_crop_window(src_w, src_h, format_config.width, format_config.height)
+        }
+    };
+
+    console_debug!("Cropping photo to ({}, {}) size {}x{}", window.x, window.y, window.width, window.height);
+    (img.crop_imm(window.x, window.y, window.width, window.height), detected, window)
+}
+
+fn resize_with_border(
+    img: &DynamicImage,
+    format_config: &DocumentFormat,
+    border: &BorderSpec,
+) -> Result<DynamicImage, FormatError> {
+    let color = parse_hex_color(&border.color)?;
+    let frame = border.width_px;
+
+    let resized = if border.inset {
+        if frame.saturating_mul(2) >= format_config.width.min(format_config.height) {
+            return Err(FormatError::InvalidConfig(format!(
+                "Border of {}px leaves no room inside a {}x{} image",
+                frame, format_config.width, format_config.height
+            )));
+        }
+        resize_to(img, format_config, format_config.width - frame * 2, format_config.height - frame * 2)?
+    } else {
+        resize_to_fit(img, format_config)?
+    };
+
+    let framed = |side: u32| frame.checked_mul(2).and_then(|both| side.checked_add(both));
+    if framed(resized.width()).zip(framed(resized.height())).is_none() {
+        return Err(FormatError::InvalidConfig(format!("Border of {}px is too wide", frame)));
+    }
+    console_log!("Adding {}px {} border", frame, border.color);
+    Ok(enhance::add_border(&resized, frame, color))
+}
+
+fn resize_to_fit(img: &DynamicImage, format_config: &DocumentFormat) -> Result<DynamicImage, FormatError> {
+    resize_to(img, format_config, format_config.width, format_config.height)
+}
+
+fn resize_to(
+    img: &DynamicImage,
+    format_config: &DocumentFormat,
+    target_w: u32,
+    target_h: u32,
+) -> Result<DynamicImage, FormatError> {
+    let (src_w, src_h) = img.dimensions();
+    let filter = parse_filter(&format_config.filter)?;
+
+    // Only shrink to fit inside the box; small scans keep their native
+    // resolution, so the output can be smaller than the target
+    if format_config.downscale_only {
+        let scale = f64::min(target_w as f64 / src_w as f64, target_h as f64 / src_h as f64);
+        if scale >= 1.0 {
+            return Ok(img.clone());
+        }
+        let new_w = ((src_w as f64 * scale).round() as u32).clamp(1, target_w);
+        let new_h = ((src_h as f64 * scale).round() as u32).clamp(1, target_h);
+        return Ok(enhance::resize_exact(img, new_w, new_h, filter, format_config.assume_premultiplied));
+    }
+
+    // Matching aspect ratios never need padding or cropping, whatever the mode
+    let same_aspect = src_w as u64 * target_h as u64 == src_h as u64 * target_w as u64;
+
+    // Stretching a landscape photo into a tall box squashes it beyond use
+    let skew = (src_w as f64 * target_h as f64) / (src_h as f64 * target_w as f64);
+    let fit_mode = if format_config.fit_mode == "stretch" && skew.max(1.0 / skew) > format_config.max_aspect_skew as f64 {
+        if !format_config.auto_fit_on_skew {
+            return Err(FormatError::AspectMismatch {
+                width: src_w,
+                height: src_h,
+                target_width: target_w,
+                target_height: target_h,
+            });
+        }
+        console_log!("Warning: {}x{} is too far from the {}x{} shape to stretch, using contain", src_w, src_h, target_w, target_h);
+        "contain"
+    } else {
+        format_config.fit_mode.as_str()
+    };
+
+    match fit_mode {
+        "stretch" => Ok(enhance::resize_exact(img, target_w, target_h, filter, format_config.assume_premultiplied)),
+        _ if same_aspect => Ok(enhance::resize_exact(img, target_w, target_h, filter, format_config.assume_premultiplied)),
+        "contain" => {
+            let scale = f64::min(target_w as f64 / src_w as f64, target_h as f64 / src_h as f64);
+            let new_w = ((src_w as f64 * scale).round() as u32).max(1).min(target_w);
+            let new_h = ((src_h as f64 * scale).round() as u32).max(1).min(target_h);
+            let scaled = enhance::resize_exact(img, new_w, new_h, filter, format_config.assume_premultiplied);
+
+            let background = background_color(img, format_config)?;
+            let mut canvas = RgbaImage::from_pixel(target_w, target_h, background);
+            let offset_x = (target_w - new_w) / 2;
+            let offset_y = (target_h - new_h) / 2;
+            image::imageops::overlay(&mut canvas, &scaled.to_rgba8(), offset_x as i64, offset_y as i64);
+
+            console_debug!("Letterboxed {}x{} image at offset ({}, {})", new_w, new_h, offset_x, offset_y);
+            Ok(DynamicImage::ImageRgba8(canvas))
+        }
+        "cover" => {
+            let scale = f64::max(target_w as f64 / src_w as f64, target_h as f64 / src_h as f64);
+            let new_w = ((src_w as f64 * scale).round() as u32).max(target_w);
+            let new_h = ((src_h as f64 * scale).round() as u32).max(target_h);
+            let scaled = enhance::resize_exact(img, new_w, new_h, filter, format_config.assume_premultiplied);
+
+            let offset_x = (new_w - target_w) / 2;
+            let offset_y = (new_h - target_h) / 2;
+            console_debug!("Cropping {}x{} image at offset ({}, {})", new_w, new_h, offset_x, offset_y);
+            Ok(scaled.crop_imm(offset_x, offset_y, target_w, target_h))
+        }
+        other => Err(FormatError::InvalidConfig(format!(
+            "Unknown fit mode '{}'. Expected one of: stretch, contain, cover",
+            other
+        ))),
+    }
+}
+
+fn process_pdf(file_data: &[u8], format_config: &DocumentFormat) -> Result<Vec<u8>, FormatError> {
+    console_log!("Processing PDF document");
+    
+    // For PDF files, we'll just check the size and return as-is if within limits
+    // In a more advanced implementation, you could use a PDF library to resize/compress
+    let target_size = format_config.max_size * 1024;
+    
+    if file_data.len() <= target_size as usize {
+        console_debug!("PDF size is within limits: {}KB", file_data.len() / 1024);
+        Ok(file_data.to_vec())
+    } else {
+        console_debug!("PDF too large: {}KB, target: {}KB", file_data.len() / 1024, format_config.max_size);
+        Err(FormatError::TooLarge(format!(
+            "PDF file is too large ({}KB). Maximum allowed size is {}KB. Please compress the PDF manually.",
+            file_data.len() / 1024,
+            format_config.max_size
+        )))
+    }
+}
+
+// Qualities passed to `encode_image` on this thread, so tests can count encodes
+#[cfg(test)]
+thread_local! {
+    static ENCODE_LOG: std::cell::RefCell<Vec<u8>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+// Encode in the spec's format with the density tag set so portals that
+// check DPI accept the file. `quality` overrides the spec's own
+pub(crate) fn encode_image(img: &DynamicImage, spec: &DocumentFormat, quality: u8) -> Result<Vec<u8>, FormatError> {
+    #[cfg(test)]
+    ENCODE_LOG.with(|log| log.borrow_mut().push(quality));
+    match spec.format.as_str() {
+        "PNG" => encode_png(img, spec),
+        "WEBP" => encode_webp(img, quality),
+        "AVIF" => encode_avif(img, quality),
+        // Images uploaded for a PDF document are delivered as JPEG
+        "JPEG" | "PDF" => encode_jpeg(img, quality, spec),
+        other if spec.strict_format => Err(FormatError::UnknownOutputFormat(other.to_string())),
+        other => {
+            console_log!("Warning: unknown output format '{}', encoding as JPEG", other);
+            encode_jpeg(img, quality, spec)
+        }
+    }
+}
+
+// WebP has no density field outside EXIF, so the DPI is not recorded
+#[cfg(feature = "webp")]
+fn encode_webp(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, FormatError> {
+    let (width, height) = img.dimensions();
+    let memory = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        webp::Encoder::from_rgba(rgba.as_raw(), width, height).encode(quality as f32)
+    } else {
+        let rgb = img.to_rgb8();
+        webp::Encoder::from_rgb(rgb.as_raw(), width, height).encode(quality as f32)
+    };
+    Ok(memory.to_vec())
+}
+
+#[cfg(not(feature = "webp"))]
+fn encode_webp(_img: &DynamicImage, _quality: u8) -> Result<Vec<u8>, FormatError> {
+    Err(FormatError::UnsupportedOutputFormat("WebP output is not enabled in this build".to_string()))
+}
+
+// Like WebP, AVIF has no density field outside EXIF. Speed 8 of 10 trades a
+// little size for encode times that stay usable in a browser
+#[cfg(feature = "avif")]
+fn encode_avif(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, FormatError> {
+    let (width, height) = img.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    let encoder = ravif::Encoder::new().with_quality(quality as f32).with_speed(8);
+    let encoded = if img.color().has_alpha() {
+        let pixels: Vec<ravif::RGBA8> = img.to_rgba8().pixels().map(|p| ravif::RGBA8::new(p[0], p[1], p[2], p[3])).collect();
+        encoder.encode_rgba(ravif::Img::new(pixels.as_slice(), width, height))
+    } else {
+        let pixels: Vec<ravif::RGB8> = img.to_rgb8().pixels().map(|p| ravif::RGB8::new(p[0], p[1], p[2])).collect();
+        encoder.encode_rgb(ravif::Img::new(pixels.as_slice(), width, height))
+    };
+    encoded
+        .map(|image| image.avif_file)
+        .map_err(|e| FormatError::EncodeFailed(e.to_string()))
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_img: &DynamicImage, _quality: u8) -> Result<Vec<u8>, FormatError> {
+    Err(FormatError::UnsupportedOutputFormat("AVIF output is not enabled in this build".to_string()))
+}
+
+fn encode_jpeg(img: &DynamicImage, quality: u8, spec: &DocumentFormat) -> Result<Vec<u8>, FormatError> {
+    let (width, height) = match (u16::try_from(img.width()), u16::try_from(img.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => {
+            return Err(FormatError::EncodeFailed(format!(
+                "{}x{} exceeds the JPEG limit of 65535 pixels per side",
+                img.width(),
+                img.height()
+            )))
+        }
+    };
+
+    let mut output_buffer = Vec::with_capacity(estimated_encoded_size(img.width(), img.height()));
+    let mut encoder = jpeg_encoder::Encoder::new(&mut output_buffer, quality);
+    encoder.set_density(jpeg_encoder::PixelDensity::dpi(spec.dpi.min(u16::MAX as u32) as u16));
+    encoder.set_sampling_factor(parse_chroma_subsampling(&spec.chroma_subsampling)?);
+    // JPEG has no alpha; composite explicitly rather than let the color
+    // values under transparent pixels (often black) show through
+    let img = if img.color().has_alpha() {
+        Cow::Owned(enhance::flatten(img, parse_hex_color(&spec.flatten_background)?, spec.assume_premultiplied))
+    } else {
+        Cow::Borrowed(img)
+    };
+    // The baseline encoder only takes 8-bit luma or RGB samples
+    let (img, color_type) = match img.color() {
+        ColorType::L8 => (img, jpeg_encoder::ColorType::Luma),
+        ColorType::Rgb8 => (img, jpeg_encoder::ColorType::Rgb),
+        _ => (Cow::Owned(DynamicImage::ImageRgb8(img.to_rgb8())), jpeg_encoder::ColorType::Rgb),
+    };
+    encoder.encode(img.as_bytes(), width, height, color_type)
+        .map_err(|e| FormatError::EncodeFailed(e.to_string()))?;
+    Ok(output_buffer)
+}
+
+pub(crate) fn encode_png(img: &DynamicImage, spec: &DocumentFormat) -> Result<Vec<u8>, FormatError> {
+    Ok(optimize_png(encode_png_with(img, spec, png::Compression::Default)?, spec))
+}
+
+fn optimize_png(data: Vec<u8>, spec: &DocumentFormat) -> Vec<u8> {
+    if !spec.png_optimize {
+        return data;
+    }
+    let optimized = png_optimize::optimize(&data);
+    console_debug!("Optimized PNG from {}KB to {}KB", data.len() / 1024, optimized.len() / 1024);
+    optimized
+}
+
+// Write the PNG color type the spec asks for; only "auto" and "rgb" are
+// guaranteed lossless for color input
+fn encode_png_with(img: &DynamicImage, spec: &DocumentFormat, compression: png::Compression) -> Result<Vec<u8>, FormatError> {
+    use png::{BitDepth, ColorType as PngColor};
+    let (width, height, dpi) = (img.width(), img.height(), spec.dpi);
+    let eight_bit = |color_type: PngColor, data: &[u8]| {
+        write_png(width, height, (color_type, BitDepth::Eight), data, None, dpi, compression)
+    };
+    let alpha = img.color().has_alpha();
+
+    match spec.png_color_type.as_str() {
+        "grayscale" if alpha => eight_bit(PngColor::GrayscaleAlpha, img.to_luma_alpha8().as_raw()),
+        "grayscale" => eight_bit(PngColor::Grayscale, img.to_luma8().as_raw()),
+        "bilevel" => {
+            let luma = img.to_luma8();
+            let bilevel = enhance::dither_bilevel(&luma, enhance::otsu_threshold(&luma), &spec.dither);
+            write_bilevel_png(&bilevel, 128, dpi, compression)
+        }
+        "palette" => {
+            let rgba = img.to_rgba8();
+            match exact_palette(&rgba) {
+                Some((palette, indices)) => write_indexed_png(&rgba, &palette, &indices, dpi, compression),
+                None => encode_indexed_png(img, 256, dpi),
+            }
+        }
+        "rgb" if alpha => eight_bit(PngColor::Rgba, img.to_rgba8().as_raw()),
+        "rgb" => eight_bit(PngColor::Rgb, img.to_rgb8().as_raw()),
+        _ => {
+            let rgba = img.to_rgba8();
+            let opaque = rgba.pixels().all(|p| p[3] == 255);
+            let gray = rgba.pixels().all(|p| p[0] == p[1] && p[1] == p[2]);
+            if gray && opaque && rgba.pixels().all(|p| p[0] == 0 || p[0] == 255) {
+                write_bilevel_png(&img.to_luma8(), 128, dpi, compression)
+            } else if gray && opaque {
+                eight_bit(PngColor::Grayscale, img.to_luma8().as_raw())
+            } else if gray {
+                eight_bit(PngColor::GrayscaleAlpha, img.to_luma_alpha8().as_raw())
+            } else if let Some((palette, indices)) = exact_palette(&rgba) {
+                write_indexed_png(&rgba, &palette, &indices, dpi, compression)
+            } else if opaque {
+                eight_bit(PngColor::Rgb, img.to_rgb8().as_raw())
+            } else {
+                eight_bit(PngColor::Rgba, rgba.as_raw())
+            }
+        }
+    }
+}
+
+// Quantize to at most `colors` palette entries and encode as an indexed PNG
+fn encode_indexed_png(img: &DynamicImage, colors: usize, dpi: u32) -> Result<Vec<u8>, FormatError> {
+    let rgba = img.to_rgba8();
+    let quantizer = NeuQuant::new(10, colors, rgba.as_raw());
+    let indices: Vec<u8> = rgba
+        .as_raw()
+        .chunks_exact(4)
+        .map(|pixel| quantizer.index_of(pixel) as u8)
+        .collect();
+
+    let color_map = quantizer.color_map_rgba();
+    let palette: Vec<u8> = color_map.chunks_exact(4).flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let alpha: Vec<u8> = color_map.chunks_exact(4).map(|c| c[3]).collect();
+    let transparency = if alpha.iter().any(|&a| a < 255) { Some(alpha.as_slice()) } else { None };
+
+    write_png(
+        img.width(),
+        img.height(),
+        (png::ColorType::Indexed, png::BitDepth::Eight),
+        &indices,
+        Some((&palette, transparency)),
+        dpi,
+        png::Compression::Best,
+    )
+}
+
+fn compress_png_to_target_size(
+    img: &DynamicImage,
+    target_size: usize,
+    spec: &DocumentFormat,
+    progress: Option<&ProgressFn>,
+) -> Result<(Vec<u8>, CompressStats), FormatError> {
+    let dpi = spec.dpi;
+    console_log!("Compressing PNG to target size: {}KB", target_size / 1024);
+
+    // PNG has no quality knob, so try stronger deflate first, then shrink the palette
+    report_progress(progress, "compressing", 70.0, None);
+    let mut best = optimize_png(encode_png_with(img, spec, png::Compression::Best)?, spec);
+    let mut stats = CompressStats { iterations: 1, hit_iteration_limit: false };
+    console_debug!("Best-compression PNG attempt: {}KB", best.len() / 1024);
+    if best.len() <= target_size {
+        return Ok((best, stats));
+    }
+
+    for (step, colors) in [256, 128, 64].into_iter().enumerate() {
+        if stats.iterations >= spec.max_compress_iterations {
+            stats.hit_iteration_limit = true;
+            break;
+        }
+        report_progress(progress, "compressing", 75.0 + step as f32 * 8.0, None);
+        let attempt = optimize_png(encode_indexed_png(img, colors, dpi)?, spec);
+        stats.iterations += 1;
+        console_debug!("Palette PNG attempt with {} colors: {}KB", colors, attempt.len() / 1024);
+        if attempt.len() < best.len() {
+            best = attempt;
+        }
+        if best.len() <= target_size {
+            return Ok((best, stats));
+        }
+    }
+
+    console_log!(
+        "Warning: smallest PNG is {}KB, still above target {}KB; returning best attempt",
+        best.len() / 1024,
+        target_size / 1024
+    );
+    Ok((best, stats))
+}
+
+pub(crate) fn compress_to_target_size(
+    img: &DynamicImage,
+    target_size: usize,
+    spec: &DocumentFormat,
+    initial: (u8, Vec<u8>),
+    progress: Option<&ProgressFn>,
+) -> Result<(Vec<u8>, u8, CompressStats), FormatError> {
+    // PNG is lossless, so there is no quality to report
+    if spec.format == "PNG" {
+        let (output_buffer, stats) = compress_png_to_target_size(img, target_size, spec, progress)?;
+        return Ok((output_buffer, 0, stats));
+    }
+
+    console_log!("Compressing image to target size: {}KB", target_size / 1024);
+
+    // The initial encode was already too large, so only lower qualities can
+    // fit; at the floor it is also the smallest output we could produce
+    let (initial_quality, initial_attempt) = initial;
+    if initial_quality <= MIN_JPEG_QUALITY {
+        return Ok((initial_attempt, initial_quality, CompressStats::default()));
+    }
+
+    // Binary search for the highest quality whose output still fits the target,
+    // over steps of `stride` counted up from the minimum quality
+    let stride = if spec.format == "AVIF" { AVIF_QUALITY_STRIDE } else { 1 };
+    let (mut low, mut high) = (0, ((initial_quality - 1).min(MAX_JPEG_QUALITY) - MIN_JPEG_QUALITY) / stride);
+    let mut best_fit: Option<(u8, Vec<u8>)> = None;
+    // The most recent attempt that was too large, which is also the smallest
+    let mut smallest_miss: Option<(u8, Vec<u8>)> = None;
+    let mut stats = CompressStats::default();
+
+    while low <= high {
+        if stats.iterations >= spec.max_compress_iterations {
+            console_log!("Warning: stopped the size search after {} encodes", stats.iterations);
+            stats.hit_iteration_limit = true;
+            break;
+        }
+        let index = low + (high - low) / 2;
+        let quality = MIN_JPEG_QUALITY + index * stride;
+        // The search over this range never takes more than seven steps
+        report_progress(progress, "compressing", 70.0 + stats.iterations.min(7) as f32 * 25.0 / 7.0, Some(quality));
+        stats.iterations += 1;
+        let attempt = encode_image(img, spec, quality)?;
+        console_debug!("Compression attempt with quality {}: {}KB", quality, attempt.len() / 1024);
+
+        if attempt.len() <= target_size {
+            best_fit = Some((quality, attempt));
+            low = index + 1;
+        } else {
+            smallest_miss = Some((quality, attempt));
+            if index == 0 {
+                break;
+            }
+            high = index - 1;
+        }
+    }
+
+    // Nothing fits: fall back to the smallest output found
+    let (quality, output_buffer) = match (best_fit, smallest_miss) {
+        (Some(fit), _) => fit,
+        (None, Some(miss)) => miss,
+        (None, None) => (initial_quality, initial_attempt),
+    };
+
+    console_log!("Final compressed size: {}KB with quality: {}", output_buffer.len() / 1024, quality);
+    Ok((output_buffer, quality, stats))
+}
+
+// Binary search for the lowest quality that still keeps `threshold` SSIM,
+// then lower it further only if the size cap demands it
+fn compress_to_target_ssim(
+    img: &DynamicImage,
+    target_size: usize,
+    spec: &DocumentFormat,
+    threshold: f32,
+    progress: Option<&ProgressFn>,
+) -> Result<(Vec<u8>, u8, CompressStats), FormatError> {
+    console_log!("Searching for the lowest quality with SSIM >= {}", threshold);
+    let stride = if spec.format == "AVIF" { AVIF_QUALITY_STRIDE } else { 1 };
+    let (mut low, mut high) = (0, (MAX_JPEG_QUALITY - MIN_JPEG_QUALITY) / stride);
+    let mut best_pass: Option<(u8, Vec<u8>)> = None;
+    let mut stats = CompressStats::default();
+
+    while low <= high {
+        if stats.iterations >= spec.max_compress_iterations {
+            console_log!("Warning: stopped the SSIM search after {} encodes", stats.iterations);
+            stats.hit_iteration_limit = true;
+            break;
+        }
+        let index = low + (high - low) / 2;
+        let quality = MIN_JPEG_QUALITY + index * stride;
+        report_progress(progress, "compressing", 70.0 + stats.iterations.min(7) as f32 * 25.0 / 7.0, Some(quality));
+        stats.iterations += 1;
+        let attempt = encode_image(img, spec, quality)?;
+        let score = encoded_ssim(img, &attempt)?;
+        console_debug!("SSIM attempt with quality {}: {:.4}, {}KB", quality, score, attempt.len() / 1024);
+
+        if score >= threshold {
+            best_pass = Some((quality, attempt));
+            if index == 0 {
+                break;
+            }
+            high = index - 1;
+        } else {
+            low = index + 1;
+        }
+    }
+
+    // Even the top quality misses the threshold: keep the closest
+    let (quality, output_buffer) = match best_pass {
+        Some(pass) => pass,
+        None => {
+            console_log!("Warning: no quality reaches SSIM {}, using {}", threshold, MAX_JPEG_QUALITY);
+            (MAX_JPEG_QUALITY, encode_image(img, spec, MAX_JPEG_QUALITY)?)
+        }
+    };
+    if output_buffer.len() <= target_size {
+        console_log!("Final SSIM-targeted size: {}KB with quality: {}", output_buffer.len() / 1024, quality);
+        return Ok((output_buffer, quality, stats));
+    }
+
+    // The size cap wins over appearance
+    console_log!("Quality {} is {}KB, above the {}KB cap; compressing below the SSIM target", quality, output_buffer.len() / 1024, target_size / 1024);
+    let (output_buffer, quality, more) = compress_to_target_size(img, target_size, spec, (quality, output_buffer), progress)?;
+    stats.add(more);
+    Ok((output_buffer, quality, stats))
+}
+
+// Shrink until the floor quality fits, then search quality again at that
+// size. Each try resizes from `img` so the losses do not compound. Every
+// encode here counts against the same `max_compress_iterations` budget as
+// the searches already in `stats`
+fn downscale_to_target_size(
+    img: &DynamicImage,
+    target_size: usize,
+    spec: &DocumentFormat,
+    mut stats: CompressStats,
+    progress: Option<&ProgressFn>,
+) -> Result<(DynamicImage, Vec<u8>, u8, CompressStats), FormatError> {
+    let filter = parse_filter(&spec.filter)?;
+    let cannot_meet = FormatError::CannotMeetSize { target_kb: (target_size / 1024) as u32 };
+    let mut scale = 1.0;
+    let mut step = DOWNSCALE_STEP;
+    let (smaller, floor) = loop {
+        if stats.iterations >= spec.max_compress_iterations {
+            console_log!("Warning: stopped downscaling after {} encodes", stats.iterations);
+            return Err(cannot_meet);
+        }
+        scale *= step;
+        let width = (img.width() as f64 * scale).round() as u32;
+        let height = (img.height() as f64 * scale).round() as u32;
+        if width.min(height) < MIN_DOWNSCALE_SIDE {
+            return Err(cannot_meet);
+        }
+
+        let smaller = enhance::resize_exact(img, width, height, filter, spec.assume_premultiplied);
+        let floor = encode_image(&smaller, spec, MIN_JPEG_QUALITY)?;
+        stats.iterations += 1;
+        console_debug!("Downscale attempt at {}x{}: {}KB", width, height, floor.len() / 1024);
+        if floor.len() <= target_size {
+            break (smaller, floor);
+        }
+        // Size tracks pixel count, so jump close to the size that fits
+        // rather than creeping down one step per encode
+        step = (target_size as f64 / floor.len() as f64).sqrt().min(DOWNSCALE_STEP);
+    };
+
+    console_log!("Downscaled to {}x{} to meet the {}KB limit", smaller.width(), smaller.height(), target_size / 1024);
+    // Out of encodes: the floor already fits, so it is the answer
+    if stats.iterations >= spec.max_compress_iterations {
+        stats.hit_iteration_limit = true;
+        return Ok((smaller, floor, MIN_JPEG_QUALITY, stats));
+    }
+    let initial = encode_image(&smaller, spec, spec.quality)?;
+    stats.iterations += 1;
+    if initial.len() <= target_size {
+        return Ok((smaller, initial, spec.quality, stats));
+    }
+    if stats.iterations >= spec.max_compress_iterations {
+        stats.hit_iteration_limit = true;
+        return Ok((smaller, floor, MIN_JPEG_QUALITY, stats));
+    }
+    let budget = DocumentFormat { max_compress_iterations: spec.max_compress_iterations - stats.iterations, ..spec.clone() };
+    let (output_buffer, quality, more) = compress_to_target_size(&smaller, target_size, &budget, (spec.quality, initial), progress)?;
+    stats.add(more);
+    // A search cut short may stop above the cap, where the floor still fits
+    if output_buffer.len() > target_size {
+        return Ok((smaller, floor, MIN_JPEG_QUALITY, stats));
+    }
+    Ok((smaller, output_buffer, quality, stats))
+}
+
+// Rough starting capacity for an encode buffer: photos and scans typically
+// land around two bits per pixel, so this avoids most reallocations
+fn estimated_encoded_size(width: u32, height: u32) -> usize {
+    (width as usize * height as usize / 4).max(1024)
+}
+
+fn write_png(
+    width: u32,
+    height: u32,
+    (color_type, depth): (png::ColorType, png::BitDepth),
+    data: &[u8],
+    palette: Option<(&[u8], Option<&[u8]>)>,
+    dpi: u32,
+    compression: png::Compression,
+) -> Result<Vec<u8>, FormatError> {
+    let mut output_buffer = Vec::with_capacity(estimated_encoded_size(width, height));
+    let mut encoder = png::Encoder::new(&mut output_buffer, width, height);
+    encoder.set_color(color_type);
+    encoder.set_depth(depth);
+    encoder.set_compression(compression);
+    if let png::Compression::Best = compression {
+        encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+    }
+    if let Some((rgb, transparency)) = palette {
+        encoder.set_palette(rgb.to_vec());
+        if let Some(alpha) = transparency {
+            encoder.set_trns(alpha.to_vec());
+        }
+    }
+    encoder.set_pixel_dims(Some(png::PixelDimensions {
+        xppu: dpi_to_pixels_per_meter(dpi),
+        yppu: dpi_to_pixels_per_meter(dpi),
+        unit: png::Unit::Meter,
+    }));
+
+    let mut writer = encoder.write_header()
+        .map_err(|e| FormatError::EncodeFailed(e.to_string()))?;
+    writer.write_image_data(data)
+        .map_err(|e| FormatError::EncodeFailed(e.to_string()))?;
+    writer.finish()
+        .map_err(|e| FormatError::EncodeFailed(e.to_string()))?;
+    Ok(output_buffer)
+}
+
+// Every distinct RGBA color and each pixel's index into them, when there are
+// few enough for a lossless 8-bit palette
+fn exact_palette(rgba: &RgbaImage) -> Option<(Vec<[u8; 4]>, Vec<u8>)> {
+    let mut lookup: HashMap<[u8; 4], u8> = HashMap::new();
+    let mut palette = Vec::new();
+    let mut indices = Vec::with_capacity(rgba.width() as usize * rgba.height() as usize);
+    for pixel in rgba.pixels() {
+        let index = match lookup.get(&pixel.0) {
+            Some(&index) => index,
+            None if palette.len() < 256 => {
+                let index = palette.len() as u8;
+                lookup.insert(pixel.0, index);
+                palette.push(pixel.0);
+                index
+            }
+            None => return None,
+        };
+        indices.push(index);
+    }
+    Some((palette, indices))
+}
+
+fn write_indexed_png(
+    rgba: &RgbaImage,
+    palette: &[[u8; 4]],
+    indices: &[u8],
+    dpi: u32,
+    compression: png::Compression,
+) -> Result<Vec<u8>, FormatError> {
+    let rgb: Vec<u8> = palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect();
+    let alpha: Vec<u8> = palette.iter().map(|c| c[3]).collect();
+    let transparency = alpha.iter().any(|&a| a < 255).then_some(alpha.as_slice());
+    write_png(
+        rgba.width(),
+        rgba.height(),
+        (png::ColorType::Indexed, png::BitDepth::Eight),
+        indices,
+        Some((&rgb, transparency)),
+        dpi,
+        compression,
+    )
+}
+
+// 1-bit grayscale: pixels at or above `threshold` become white, packed eight to
+// a byte with each row padded to a whole byte
+fn write_bilevel_png(luma: &GrayImage, threshold: u8, dpi: u32, compression: png::Compression) -> Result<Vec<u8>, FormatError> {
+    let row_bytes = (luma.width() as usize).div_ceil(8);
+    let mut packed = vec![0u8; row_bytes * luma.height() as usize];
+    for (x, y, pixel) in luma.enumerate_pixels() {
+        if pixel[0] >= threshold {
+            packed[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+        }
+    }
+    write_png(
+        luma.width(),
+        luma.height(),
+        (png::ColorType::Grayscale, png::BitDepth::One),
+        &packed,
+        None,
+        dpi,
+        compression,
+    )
+}
+
+// High-resolution clock: `performance.now()` in the browser (falling back to
+// `Date.now()` where there is no window, e.g. workers), `Instant` natively
+#[cfg(target_arch = "wasm32")]
+fn now_ms() -> f64 {
+    web_sys::window()
+        .and_then(|window| window.performance())
+        .map(|performance| performance.now())
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+#[cfg(all(not(target_arch = "wasm32"), not(test)))]
+fn now_ms() -> f64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START.get_or_init(std::time::Instant::now).elapsed().as_secs_f64() * 1000.0
+}
+
+// Unit tests get a clock that moves 1ms per reading, so timings are exact
+#[cfg(all(not(target_arch = "wasm32"), test))]
+fn now_ms() -> f64 {
+    thread_local! {
+        static CLOCK: std::cell::Cell<f64> = const { std::cell::Cell::new(0.0) };
+    }
+    CLOCK.with(|clock| {
+        clock.set(clock.get() + 1.0);
+        clock.get()
+    })
+}
+
+// Usual file extension for a configured output format
+pub(crate) fn file_extension(format: &str) -> &'static str {
+    match format {
+        "PNG" => "png",
+        "WEBP" => "webp",
+        "AVIF" => "avif",
+        "PDF" => "pdf",
+        _ => "jpg",
+    }
+}
+
+// Expand `{docType}`, `{ext}` and `{examCode}` in the exam's filename template
+fn suggested_filename(config: &ExamConfig, document_type: &str, format: &str) -> Option<String> {
+    config.filename_template.as_ref().map(|template| {
+        template
+            .replace("{docType}", document_type)
+            .replace("{ext}", file_extension(format))
+            .replace("{examCode}", &config.code)
+    })
+}
+
+// Lowercase hex SHA-256 of the exact bytes handed back, for server-side dedup
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn report_progress(progress: Option<&ProgressFn>, stage: &str, percent: f32, quality: Option<u8>) {
+    if let Some(callback) = progress {
+        callback(stage, percent, quality);
+    }
+}
+
+// Some portals reject files below a minimum size, which a plain signature on
+// white easily undershoots. Lowering quality only ever shrinks the file and the
+// encode already starts at the configured quality, so instead the output is
+// padded with metadata the decoder ignores: JPEG comment segments, or a PNG
+// text chunk. The pixels are untouched
+fn pad_to_min_size(data: Vec<u8>, format: &str, min_bytes: usize) -> Vec<u8> {
+    if data.len() >= min_bytes {
+        return data;
+    }
+    let missing = min_bytes - data.len();
+
+    match format {
+        "JPEG" if data.starts_with(&[0xFF, 0xD8]) => {
+            const MAX_COMMENT: usize = u16::MAX as usize - 2;
+            let mut padded = Vec::with_capacity(min_bytes + 4);
+            padded.extend_from_slice(&data[..2]);
+            let mut remaining = missing;
+            while remaining > 0 {
+                // Each segment costs four header bytes on top of its payload
+                let payload = remaining.saturating_sub(4).clamp(1, MAX_COMMENT);
+                padded.extend_from_slice(&[0xFF, 0xFE]);
+                padded.extend_from_slice(&((payload + 2) as u16).to_be_bytes());
+                padded.resize(padded.len() + payload, b' ');
+                remaining = remaining.saturating_sub(payload + 4);
+            }
+            padded.extend_from_slice(&data[2..]);
+            padded
+        }
+        "PNG" if data.len() > 33 => {
+            // Signature (8) plus the IHDR chunk (25) must come first
+            let mut chunk_data = b"Comment\0".to_vec();
+            chunk_data.resize(chunk_data.len() + missing.saturating_sub(20), b' ');
+            let mut chunk = (chunk_data.len() as u32).to_be_bytes().to_vec();
+            chunk.extend_from_slice(b"tEXt");
+            chunk.extend_from_slice(&chunk_data);
+            chunk.extend_from_slice(&crc32(&chunk[4..]).to_be_bytes());
+
+            let mut padded = data[..33].to_vec();
+            padded.extend_from_slice(&chunk);
+            padded.extend_from_slice(&data[33..]);
+            padded
+        }
+        _ => {
+            console_log!("Warning: cannot pad {} output up to the minimum size", format);
+            data
+        }
+    }
+}
+
+// CRC-32 as used by PNG chunks
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Lossy output this build can decode again to measure its SSIM
+fn decodes_lossy_output(spec: &DocumentFormat) -> bool {
+    matches!(spec.format.as_str(), "JPEG" | "PDF") || (spec.format == "WEBP" && cfg!(feature = "webp"))
+}
+
+// SSIM of an encoded output against the image it was encoded from
+fn encoded_ssim(reference: &DynamicImage, data: &[u8]) -> Result<f32, FormatError> {
+    Ok(enhance::ssim(reference, &decode_image(data, DEFAULT_MAX_MEGAPIXELS)?))
+}
+
+// Audit name of the turn or flip an EXIF orientation tag calls for
+fn exif_transform(orientation: u32) -> Option<&'static str> {
+    match orientation {
+        2 => Some("exif-flip-horizontal"),
+        3 => Some("exif-rotate-180"),
+        4 => Some("exif-flip-vertical"),
+        5 => Some("exif-transpose"),
+        6 => Some("exif-rotate-90"),
+        7 => Some("exif-transverse"),
+        8 => Some("exif-rotate-270"),
+        _ => None,
+    }
+}
+
+// Audit entry for one encode, e.g. "jpeg q82" or "png"
+fn encode_transform(spec: &DocumentFormat, quality: u8) -> String {
+    match spec.format.as_str() {
+        "PNG" if spec.png_optimize => "png optimized".to_string(),
+        "PNG" => "png".to_string(),
+        // Images for a PDF document are delivered as JPEG
+        "PDF" => format!("jpeg q{}", quality),
+        other => format!("{} q{}", other.to_lowercase(), quality),
+    }
+}
+
+pub(crate) fn read_exif_orientation(data: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::Cursor::new(data))
+        .ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+// Dimensions of an upload that can be returned byte-for-byte: already the
+// target format, size and density, upright and sRGB, with no pixel pass
+// configured and no metadata that would otherwise be stripped. Only reads the
+// header
+fn conformant_dimensions(
+    data: &[u8],
+    mime: &str,
+    spec: &DocumentFormat,
+    options: &ProcessingOptions,
+    target_size: usize,
+) -> Option<(u32, u32)> {
+    let same_format = matches!(spec.format.as_str(), "JPEG" | "PNG") && output_mime_type(&spec.format) == mime;
+    let min_bytes = spec.min_size.unwrap_or(0) as usize * 1024;
+    let untouched = options.rotate_degrees == 0.0
+        && options.watermark.is_none()
+        && !spec.face_center
+        && !spec.signature_cleanup
+        && !spec.auto_trim
+        && !spec.grayscale
+        && !spec.auto_deskew
+        && !spec.auto_contrast
+        && spec.sharpen == 0.0
+        && !spec.roi_optimize
+        && spec.border.is_none()
+        && spec.crop_shape == "rect"
+        && (spec.format != "PNG" || (spec.png_color_type == "auto" && !spec.png_optimize));
+    if !same_format || !untouched || data.len() > target_size || data.len() < min_bytes {
+        return None;
+    }
+    if metadata::declared_dpi(data) != Some(spec.dpi) {
+        return None;
+    }
+    // Non-square pixels are stretched square by `correct_pixel_aspect`
+    if spec.correct_pixel_aspect && input_density(data).is_some_and(|(x, y)| x != y) {
+        return None;
+    }
+    if read_exif_orientation(data).unwrap_or(1) != 1 || (!spec.preserve_metadata && metadata::has_exif(data)) {
+        return None;
+    }
+    // Wide-gamut uploads still need converting to sRGB
+    if color::detect(data) != ColorSpace::Srgb {
+        return None;
+    }
+
+    let dimensions = image::io::Reader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()?;
+    let fits = if spec.downscale_only {
+        dimensions.0 <= spec.width && dimensions.1 <= spec.height
+    } else {
+        dimensions == (spec.width, spec.height)
+    };
+    fits.then_some(dimensions)
+}
+
+// Decode after reading just the header's dimensions, so an oversized image is
+// refused before its pixel buffer is ever allocated
+pub(crate) fn decode_image(data: &[u8], max_megapixels: u32) -> Result<DynamicImage, FormatError> {
+    check_pixel_limit(data, max_megapixels)?;
+    // The JPEG decoder fills a cut-off scan with gray instead of failing
+    if is_truncated(data) {
+        return Err(FormatError::Truncated);
+    }
+    if let Some(img) = decode_jpeg_direct(data, max_megapixels, None)? {
+        return Ok(img);
+    }
+    image::load_from_memory(data).map_err(|e| FormatError::DecodeFailed(e.to_string()))
+}
+
+fn check_pixel_limit(data: &[u8], max_megapixels: u32) -> Result<(), FormatError> {
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| FormatError::DecodeFailed(e.to_string()))?
+        .into_dimensions()
+        .map_err(|e| FormatError::DecodeFailed(e.to_string()))?;
+
+    let megapixels = width as f64 * height as f64 / 1_000_000.0;
+    if megapixels > max_megapixels as f64 {
+        return Err(FormatError::TooLarge(format!(
+            "Image is {}x{} ({:.1} MP), above the {} MP limit. Please upload a smaller image",
+            width, height, megapixels, max_megapixels
+        )));
+    }
+    Ok(())
+}
+
+// Every frame of an animated GIF, keeping the one with the most content:
+// signature apps often export a blank frame first. Frames come out composited
+// on the full canvas, and only the current and the best are held at a time
+fn decode_gif(data: &[u8], max_megapixels: u32, allow_animated: bool) -> Result<(DynamicImage, u32), FormatError> {
+    use image::AnimationDecoder;
+
+    check_pixel_limit(data, max_megapixels)?;
+    let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(data))
+        .map_err(|e| FormatError::DecodeFailed(e.to_string()))?;
+    let mut best: Option<(f32, DynamicImage)> = None;
+    let mut frames = 0;
+    for frame in decoder.into_frames() {
+        let frame = frame.map_err(|e| FormatError::DecodeFailed(e.to_string()))?;
+        frames += 1;
+        if frames > 1 && !allow_animated {
+            return Err(FormatError::UnsupportedInput(
+                "Animated images are not accepted. Please upload a still image".to_string(),
+            ));
+        }
+        let img = DynamicImage::ImageRgba8(frame.into_buffer());
+        let coverage = enhance::uniform_coverage(&img);
+        if best.as_ref().is_none_or(|(best_coverage, _)| coverage < *best_coverage) {
+            best = Some((coverage, img));
+        }
+    }
+
+    let (_, img) = best.ok_or_else(|| FormatError::DecodeFailed("GIF has no frames".to_string()))?;
+    if frames > 1 {
+        console_log!("Warning: animated input with {} frames, using the one with the most content", frames);
+    }
+    Ok((img, frames))
+}
+
+// Scanners can save every page of a batch into one TIFF; the decoder only
+// reads the first IFD, so check whether it links to another
+fn is_multi_page_tiff(data: &[u8]) -> bool {
+    let little_endian = match data.get(..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => return false,
+    };
+    let u16_at = |at: usize| {
+        data.get(at..at + 2).map(|b| {
+            let bytes = [b[0], b[1]];
+            if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) }
+        })
+    };
+    let u32_at = |at: usize| {
+        data.get(at..at + 4).map(|b| {
+            let bytes = [b[0], b[1], b[2], b[3]];
+            if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+        })
+    };
+    let Some(first_ifd) = u32_at(4) else {
+        return false;
+    };
+    let Some(entries) = u16_at(first_ifd as usize) else {
+        return false;
+    };
+    u32_at(first_ifd as usize + 2 + entries as usize * 12).is_some_and(|next| next != 0)
+}
+
+// Decode a JPEG straight at the smallest DCT scale (1/2, 1/4 or 1/8) that
+// still covers `min_w`x`min_h`, so a large scan never materializes its full
+// resolution. Anything else takes the normal full decode
+fn decode_image_at_least(data: &[u8], max_megapixels: u32, min_size: (u32, u32)) -> Result<DynamicImage, FormatError> {
+    if is_truncated(data) {
+        return Err(FormatError::Truncated);
+    }
+    match decode_jpeg_direct(data, max_megapixels, Some(min_size))? {
+        Some(img) => Ok(img),
+        None => decode_image(data, max_megapixels),
+    }
+}
+
+// JPEGs that need more than `image` offers: a DCT-scaled decode, or CMYK,
+// which `image` renders as a negative when the Adobe marker is missing. None
+// leaves the file to `image`
+fn decode_jpeg_direct(data: &[u8], max_megapixels: u32, min_size: Option<(u32, u32)>) -> Result<Option<DynamicImage>, FormatError> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Ok(None);
+    }
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info().map_err(|e| FormatError::DecodeFailed(e.to_string()))?;
+    let Some(info) = decoder.info() else {
+        return Ok(None);
+    };
+    // 16-bit needs the conversions `image` does
+    let (full_w, full_h) = (info.width as u32, info.height as u32);
+    let cmyk = info.pixel_format == jpeg_decoder::PixelFormat::CMYK32;
+    if info.pixel_format == jpeg_decoder::PixelFormat::L16 {
+        return Ok(None);
+    }
+
+    // The smallest scale halves both sides. The decoder settles for a scale
+    // where either side is large enough, so pick the eighths that cover both
+    // sides here and request exactly that
+    let scaled = |side: u32, eighths: u32| (side * eighths).div_ceil(8);
+    let eighths = min_size.filter(|&(min_w, min_h)| min_w > 0 && min_h > 0).and_then(|(min_w, min_h)| {
+        [1, 2, 4].into_iter().find(|&e| scaled(full_w, e) >= min_w && scaled(full_h, e) >= min_h)
+    });
+    let (width, height) = match eighths {
+        Some(eighths) => {
+            let (width, height) = decoder
+                .scale(scaled(full_w, eighths) as u16, scaled(full_h, eighths) as u16)
+                .map_err(|e| FormatError::DecodeFailed(e.to_string()))?;
+            (width as u32, height as u32)
+        }
+        None if cmyk => (full_w, full_h),
+        None => return Ok(None),
+    };
+    if let Some((min_w, min_h)) = min_size {
+        if width < min_w || height < min_h {
+            return Ok(None);
+        }
+    }
+    let megapixels = width as f64 * height as f64 / 1_000_000.0;
+    if megapixels > max_megapixels as f64 {
+        return Err(FormatError::TooLarge(format!(
+            "Image is {}x{} ({:.1} MP), above the {} MP limit. Please upload a smaller image",
+            width, height, megapixels, max_megapixels
+        )));
+    }
+
+    if (width, height) != (full_w, full_h) {
+        console_debug!("Decoding {}x{} JPEG at reduced scale as {}x{}", full_w, full_h, width, height);
+    }
+    let pixels = decoder.decode().map_err(|e| FormatError::DecodeFailed(e.to_string()))?;
+    let img = match info.pixel_format {
+        jpeg_decoder::PixelFormat::L8 => GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+        jpeg_decoder::PixelFormat::CMYK32 => {
+            RgbImage::from_raw(width, height, cmyk_to_rgb(&pixels, has_adobe_marker(data))).map(DynamicImage::ImageRgb8)
+        }
+        _ => RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+    };
+    img.map(Some).ok_or_else(|| FormatError::DecodeFailed("Decoded pixel data has the wrong length".to_string()))
+}
+
+// Horizontal and vertical DPI from the JFIF/pHYs header, else from EXIF
+pub(crate) fn input_density(data: &[u8]) -> Option<(u32, u32)> {
+    metadata::declared_density(data).or_else(|| metadata::exif_density(data))
+}
+
+// Stretch the coarser axis of a non-square-DPI image up to the finer one, so
+// each pixel covers the same distance both ways
+fn square_pixels(img: DynamicImage, density: Option<(u32, u32)>, spec: &DocumentFormat) -> Result<DynamicImage, FormatError> {
+    let (width, height) = img.dimensions();
+    let (new_width, new_height) = match density {
+        Some((x, y)) if x > y => (width, (height as u64 * x as u64 / y as u64) as u32),
+        Some((x, y)) if y > x => ((width as u64 * y as u64 / x as u64) as u32, height),
+        _ => return Ok(img),
+    };
+    console_log!("Resampling {}x{} to {}x{} for its non-square DPI", width, height, new_width, new_height);
+    let filter = parse_filter(&spec.filter)?;
+    Ok(enhance::resize_exact(&img, new_width, new_height, filter, spec.assume_premultiplied))
+}
+
+// IJG base luminance table; exported JPEGs scale it by their quality setting
+const JPEG_BASE_LUMA_TABLE: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51, 87,
+    80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92,
+    95, 98, 112, 100, 103, 99,
+];
+// A JPEG this small at this quality or lower reads as an earlier export rather
+// than a camera original or scan
+const RECOMPRESSED_MAX_PIXELS: u64 = 1_000_000;
+const RECOMPRESSED_MAX_QUALITY: u8 = 90;
+
+// The IJG quality the JPEG's luminance table was scaled for; order-independent,
+// so the zigzag layout of DQT does not matter
+fn jpeg_quality(data: &[u8]) -> Option<u8> {
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF && data[pos + 1] != 0xDA {
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if data[pos + 1] == 0xDB {
+            let body = data.get(pos + 4..pos + 2 + length)?;
+            let mut at = 0;
+            while let Some(&header) = body.get(at) {
+                let wide = header >> 4 == 1;
+                let size = if wide { 128 } else { 64 };
+                let table = body.get(at + 1..at + 1 + size)?;
+                if header & 0x0F == 0 {
+                    let sum: u32 = if wide {
+                        table.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32).sum()
+                    } else {
+                        table.iter().map(|&q| q as u32).sum()
+                    };
+                    let base: u32 = JPEG_BASE_LUMA_TABLE.iter().map(|&q| q as u32).sum();
+                    let scale = sum as f64 * 100.0 / base as f64;
+                    let quality = if scale <= 100.0 { (200.0 - scale) / 2.0 } else { 5000.0 / scale };
+                    return Some(quality.round().clamp(1.0, 100.0) as u8);
+                }
+                at += 1 + size;
+            }
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+// A small, already lossy JPEG: formatting it again compresses twice, so the
+// original upload would give a better result
+pub(crate) fn is_likely_already_compressed(data: &[u8]) -> bool {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return false;
+    }
+    let Ok((width, height)) = image::io::Reader::with_format(std::io::Cursor::new(data), image::ImageFormat::Jpeg).into_dimensions() else {
+        return false;
+    };
+    width as u64 * height as u64 <= RECOMPRESSED_MAX_PIXELS
+        && !matches!(jpeg_quality(data), Some(quality) if quality > RECOMPRESSED_MAX_QUALITY)
+}
+
+// Whether the JPEG has an Adobe APP14 marker, which Photoshop and libjpeg
+// write for every CMYK file they store with inverted inks
+fn has_adobe_marker(data: &[u8]) -> bool {
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF && data[pos + 1] != 0xDA {
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if data[pos + 1] == 0xEE && data.get(pos + 4..pos + 9) == Some(b"Adobe") {
+            return true;
+        }
+        pos += 2 + length;
+    }
+    false
+}
+
+// jpeg-decoder inverts every CMYK (and YCCK) sample on the assumption the file
+// follows the Adobe convention; that yields the true inks for Adobe files but
+// a negative for plain CMYK without the marker, so undo it there. The inks
+// then map to RGB naively: there is no ICC handling
+fn cmyk_to_rgb(pixels: &[u8], adobe: bool) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(pixels.len() / 4 * 3);
+    for pixel in pixels.chunks_exact(4) {
+        let ink = |sample: u8| if adobe { sample as u32 } else { 255 - sample as u32 };
+        let black = ink(pixel[3]);
+        for &sample in &pixel[..3] {
+            rgb.push(((255 - ink(sample)) * (255 - black) / 255) as u8);
+        }
+    }
+    rgb
+}
+
+// A JPEG whose scan data never reaches EOI, or a PNG that stops before IEND.
+// Bytes after the end marker (motion-photo trailers) are fine
+fn is_truncated(data: &[u8]) -> bool {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        // Skip the header segments so an EXIF thumbnail's EOI is not mistaken
+        // for ours. Inside scan data 0xFF is always stuffed, so the first
+        // FF D9 after SOS is the real end
+        let mut pos = 2;
+        while pos + 4 <= data.len() && data[pos] == 0xFF {
+            if data[pos + 1] == 0xDA {
+                return !data[pos..].windows(2).any(|pair| pair == [0xFF, 0xD9]);
+            }
+            pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        }
+        return true;
+    }
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        let mut pos = 8;
+        while let Some(header) = data.get(pos..pos + 8) {
+            if &header[4..8] == b"IEND" {
+                return false;
+            }
+            pos += 12 + u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        }
+        return true;
+    }
+    false
+}
+
+// The smallest decode that still covers the target box, in the orientation
+// the pixels are stored in. None when a pass needs the full resolution: face
+// cropping and trimming zoom in, and a free-angle rotation changes what the
+// box covers
+fn decode_target(data: &[u8], format_config: &DocumentFormat, options: &ProcessingOptions) -> Option<(u32, u32)> {
+    let degrees = options.rotate_degrees.rem_euclid(360.0);
+    if format_config.face_center || format_config.auto_trim || degrees % 90.0 != 0.0 {
+        return None;
+    }
+    let exif_swaps = matches!(read_exif_orientation(data), Some(5..=8));
+    let rotation_swaps = degrees == 90.0 || degrees == 270.0;
+    let (width, height) = (format_config.width, format_config.height);
+    Some(if exif_swaps != rotation_swaps { (height, width) } else { (width, height) })
+}
+
+// Whether fitting a source of this size into the target box scales it up on
+// any axis: independently per axis for stretch, by the shared factor otherwise
+pub(crate) fn needs_upscale((src_w, src_h): (u32, u32), format_config: &DocumentFormat) -> bool {
+    if format_config.downscale_only {
+        return false;
+    }
+    let scale_x = format_config.width as f64 / src_w.max(1) as f64;
+    let scale_y = format_config.height as f64 / src_h.max(1) as f64;
+    match format_config.fit_mode.as_str() {
+        "contain" => scale_x.min(scale_y) > 1.0,
+        "cover" => scale_x.max(scale_y) > 1.0,
+        _ => scale_x > 1.0 || scale_y > 1.0,
+    }
+}
+
+// Settle a profile whose `format` is "AUTO": the upload's own format when the
+// exam accepts it, else the accepted format suited to what the image shows
+// (PNG for screenshots, JPEG otherwise), else the first that can be written
+pub(crate) fn resolve_auto_format<'a>(config: &ExamConfig, format_config: &'a DocumentFormat, file_data: &[u8]) -> Cow<'a, DocumentFormat> {
+    if format_config.format != "AUTO" {
+        return Cow::Borrowed(format_config);
+    }
+    let writable = |mime: &str| OUTPUT_FORMATS.iter().find(|format| output_mime_type(format) == mime);
+    let input = detect_mime_type(file_data).filter(|mime| config.allowed_formats.iter().any(|allowed| allowed == mime));
+    let format = input
+        .and_then(writable)
+        .or_else(|| {
+            let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS).ok()?;
+            let classification = classify::classify(&img);
+            console_debug!("AUTO input classified as {:?} ({:.2})", classification.kind, classification.confidence);
+            let suited = output_mime_type(if classification.kind == classify::ImageKind::Screenshot { "PNG" } else { "JPEG" });
+            config.allowed_formats.iter().find(|mime| *mime == suited).and_then(|mime| writable(mime))
+        })
+        .or_else(|| config.allowed_formats.iter().find_map(|mime| writable(mime)))
+        .unwrap_or(&"JPEG");
+    console_debug!("Resolved AUTO output format to {}", format);
+    Cow::Owned(DocumentFormat { format: format.to_string(), ..format_config.clone() })
+}
+
+// Signatures and thumb impressions are both dark strokes on paper, so they
+// share the cleanup passes
+fn is_ink_document(document_type: &str) -> bool {
+    matches!(document_type, "signature" | "thumb_impression")
+}
+
+// First lossy format the exam accepts and this build can write, for PNGs that
+// cannot meet their cap
+fn lossy_fallback_format(config: &ExamConfig) -> Option<&'static str> {
+    let writable = output_formats();
+    config
+        .allowed_formats
+        .iter()
+        .filter(|mime| writable.contains(mime))
+        .find_map(|mime| ["JPEG", "WEBP", "AVIF"].into_iter().find(|format| output_mime_type(format) == mime))
+}
+
+// Pick the format profile for a document type: an explicit per-type profile
+// wins, then photo and signature have their own, and anything else must be one
+// of the exam's listed document types
+pub(crate) fn resolve_format<'a>(config: &'a ExamConfig, document_type: &str) -> Result<&'a DocumentFormat, FormatError> {
+    if let Some(format) = config.document_type_formats.get(document_type) {
+        return Ok(format);
+    }
+
+    match document_type {
+        "photo" => Ok(&config.formats.photo),
+        "signature" => Ok(&config.formats.signature),
+        other if config.document_types.iter().any(|known| known == other) => Ok(&config.formats.documents),
+        other => Err(FormatError::UnknownDocumentType {
+            document_type: other.to_string(),
+            allowed: config.document_types.clone(),
+        }),
+    }
+}
+
+// MIME type of the bytes produced for a configured output format
+pub(crate) fn output_mime_type(format: &str) -> &'static str {
+    match format {
+        "PNG" => "image/png",
+        "WEBP" => "image/webp",
+        "AVIF" => "image/avif",
+        "PDF" => "application/pdf",
+        _ => "image/jpeg",
+    }
+}
+
+// Identify the real file type from its magic bytes, independent of the file name
+pub(crate) fn detect_mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"%PDF") {
+        return Some("application/pdf");
+    }
+    if let Some(mime) = bmff_mime_type(data) {
+        return Some(mime);
+    }
+    image::guess_format(data).ok().map(|format| format.to_mime_type())
+}
+
+// HEIF (the iPhone camera's HEIC) and AVIF are ISO-BMFF containers identified
+// by the major brand of the leading `ftyp` box; `guess_format` only recognises
+// AVIF files whose box happens to be one particular size
+fn bmff_mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.len() < 12 || &data[4..8] != b"ftyp" {
+        return None;
+    }
+    match &data[8..12] {
+        b"heic" | b"heix" | b"heim" | b"heis" => Some("image/heic"),
+        b"hevc" | b"hevx" | b"hevm" | b"hevs" => Some("image/heic-sequence"),
+        b"mif1" => Some("image/heif"),
+        b"msf1" => Some("image/heif-sequence"),
+        b"avif" | b"avis" => Some("image/avif"),
+        _ => None,
+    }
+}
+
+// PNG stores density in the pHYs chunk as pixels per meter
+fn dpi_to_pixels_per_meter(dpi: u32) -> u32 {
+    (dpi as f64 / 0.0254).round() as u32
+}
+
+// Lanczos3 suits photos; sharp-edged scans and signatures resize faster and
+// ring less with a simpler kernel
+fn parse_filter(name: &str) -> Result<image::imageops::FilterType, FormatError> {
+    use image::imageops::FilterType;
+    match name.to_lowercase().as_str() {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" => Ok(FilterType::Triangle),
+        "catmull" => Ok(FilterType::CatmullRom),
+        "gaussian" => Ok(FilterType::Gaussian),
+        "lanczos3" => Ok(FilterType::Lanczos3),
+        other => Err(FormatError::InvalidConfig(format!(
+            "Unknown resize filter '{}'. Expected one of: nearest, triangle, catmull, gaussian, lanczos3",
+            other
+        ))),
+    }
+}
+
+// 4:2:0 halves color resolution for smaller photos; 4:4:4 keeps colored
+// text and stamps crisp
+fn parse_chroma_subsampling(name: &str) -> Result<jpeg_encoder::SamplingFactor, FormatError> {
+    use jpeg_encoder::SamplingFactor;
+    match name {
+        "4:4:4" => Ok(SamplingFactor::R_4_4_4),
+        "4:2:2" => Ok(SamplingFactor::R_4_2_2),
+        "4:2:0" => Ok(SamplingFactor::R_4_2_0),
+        other => Err(FormatError::InvalidConfig(format!(
+            "Unknown chroma subsampling '{}'. Expected one of: 4:4:4, 4:2:2, 4:2:0",
+            other
+        ))),
+    }
+}
+
+// Below this share of the edge ring, "auto" has no clear paper color to match
+const AUTO_BG_MIN_SHARE: f32 = 0.5;
+
+// The spec's `bg_color`, with "auto" read from the image's edges and white
+// when they are too mixed to pick one
+fn background_color(img: &DynamicImage, spec: &DocumentFormat) -> Result<Rgba<u8>, FormatError> {
+    if spec.bg_color != "auto" {
+        return parse_hex_color(&spec.bg_color);
+    }
+    match enhance::dominant_edge_color(img) {
+        (image::Rgb([r, g, b]), share) if share >= AUTO_BG_MIN_SHARE => Ok(Rgba([r, g, b, 255])),
+        _ => {
+            console_debug!("No dominant edge color, padding with white");
+            Ok(Rgba([255, 255, 255, 255]))
+        }
+    }
+}
+
+// Parse a `#RRGGBB` color string into an opaque pixel
+pub(crate) fn parse_hex_color(color: &str) -> Result<Rgba<u8>, FormatError> {
+    let hex = color.strip_prefix('#').unwrap_or(color);
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(FormatError::InvalidConfig(format!(
+            "Invalid color '{}'. Expected format #RRGGBB",
+            color
+        )));
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok(Rgba([channel(0), channel(2), channel(4), 255]))
+}
+
+// Fill in pixel dimensions from a size given in cm, once it has been validated
+pub(crate) fn apply_physical_size(format: &mut DocumentFormat) {
+    if let Some((width, height)) = physical_pixels(format) {
+        format.width = width;
+        format.height = height;
+    }
+}
+
+// Byte budget once the spec's safety margin is taken off the cap
+pub(crate) fn with_size_margin(target_size: usize, spec: &DocumentFormat) -> usize {
+    (target_size as f64 * (1.0 - spec.size_margin_pct as f64 / 100.0)) as usize
+}
+
+// Pixel dimensions for a size given in cm, rounded to the nearest pixel
+fn physical_pixels(format: &DocumentFormat) -> Option<(u32, u32)> {
+    let to_pixels = |cm: f32| (cm as f64 / 2.54 * format.dpi as f64).round() as u32;
+    Some((to_pixels(format.width_cm?), to_pixels(format.height_cm?)))
+}
+
+// Output formats `encode_image` and the PDF passthrough understand
+pub(crate) const OUTPUT_FORMATS: [&str; 5] = ["JPEG", "PNG", "WEBP", "AVIF", "PDF"];
+
+// The profile with a call's overrides merged in, checked like a stored config.
+// The stored config itself is never touched
+fn apply_overrides<'a>(
+    config: &ExamConfig,
+    format_config: &'a DocumentFormat,
+    overrides: Option<&FormatOverrides>,
+) -> Result<Cow<'a, DocumentFormat>, FormatError> {
+    let Some(overrides) = overrides else {
+        return Ok(Cow::Borrowed(format_config));
+    };
+    let mut merged = format_config.clone();
+    if let Some(quality) = overrides.quality {
+        merged.quality = quality;
+    }
+    if let Some(filter) = &overrides.filter {
+        merged.filter = filter.clone();
+    }
+    if let Some(format) = &overrides.format {
+        merged.format = format.to_uppercase();
+    }
+    if let Some(fit_mode) = &overrides.fit_mode {
+        merged.fit_mode = fit_mode.clone();
+    }
+
+    let mut issues = Vec::new();
+    validate_format(config, "overrides", &merged, &mut issues);
+    if !issues.is_empty() {
+        return Err(FormatError::InvalidInput(issues.join("; ")));
+    }
+    Ok(Cow::Owned(merged))
+}
+
+// Problems with one format profile, reported under `path`
+pub(crate) fn validate_format(config: &ExamConfig, path: &str, format: &DocumentFormat, issues: &mut Vec<String>) {
+    match (format.width_cm, format.height_cm) {
+        (None, None) => {
+            if format.width == 0 || format.height == 0 {
+                issues.push(format!("{}: dimensions {}x{} must be nonzero", path, format.width, format.height));
+            }
+        }
+        (Some(width_cm), Some(height_cm)) => {
+            if !(width_cm.is_finite() && height_cm.is_finite() && width_cm > 0.0 && height_cm > 0.0) {
+                issues.push(format!("{}: physical size {}x{} cm must be positive", path, width_cm, height_cm));
+            } else if let Some((width, height)) = physical_pixels(format) {
+                let pixels_set = format.width != 0 || format.height != 0;
+                if width == 0 || height == 0 {
+                    issues.push(format!("{}: {}x{} cm is under one pixel at {} DPI", path, width_cm, height_cm, format.dpi));
+                } else if pixels_set && (format.width, format.height) != (width, height) {
+                    issues.push(format!(
+                        "{}: {}x{} px does not match {}x{} cm at {} DPI ({}x{} px); set only one",
+                        path, format.width, format.height, width_cm, height_cm, format.dpi, width, height
+                    ));
+                }
+            }
+        }
+        _ => issues.push(format!("{}: width_cm and height_cm must be set together", path)),
+    }
+    if format.dpi == 0 {
+        issues.push(format!("{}.dpi must be greater than 0", path));
+    }
+    if !(1..=100).contains(&format.quality) {
+        issues.push(format!("{}.quality {} out of range 1-100", path, format.quality));
+    }
+    if format.strict_format && format.format != "AUTO" && !OUTPUT_FORMATS.contains(&format.format.as_str()) {
+        issues.push(format!("{}.format '{}' unsupported", path, format.format));
+    }
+    if format.max_size == 0 {
+        issues.push(format!("{}.max_size must be greater than 0", path));
+    } else if format.max_size > config.max_file_size {
+        issues.push(format!(
+            "{}.max_size {}KB exceeds max_file_size {}KB",
+            path, format.max_size, config.max_file_size
+        ));
+    }
+    if let Some(min_size) = format.min_size.filter(|&min| min > format.max_size) {
+        issues.push(format!("{}.min_size {}KB exceeds max_size {}KB", path, min_size, format.max_size));
+    }
+    // Only JPEG and PNG can be padded; "AUTO" may land on any accepted format
+    if format.min_size.is_some() {
+        let unpaddable = OUTPUT_FORMATS.iter().filter(|&&output| !matches!(output, "JPEG" | "PNG")).find(|&&output| {
+            if format.format == "AUTO" {
+                config.allowed_formats.iter().any(|mime| mime == output_mime_type(output))
+            } else {
+                format.format == output
+            }
+        });
+        if let Some(output) = unpaddable {
+            issues.push(format!("{}.min_size cannot be met for {} output; only JPEG and PNG are padded", path, output));
+        }
+    }
+    if !matches!(format.fit_mode.as_str(), "stretch" | "contain" | "cover") {
+        issues.push(format!("{}.fit_mode '{}' unsupported", path, format.fit_mode));
+    }
+    if format.bg_color != "auto" && parse_hex_color(&format.bg_color).is_err() {
+        issues.push(format!("{}.bg_color '{}' is not #RRGGBB or auto", path, format.bg_color));
+    }
+    if format.max_compress_iterations == 0 {
+        issues.push(format!("{}.max_compress_iterations must be at least 1", path));
+    }
+    if !format.max_aspect_skew.is_finite() || format.max_aspect_skew < 1.0 {
+        issues.push(format!("{}.max_aspect_skew {} must be at least 1.0", path, format.max_aspect_skew));
+    }
+    if parse_hex_color(&format.flatten_background).is_err() {
+        issues.push(format!("{}.flatten_background '{}' is not #RRGGBB", path, format.flatten_background));
+    }
+    if parse_filter(&format.filter).is_err() {
+        issues.push(format!("{}.filter '{}' unsupported", path, format.filter));
+    }
+    if parse_chroma_subsampling(&format.chroma_subsampling).is_err() {
+        issues.push(format!("{}.chroma_subsampling '{}' unsupported", path, format.chroma_subsampling));
+    }
+    match format.crop_shape.as_str() {
+        "rect" => {}
+        "circle" | "oval" if format.format != "PNG" => issues.push(format!(
+            "{}.crop_shape '{}' needs PNG output for transparency, not {}",
+            path, format.crop_shape, format.format
+        )),
+        "circle" | "oval" => {}
+        other => issues.push(format!("{}.crop_shape '{}' unsupported", path, other)),
+    }
+    if !matches!(format.png_color_type.as_str(), "auto" | "rgb" | "grayscale" | "palette" | "bilevel") {
+        issues.push(format!("{}.png_color_type '{}' unsupported", path, format.png_color_type));
+    }
+    if !matches!(format.dither.as_str(), "none" | "floyd-steinberg" | "ordered") {
+        issues.push(format!("{}.dither '{}' unsupported", path, format.dither));
+    }
+    if let Some(border) = &format.border {
+        if parse_hex_color(&border.color).is_err() {
+            issues.push(format!("{}.border.color '{}' is not #RRGGBB", path, border.color));
+        }
+        // A frame wider than the picture it surrounds is a typo, and
+        // unbounded widths would allocate or overflow
+        let (width, height) = physical_pixels(format).unwrap_or((format.width, format.height));
+        if border.width_px as u64 * 2 >= width.min(height) as u64 {
+            issues.push(format!(
+                "{}.border.width_px {} must be under half the {}x{} target",
+                path, border.width_px, width, height
+            ));
+        }
+    }
+    if !(0.0..100.0).contains(&format.size_margin_pct) {
+        issues.push(format!("{}.size_margin_pct {} out of range 0-100", path, format.size_margin_pct));
+    }
+    if !format.sharpen.is_finite() || format.sharpen < 0.0 {
+        issues.push(format!("{}.sharpen {} must be zero or positive", path, format.sharpen));
+    }
+    if let Some(target) = format.target_ssim {
+        if !(target > 0.0 && target <= 1.0) {
+            issues.push(format!("{}.target_ssim {} out of range 0-1", path, target));
+        }
+    }
+}
+
+// MIME types this build can decode; WebP depends on the `webp` feature, and
+// HEIC/AVIF have no decoder at all yet
+pub(crate) fn input_formats() -> Vec<String> {
+    let mut formats = vec!["image/jpeg", "image/png", "image/gif", "image/tiff", "application/pdf"];
+    if cfg!(feature = "webp") {
+        formats.push("image/webp");
+    }
+    formats.into_iter().map(String::from).collect()
+}
+
+// MIME types this build can encode to, following the enabled encoder features
+pub(crate) fn output_formats() -> Vec<String> {
+    let mut formats = vec!["image/jpeg", "image/png"];
+    if cfg!(feature = "webp") {
+        formats.push("image/webp");
+    }
+    if cfg!(feature = "avif") {
+        formats.push("image/avif");
+    }
+    formats.push("application/pdf");
+    formats.into_iter().map(String::from).collect()
+}
+
+#[cfg(feature = "watermark")]
+fn apply_watermark(img: &DynamicImage, spec: &WatermarkSpec) -> Result<DynamicImage, FormatError> {
+    if spec.text.trim().is_empty() {
+        return Err(FormatError::InvalidInput("Watermark text must not be empty".to_string()));
+    }
+    if !(0.0..=1.0).contains(&spec.opacity) {
+        return Err(FormatError::InvalidInput(format!("Watermark opacity {} is outside 0-1", spec.opacity)));
+    }
+    console_log!("Stamping watermark '{}' at opacity {}", spec.text, spec.opacity);
+    Ok(watermark::stamp(img, spec.text.trim(), spec.opacity))
+}
+
+#[cfg(not(feature = "watermark"))]
+fn apply_watermark(_img: &DynamicImage, _spec: &WatermarkSpec) -> Result<DynamicImage, FormatError> {
+    Err(FormatError::InvalidInput("Watermarking is not enabled in this build".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{encode, exam, gradient_energy, jpeg, noise, orientation, photo, png, run, spec, with_exif};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn embeds_the_configured_dpi() {
+        let input = png(&photo(320, 240));
+        for (format, dpi) in [("JPEG", 300), ("PNG", 150)] {
+            let output = run(&exam(DocumentFormat { dpi, ..spec(format, 160, 120, 500) }), &input, "photo").unwrap();
+            assert_eq!(metadata::declared_dpi(&output.data), Some(dpi), "{}", format);
+        }
+    }
+
+    #[test]
+    fn size_search_keeps_the_highest_quality_that_fits() {
+        let img = noise(160, 160);
+        let spec = spec("JPEG", 160, 160, 0);
+        let target = encode_image(&img, &spec, 50).unwrap().len();
+        let initial = encode_image(&img, &spec, spec.quality).unwrap();
+
+        let (data, quality, stats) = compress_to_target_size(&img, target, &spec, (spec.quality, initial), None).unwrap();
+        assert!(data.len() <= target);
+        assert!(encode_image(&img, &spec, quality + 1).unwrap().len() > target, "q{} is not the highest fit", quality);
+        // Far fewer encodes than stepping down one quality at a time
+        assert!(stats.iterations <= 7);
+    }
+
+    #[test]
+    fn accepts_only_the_exam_allowed_formats() {
+        let config = exam(spec("JPEG", 100, 100, 100));
+        let img = photo(40, 40);
+        assert_eq!(check_allowed_format(&config, &png(&img)), Ok("image/png"));
+
+        let gif = encode(&img, image::ImageOutputFormat::Gif);
+        match run(&config, &gif, "photo") {
+            Err(FormatError::FormatNotAllowed { format, allowed, .. }) => {
+                assert_eq!(format, "image/gif");
+                assert_eq!(allowed, ["image/jpeg", "image/png"]);
+            }
+            _ => panic!("GIF should be rejected"),
+        }
+    }
+
+    #[test]
+    fn rejects_input_over_the_exam_limit() {
+        let config = ExamConfig { max_file_size: 1, ..exam(spec("JPEG", 100, 100, 100)) };
+        let input = png(&noise(64, 64));
+        assert!(input.len() > 1024);
+        assert!(matches!(run(&config, &input, "photo"), Err(FormatError::TooLarge(_))));
+    }
+
+    #[test]
+    fn exam_limit_caps_a_larger_document_limit() {
+        let config = ExamConfig { max_file_size: 20, ..exam(spec("JPEG", 300, 300, 500)) };
+        let input = jpeg(&noise(160, 160), 30);
+        assert!(input.len() <= 20 * 1024);
+
+        let output = run(&config, &input, "photo").unwrap();
+        assert!(output.was_compressed);
+        assert!(output.data.len() <= 20 * 1024, "{} bytes", output.data.len());
+    }
+
+    #[test]
+    fn turns_exif_orientation_6_upright() {
+        // Stored landscape with its top-left quarter red; orientation 6 means
+        // the camera was turned a quarter clockwise
+        let stored = RgbImage::from_fn(60, 40, |x, y| {
+            if x < 30 && y < 20 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) }
+        });
+        let input = with_exif(jpeg(&DynamicImage::ImageRgb8(stored), 95), &[orientation(6)]);
+        assert_eq!(read_exif_orientation(&input), Some(6));
+
+        let output = run(&exam(spec("PNG", 40, 60, 500)), &input, "photo").unwrap();
+        assert_eq!((output.width, output.height), (40, 60));
+        let upright = image::load_from_memory(&output.data).unwrap().to_rgb8();
+        let (top_left, top_right) = (upright.get_pixel(2, 2), upright.get_pixel(37, 2));
+        assert!(top_right[0] > 200 && top_right[2] < 60, "{:?}", top_right);
+        assert!(top_left[2] > 200 && top_left[0] < 60, "{:?}", top_left);
+    }
+
+    #[cfg(feature = "webp")]
+    #[test]
+    fn webp_output_decodes_to_the_spec_size() {
+        let output = run(&exam(spec("WEBP", 120, 90, 500)), &png(&photo(240, 180)), "photo").unwrap();
+        assert_eq!(output.format, "WEBP");
+        assert_eq!(detect_mime_type(&output.data), Some("image/webp"));
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (120, 90));
+    }
+
+    #[test]
+    fn target_size_override_replaces_max_size() {
+        let config = exam(spec("JPEG", 200, 200, 30));
+        let input = png(&noise(200, 200));
+
+        let options = ProcessingOptions { target_size_kb: Some(8), ..ProcessingOptions::new("photo", "upload") };
+        let overridden = format_with(&config, &input, &options).unwrap();
+        assert!(overridden.data.len() <= 8 * 1024);
+
+        let default = run(&config, &input, "photo").unwrap();
+        assert!(default.data.len() <= 30 * 1024);
+        assert!(default.data.len() > 8 * 1024);
+    }
+
+    #[test]
+    fn grayscale_writes_one_component_with_luminance_weights() {
+        let green = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, image::Rgb([0, 255, 0])));
+        let spec = DocumentFormat { grayscale: true, quality: 95, ..spec("JPEG", 64, 64, 100) };
+        let output = run(&exam(spec), &png(&green), "documents").unwrap();
+        assert_eq!(crate::pdf::jpeg_components(&output.data), Some(1));
+
+        // Rec. 709 puts pure green at 182; a plain average would give 85
+        let gray = image::load_from_memory(&output.data).unwrap().to_luma8();
+        assert!(gray.get_pixel(32, 32)[0].abs_diff(182) <= 3, "{:?}", gray.get_pixel(32, 32));
+    }
+
+    #[test]
+    fn small_sources_are_upscaled_with_a_flag_or_blocked() {
+        let input = png(&photo(100, 120));
+        let warned = run(&exam(spec("JPEG", 200, 240, 100)), &input, "photo").unwrap();
+        assert!(warned.upscaled);
+        assert_eq!((warned.width, warned.height), (200, 240));
+
+        let blocking = DocumentFormat { allow_upscale: false, ..spec("JPEG", 200, 240, 100) };
+        match run(&exam(blocking), &input, "photo") {
+            Err(error) => assert_eq!(
+                error,
+                FormatError::UpscaleBlocked { width: 100, height: 120, target_width: 200, target_height: 240 }
+            ),
+            Ok(_) => panic!("upscaling should be blocked"),
+        }
+    }
+
+    #[test]
+    fn progress_stages_fire_in_order() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Rc::clone(&events);
+        let options = ProcessingOptions {
+            progress: Some(Box::new(move |stage: &str, percent, quality| {
+                recorder.borrow_mut().push((stage.to_string(), percent, quality));
+            })),
+            ..ProcessingOptions::new("photo", "upload")
+        };
+        format_with(&exam(spec("JPEG", 160, 160, 20)), &png(&noise(160, 160)), &options).unwrap();
+
+        let events = events.borrow();
+        let mut stages: Vec<&str> = events.iter().map(|(stage, ..)| stage.as_str()).collect();
+        stages.dedup();
+        assert_eq!(stages, ["decoding", "resizing", "encoding", "compressing", "done"]);
+        assert!(events.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        // Each size-search encode reports the quality it tries
+        assert!(events.iter().filter(|(stage, ..)| stage == "compressing").all(|(.., quality)| quality.is_some()));
+    }
+
+    // Start of an ISO-BMFF file (HEIF, AVIF) with the given major brand
+    fn ftyp(brand: &[u8; 4]) -> Vec<u8> {
+        [&24u32.to_be_bytes()[..], b"ftyp", brand, &[0, 0, 0, 0], b"mif1", brand, &[0; 64]].concat()
+    }
+
+    #[test]
+    fn heic_uploads_are_rejected_with_guidance() {
+        let config = exam(spec("JPEG", 100, 100, 100));
+        assert_eq!(detect_mime_type(&ftyp(b"heic")), Some("image/heic"));
+        match run(&config, &ftyp(b"heic"), "photo") {
+            Err(error @ FormatError::UnsupportedInput(_)) => {
+                assert_eq!(error.code(), "UNSUPPORTED_INPUT_FORMAT");
+                assert!(error.to_string().contains("Most Compatible"), "{}", error);
+            }
+            _ => panic!("HEIC should be rejected before decoding"),
+        }
+        match run(&config, &ftyp(b"hevc"), "photo") {
+            Err(FormatError::UnsupportedInput(message)) => assert!(message.contains("sequences"), "{}", message),
+            _ => panic!("HEIC sequences should be rejected before decoding"),
+        }
+    }
+
+    #[test]
+    fn unknown_document_types_list_the_configured_ones() {
+        let config = exam(spec("JPEG", 100, 100, 100));
+        match run(&config, &png(&photo(100, 100)), "selfie") {
+            Err(FormatError::UnknownDocumentType { document_type, allowed }) => {
+                assert_eq!(document_type, "selfie");
+                assert_eq!(allowed, ["photo", "signature", "documents"]);
+            }
+            _ => panic!("an unconfigured document type should be rejected"),
+        }
+        let error = resolve_format(&config, "selfie").err().unwrap();
+        assert!(error.to_string().contains("allowed: photo, signature, documents"), "{}", error);
+    }
+
+    #[test]
+    fn per_type_formats_override_the_shared_ones() {
+        let mut config = exam(spec("JPEG", 600, 800, 300));
+        config.document_types.push("marksheet".to_string());
+        config.document_types.push("caste_certificate".to_string());
+        config.document_type_formats.insert("marksheet".to_string(), spec("JPEG", 707, 1000, 300));
+
+        assert_eq!(resolve_format(&config, "marksheet").map(|f| (f.width, f.height)), Ok((707, 1000)));
+        assert_eq!(resolve_format(&config, "caste_certificate").map(|f| (f.width, f.height)), Ok((600, 800)));
+        // Overrides apply to the built-in types too
+        config.document_type_formats.insert("photo".to_string(), spec("PNG", 200, 230, 100));
+        assert_eq!(resolve_format(&config, "photo").map(|f| f.format.as_str()), Ok("PNG"));
+        assert_eq!(resolve_format(&config, "signature").map(|f| f.format.as_str()), Ok("JPEG"));
+    }
+
+    #[test]
+    fn tiny_outputs_are_padded_to_min_size() {
+        let faint = RgbImage::from_fn(300, 100, |x, y| {
+            if y == 50 && (100..200).contains(&x) { image::Rgb([40, 40, 40]) } else { image::Rgb([255, 255, 255]) }
+        });
+        let signature = DocumentFormat { min_size: Some(10), ..spec("JPEG", 300, 100, 20) };
+        let output = run(&exam(signature), &png(&DynamicImage::ImageRgb8(faint)), "signature").unwrap();
+        assert!(output.data.len() >= 10 * 1024, "{} bytes", output.data.len());
+        assert!(output.data.len() <= 20 * 1024);
+        assert_eq!(image::load_from_memory(&output.data).unwrap().dimensions(), (300, 100));
+    }
+
+    // Qualities encoded while `f` runs on this thread
+    fn encodes_during(f: impl FnOnce()) -> Vec<u8> {
+        ENCODE_LOG.with(|log| log.borrow_mut().clear());
+        f();
+        ENCODE_LOG.with(|log| log.take())
+    }
+
+    #[test]
+    fn size_search_reuses_the_initial_encode() {
+        let input = png(&noise(160, 160));
+        let mut output = None;
+        let qualities = encodes_during(|| output = Some(run(&exam(spec("JPEG", 160, 160, 20)), &input, "photo").unwrap()));
+        let output = output.unwrap();
+
+        // One encode at the spec quality, then only the search's own attempts
+        assert!(output.was_compressed);
+        assert_eq!(qualities.len() as u32, 1 + output.compress_iterations, "{:?}", qualities);
+        assert_eq!(qualities[0], 85);
+        assert!(!qualities[1..].contains(&85), "{:?}", qualities);
+
+        let fits = encodes_during(|| {
+            run(&exam(spec("JPEG", 160, 160, 500)), &input, "photo").unwrap();
+        });
+        assert_eq!(fits, [85]);
+    }
+
+    fn bordered(width_px: u32, inset: bool) -> DocumentFormat {
+        let border = BorderSpec { width_px, color: "#CC0000".to_string(), inset };
+        DocumentFormat { border: Some(border), ..spec("PNG", 200, 240, 500) }
+    }
+
+    #[test]
+    fn border_frames_the_output() {
+        let input = png(&photo(400, 480));
+        for (inset, size) in [(false, (220, 260)), (true, (200, 240))] {
+            let output = run(&exam(bordered(10, inset)), &input, "photo").unwrap();
+            assert_eq!((output.width, output.height), size);
+            let framed = image::load_from_memory(&output.data).unwrap().to_rgb8();
+            for (x, y) in [(0, 0), (size.0 - 1, 0), (0, size.1 - 1), (size.0 - 1, size.1 - 1), (9, 9)] {
+                assert_eq!(framed.get_pixel(x, y).0, [0xCC, 0, 0], "inset {} at {},{}", inset, x, y);
+            }
+            assert_ne!(framed.get_pixel(10, 10).0, [0xCC, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn oversized_borders_are_rejected() {
+        let input = png(&photo(40, 40));
+        for border in [bordered(100, true), bordered(u32::MAX / 2, false)] {
+            assert!(matches!(run(&exam(border), &input, "photo"), Err(FormatError::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn filter_names_map_to_their_variants() {
+        use image::imageops::FilterType;
+        let cases = [
+            ("nearest", FilterType::Nearest),
+            ("triangle", FilterType::Triangle),
+            ("catmull", FilterType::CatmullRom),
+            ("gaussian", FilterType::Gaussian),
+            ("Lanczos3", FilterType::Lanczos3),
+        ];
+        for (name, filter) in cases {
+            assert_eq!(parse_filter(name), Ok(filter), "{}", name);
+        }
+        assert!(matches!(parse_filter("bicubic"), Err(FormatError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn timings_cover_every_stage() {
+        let output = run(&exam(spec("JPEG", 160, 160, 20)), &png(&noise(160, 160)), "photo").unwrap();
+        let Timings { decode_ms, resize_ms, encode_ms, compress_ms, total_ms } = output.timings;
+        // The test clock ticks once per reading, so each measured stage is at least 1ms
+        for (stage, ms) in [("decode", decode_ms), ("resize", resize_ms), ("encode", encode_ms), ("compress", compress_ms)] {
+            assert!(ms >= 1.0, "{} took {}ms", stage, ms);
+        }
+        assert!(total_ms >= decode_ms + resize_ms + encode_ms + compress_ms);
+
+        let json = serde_json::to_value(output.timings).unwrap();
+        for field in ["decode_ms", "resize_ms", "encode_ms", "compress_ms", "total_ms"] {
+            assert!(json[field].as_f64().is_some_and(|ms| ms >= 0.0), "{}", field);
+        }
+    }
+
+    fn gps_fields() -> Vec<exif::Field> {
+        let field = |tag, value| exif::Field { tag, ifd_num: exif::In::PRIMARY, value };
+        vec![
+            field(exif::Tag::Make, exif::Value::Ascii(vec![b"PhoneCo".to_vec()])),
+            field(exif::Tag::GPSLatitudeRef, exif::Value::Ascii(vec![b"N".to_vec()])),
+            field(exif::Tag::GPSLatitude, exif::Value::Rational(vec![(28, 1).into(), (36, 1).into(), (50, 1).into()])),
+        ]
+    }
+
+    fn has_gps(data: &[u8]) -> bool {
+        exif::Reader::new()
+            .read_from_container(&mut std::io::Cursor::new(data))
+            .is_ok_and(|exif| exif.get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY).is_some())
+    }
+
+    #[test]
+    fn output_carries_no_exif_or_gps() {
+        let input = with_exif(jpeg(&photo(200, 200), 90), &gps_fields());
+        assert!(has_gps(&input));
+
+        let output = run(&exam(spec("JPEG", 100, 100, 100)), &input, "photo").unwrap();
+        assert!(!metadata::has_exif(&output.data) && !has_gps(&output.data));
+        assert!(!output.data.windows(6).any(|window| window == b"Exif\0\0"));
+        assert!(!output.data.windows(2).any(|window| window == [0xFF, 0xE1]));
+    }
+
+    #[test]
+    fn avif_uploads_are_rejected_before_decoding() {
+        match run(&exam(spec("JPEG", 100, 100, 100)), &ftyp(b"avif"), "photo") {
+            Err(error @ FormatError::UnsupportedInput(_)) => assert!(error.to_string().contains("AVIF"), "{}", error),
+            _ => panic!("AVIF input should be rejected"),
+        }
+    }
+
+    // There is no AVIF decoder in the build, so the dimensions are read back
+    // from the container's `ispe` (image spatial extents) property instead
+    #[cfg(feature = "avif")]
+    #[test]
+    fn avif_output_records_the_spec_size() {
+        let output = run(&exam(spec("AVIF", 120, 90, 500)), &png(&photo(240, 180)), "photo").unwrap();
+        assert_eq!(detect_mime_type(&output.data), Some("image/avif"));
+        let ispe = output.data.windows(4).position(|window| window == b"ispe").unwrap();
+        let extent = |at: usize| u32::from_be_bytes(output.data[at..at + 4].try_into().unwrap());
+        // The box type is followed by a version/flags word, then width and height
+        assert_eq!((extent(ispe + 8), extent(ispe + 12)), (120, 90));
+    }
+
+    #[test]
+    fn hash_matches_known_answers() {
+        // FIPS 180-2 test vector
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let config = exam(spec("JPEG", 30, 40, 50));
+        let mut options = ProcessingOptions::new("photo", "upload");
+        options.include_hash = true;
+        let output = format_with(&config, &png(&photo(60, 80)), &options).unwrap();
+        let hash = output.sha256.as_deref().unwrap();
+        assert_eq!(hash, sha256_hex(&output.data));
+        // Pinned so a change in the pipeline's bytes shows up here
+        assert_eq!(hash, "0b4f8c22629d94ca2965b0cd4b67633f98a571d2516ba88f54d61fa4c272e9c2");
+    }
+
+    #[test]
+    fn supported_formats_follow_the_enabled_features() {
+        let (inputs, outputs) = (input_formats(), output_formats());
+        for always in ["image/jpeg", "image/png"] {
+            assert!(inputs.contains(&always.to_string()), "{} missing from inputs", always);
+            assert!(outputs.contains(&always.to_string()), "{} missing from outputs", always);
+        }
+        assert_eq!(inputs.contains(&"image/webp".to_string()), cfg!(feature = "webp"));
+        assert_eq!(outputs.contains(&"image/webp".to_string()), cfg!(feature = "webp"));
+        assert_eq!(outputs.contains(&"image/avif".to_string()), cfg!(feature = "avif"));
+        // Neither has a decoder in any build
+        assert!(!inputs.iter().any(|mime| mime == "image/avif" || mime == "image/heic"));
+    }
+
+    // Per-component H/V sampling bytes from the first baseline SOF0 segment
+    fn sampling_factors(jpeg: &[u8]) -> Vec<u8> {
+        let sof = jpeg.windows(2).position(|marker| marker == [0xFF, 0xC0]).unwrap();
+        let components = jpeg[sof + 9] as usize;
+        (0..components).map(|i| jpeg[sof + 11 + i * 3]).collect()
+    }
+
+    #[test]
+    fn sof_carries_the_requested_chroma_subsampling() {
+        let img = photo(64, 48);
+        for (name, luma) in [("4:4:4", 0x11), ("4:2:2", 0x21), ("4:2:0", 0x22)] {
+            let format = DocumentFormat { chroma_subsampling: name.to_string(), ..spec("JPEG", 64, 48, 50) };
+            let data = encode_jpeg(&img, 85, &format).unwrap();
+            assert_eq!(sampling_factors(&data), [luma, 0x11, 0x11], "{}", name);
+        }
+
+        let format = DocumentFormat { chroma_subsampling: "4:1:1".to_string(), ..spec("JPEG", 64, 48, 50) };
+        assert!(matches!(encode_jpeg(&img, 85, &format), Err(FormatError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn conformant_uploads_pass_through_byte_for_byte() {
+        let format = spec("JPEG", 40, 50, 50);
+        let upload = encode_jpeg(&photo(40, 50), 90, &format).unwrap();
+        let output = run(&exam(format.clone()), &upload, "photo").unwrap();
+        assert_eq!(output.data, upload);
+        assert!(!output.was_reencoded);
+        assert_eq!((output.width, output.height), (40, 50));
+
+        // One pixel off the spec is enough to need an encode
+        let upload = encode_jpeg(&photo(41, 50), 90, &format).unwrap();
+        let output = run(&exam(format), &upload, "photo").unwrap();
+        assert!(output.was_reencoded);
+        assert_ne!(output.data, upload);
+    }
+
+    #[test]
+    fn circle_crops_leave_transparent_corners() {
+        let format = DocumentFormat { crop_shape: "circle".to_string(), ..spec("PNG", 80, 60, 200) };
+        let output = run(&exam(format), &png(&photo(80, 60)), "photo").unwrap();
+        let masked = image::load_from_memory(&output.data).unwrap().to_rgba8();
+        assert_eq!(masked.dimensions(), (80, 60));
+        for (x, y) in [(0, 0), (79, 0), (0, 59), (79, 59), (5, 30), (74, 30)] {
+            assert_eq!(masked.get_pixel(x, y)[3], 0, "({}, {}) is outside the circle", x, y);
+        }
+        assert_eq!(masked.get_pixel(40, 30)[3], 255);
+
+        let format = DocumentFormat { crop_shape: "circle".to_string(), ..spec("JPEG", 80, 60, 200) };
+        match run(&exam(format), &png(&photo(80, 60)), "photo") {
+            Err(FormatError::InvalidConfig(message)) => assert!(message.contains("needs PNG"), "{}", message),
+            _ => panic!("a JPEG circle crop should be rejected"),
+        }
+    }
+
+    #[test]
+    fn downscale_only_shrinks_into_the_box_and_never_grows() {
+        let format = DocumentFormat { downscale_only: true, ..spec("JPEG", 600, 800, 300) };
+
+        let small = photo(120, 90);
+        let kept = resize_to_fit(&small, &format).unwrap();
+        assert_eq!(kept.to_rgb8(), small.to_rgb8());
+        assert!(!needs_upscale((120, 90), &format));
+
+        let large = resize_to_fit(&photo(1200, 600), &format).unwrap();
+        assert_eq!((large.width(), large.height()), (600, 300));
+        let output = run(&exam(format), &png(&photo(120, 90)), "documents").unwrap();
+        assert_eq!((output.width, output.height), (120, 90));
+        assert!(!output.upscaled);
+    }
+
+    #[test]
+    fn huge_declared_dimensions_are_rejected_before_decoding() {
+        // A real 16x16 JPEG whose frame header claims 40000x30000 (1200 MP);
+        // decoding it for real would need gigabytes
+        let mut bomb = jpeg(&photo(16, 16), 80);
+        let sof = bomb.windows(2).position(|marker| marker == [0xFF, 0xC0]).unwrap();
+        bomb[sof + 5..sof + 9].copy_from_slice(&[0x75, 0x30, 0x9C, 0x40]);
+
+        match decode_image(&bomb, 50) {
+            Err(FormatError::TooLarge(message)) => assert!(message.contains("40000x30000"), "{}", message),
+            _ => panic!("the pixel guard should reject the header"),
+        }
+        // Even decoded at 1/8 scale it would be 18.75 MP
+        let config = ExamConfig { max_megapixels: 10, ..exam(spec("JPEG", 30, 40, 50)) };
+        assert!(matches!(run(&config, &bomb, "photo"), Err(FormatError::TooLarge(_))));
+        // The same file under the limit gets as far as the decoder
+        assert!(check_pixel_limit(&jpeg(&photo(16, 16), 80), 1).is_ok());
+    }
+
+    #[test]
+    fn grayscale_png_is_smaller_with_the_same_luma() {
+        // Gray content held in an RGB buffer, as a scanner often delivers it
+        let img = DynamicImage::ImageRgb8(photo(120, 90).grayscale().to_rgb8());
+        let encode = |color_type: &str| {
+            let format = DocumentFormat { png_color_type: color_type.to_string(), ..spec("PNG", 120, 90, 200) };
+            encode_png(&img, &format).unwrap()
+        };
+        let (gray, rgb) = (encode("grayscale"), encode("rgb"));
+        assert!(gray.len() < rgb.len(), "grayscale {} bytes, rgb {} bytes", gray.len(), rgb.len());
+
+        let gray = image::load_from_memory(&gray).unwrap();
+        assert_eq!(gray.color(), ColorType::L8);
+        assert_eq!(gray.to_luma8(), image::load_from_memory(&rgb).unwrap().to_luma8());
+    }
+
+    #[test]
+    fn unknown_formats_fail_under_strict_and_fall_back_under_lenient() {
+        let img = photo(40, 50);
+        let data = encode_image(&img, &spec("PNG", 40, 50, 50), 85).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), image::ImageFormat::Png);
+
+        let strict = DocumentFormat { strict_format: true, ..spec("JPG2000", 40, 50, 50) };
+        match encode_image(&img, &strict, 85) {
+            Err(FormatError::UnknownOutputFormat(format)) => assert_eq!(format, "JPG2000"),
+            _ => panic!("strict mode should reject the format"),
+        }
+        assert!(matches!(run(&exam(strict), &png(&img), "photo"), Err(FormatError::UnknownOutputFormat(_))));
+
+        let lenient = DocumentFormat { strict_format: false, ..spec("JPG2000", 40, 50, 50) };
+        let data = encode_image(&img, &lenient, 85).unwrap();
+        assert_eq!(image::guess_format(&data).unwrap(), image::ImageFormat::Jpeg);
+    }
+
+    #[test]
+    fn truncated_uploads_fail_with_a_clear_error() {
+        let whole = jpeg(&photo(120, 90), 90);
+        assert!(!is_truncated(&whole));
+        // Header segments ahead of the scan are stepped over, not scanned
+        let with_thumbnail = with_exif(whole.clone(), &[orientation(1)]);
+        assert!(!is_truncated(&with_thumbnail));
+
+        let cut = &whole[..whole.len() * 2 / 3];
+        assert!(is_truncated(cut));
+        match run(&exam(spec("JPEG", 60, 45, 50)), cut, "photo") {
+            Err(error @ FormatError::Truncated) => assert_eq!(error.to_string(), "File appears incomplete, please re-select it"),
+            _ => panic!("a cut-off JPEG should be reported as truncated"),
+        }
+
+        let whole = png(&photo(120, 90));
+        assert!(is_truncated(&whole[..whole.len() - 12]));
+    }
+
+    #[test]
+    fn transparency_flattens_onto_white_not_black() {
+        // Left half fully transparent with black color values, as most editors save it
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(80, 60, |x, _| {
+            if x < 40 { image::Rgba([0, 0, 0, 0]) } else { image::Rgba([200, 30, 30, 255]) }
+        }));
+        let output = run(&exam(spec("JPEG", 80, 60, 50)), &png(&img), "photo").unwrap();
+        let flat = image::load_from_memory(&output.data).unwrap().to_rgb8();
+        assert!(flat.get_pixel(10, 30).0.iter().all(|&c| c >= 250), "{:?}", flat.get_pixel(10, 30));
+        assert!(flat.get_pixel(70, 30)[0] > 180);
+
+        let green = DocumentFormat { flatten_background: "#00FF00".to_string(), ..spec("JPEG", 80, 60, 50) };
+        let flat = image::load_from_memory(&run(&exam(green), &png(&img), "photo").unwrap().data).unwrap().to_rgb8();
+        let [r, g, b] = flat.get_pixel(10, 30).0;
+        assert!(r < 10 && g > 245 && b < 10, "{:?}", (r, g, b));
+    }
+
+    #[test]
+    fn unreachable_caps_shrink_the_image_or_fail_cleanly() {
+        let upload = png(&noise(300, 300));
+        let output = run(&exam(spec("JPEG", 300, 300, 5)), &upload, "photo").unwrap();
+        assert!(output.data.len() <= 5 * 1024, "{} bytes", output.data.len());
+        assert!(output.downscaled && output.width < 300 && output.height < 300);
+        let decoded = image::load_from_memory(&output.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (output.width, output.height));
+
+        // Too few encodes allowed to shrink that far
+        let tight = DocumentFormat { max_compress_iterations: 4, ..spec("JPEG", 300, 300, 1) };
+        match run(&exam(tight), &upload, "photo") {
+            Err(FormatError::CannotMeetSize { target_kb }) => assert_eq!(target_kb, 1),
+            Ok(output) => panic!("{} bytes came back for a 1KB cap", output.data.len()),
+            Err(other) => panic!("unexpected error {}", other),
+        }
+    }
+
+    // `tiff` with its first IFD copied to the end and linked as a second page;
+    // both pages point at the same strips
+    fn two_page_tiff(mut tiff: Vec<u8>) -> Vec<u8> {
+        assert_eq!(&tiff[..4], b"II*\0");
+        let first = u32::from_le_bytes(tiff[4..8].try_into().unwrap()) as usize;
+        let entries = u16::from_le_bytes([tiff[first], tiff[first + 1]]) as usize;
+        let next_at = first + 2 + entries * 12;
+        let mut ifd = tiff[first..next_at].to_vec();
+        ifd.extend_from_slice(&0u32.to_le_bytes());
+
+        tiff.resize(tiff.len().next_multiple_of(2), 0);
+        let second = tiff.len() as u32;
+        tiff[next_at..next_at + 4].copy_from_slice(&second.to_le_bytes());
+        tiff.extend_from_slice(&ifd);
+        tiff
+    }
+
+    #[test]
+    fn tiff_scans_decode_and_multi_page_files_use_the_first_page() {
+        let single = encode(&photo(90, 120), image::ImageOutputFormat::Tiff);
+        assert!(!is_multi_page_tiff(&single));
+        let config = ExamConfig { allowed_formats: vec!["image/tiff".to_string()], ..exam(spec("JPEG", 45, 60, 50)) };
+        let decoded = decode_image(&single, 50).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (90, 120));
+        let output = run(&config, &single, "documents").unwrap();
+        assert_eq!((output.width, output.height), (45, 60));
+
+        let multi = two_page_tiff(single);
+        assert!(is_multi_page_tiff(&multi));
+        // Only warned about: the first page is converted as usual
+        let output = run(&config, &multi, "documents").unwrap();
+        assert_eq!((output.width, output.height), (45, 60));
+    }
+
+    #[test]
+    fn extreme_aspect_mismatches_are_guarded() {
+        // 300x400 into 300x400 is exact; 400x400 is a 1.33 skew, inside the default 2.0
+        let format = spec("JPEG", 300, 400, 200);
+        for (w, h) in [(300, 400), (400, 400)] {
+            let output = run(&exam(format.clone()), &png(&photo(w, h)), "photo").unwrap();
+            assert_eq!((output.width, output.height), (300, 400));
+        }
+
+        // A 4:1 panorama squashed into 3:4 is a 5.3 skew
+        let panorama = png(&photo(800, 200));
+        match run(&exam(format.clone()), &panorama, "photo") {
+            Err(FormatError::AspectMismatch { width, height, target_width, target_height }) => {
+                assert_eq!((width, height, target_width, target_height), (800, 200, 300, 400))
+            }
+            _ => panic!("the skew guard should refuse to stretch a panorama"),
+        }
+
+        // With the fallback on it is letterboxed rather than stretched
+        let lenient = DocumentFormat { auto_fit_on_skew: true, ..format };
+        let output = run(&exam(lenient), &panorama, "photo").unwrap();
+        assert_eq!((output.width, output.height), (300, 400));
+        let boxed = image::load_from_memory(&output.data).unwrap().to_rgb8();
+        assert!(boxed.get_pixel(150, 5).0.iter().all(|&c| c >= 245), "top band should be padding");
+    }
+
+    #[test]
+    fn physical_sizes_round_to_the_nearest_pixel() {
+        // 3.5cm x 4.5cm at 200 DPI is 275.6 x 354.3 pixels
+        let mut passport = DocumentFormat { width_cm: Some(3.5), height_cm: Some(4.5), ..spec("JPEG", 0, 0, 100) };
+        assert_eq!(physical_pixels(&passport), Some((276, 354)));
+        apply_physical_size(&mut passport);
+        assert_eq!((passport.width, passport.height), (276, 354));
+
+        let cm_only = DocumentFormat { width_cm: Some(3.5), height_cm: Some(4.5), ..spec("JPEG", 0, 0, 100) };
+        let output = format(&png(&photo(552, 708)), &cm_only, "photo").unwrap();
+        assert_eq!((output.width, output.height), (276, 354));
+    }
+
+    #[test]
+    fn large_jpegs_decode_at_a_reduced_dct_scale() {
+        let scan = jpeg(&photo(1600, 1200), 90);
+        // The smallest eighth that still covers the box
+        for (min_size, decoded) in [((200, 150), (200, 150)), ((300, 200), (400, 300)), ((700, 500), (800, 600))] {
+            let img = decode_jpeg_direct(&scan, 50, Some(min_size)).unwrap().expect("DCT-scaled path");
+            assert_eq!((img.width(), img.height()), decoded, "for at least {:?}", min_size);
+        }
+        // Nothing to gain at full size, so `image` decodes it as usual
+        assert!(decode_jpeg_direct(&scan, 50, Some((1600, 1200))).unwrap().is_none());
+
+        let format = spec("JPEG", 200, 150, 200);
+        assert_eq!(decode_target(&scan, &format, &ProcessingOptions::new("photo", "scan.jpg")), Some((200, 150)));
+        let output = run(&exam(format), &scan, "photo").unwrap();
+        assert_eq!((output.width, output.height), (200, 150));
+    }
+
+    // Bounding box of the dark pixels, as (width, height)
+    fn ink_extent(data: &[u8]) -> (u32, u32) {
+        let gray = image::load_from_memory(data).unwrap().to_luma8();
+        let ink: Vec<(u32, u32)> = gray.enumerate_pixels().filter(|(_, _, p)| p[0] < 128).map(|(x, y, _)| (x, y)).collect();
+        let span = |axis: fn(&(u32, u32)) -> u32| ink.iter().map(axis).max().unwrap() - ink.iter().map(axis).min().unwrap() + 1;
+        (span(|&(x, _)| x), span(|&(_, y)| y))
+    }
+
+    #[test]
+    fn trimmed_signatures_fill_the_frame() {
+        // A 100x40 scrawl in the middle of a 1000x400 white sheet
+        let sheet = DynamicImage::ImageLuma8(image::GrayImage::from_fn(1000, 400, |x, y| {
+            let on_stroke = (450..550).contains(&x) && (y as i32 - 200 - ((x as i32 / 7) % 5 - 2) * 8).abs() < 3;
+            image::Luma([if on_stroke { 20 } else { 255 }])
+        }));
+        let format = DocumentFormat { fit_mode: "contain".to_string(), ..spec("PNG", 250, 100, 200) };
+
+        let (width, _) = ink_extent(&run(&exam(format.clone()), &png(&sheet), "signature").unwrap().data);
+        assert!(width < 40, "untrimmed strokes span {}px", width);
+
+        let trimmed = DocumentFormat { auto_trim: true, ..format };
+        let output = run(&exam(trimmed), &png(&sheet), "signature").unwrap();
+        assert_eq!((output.width, output.height), (250, 100));
+        let (width, height) = ink_extent(&output.data);
+        assert!(width > 180 || height > 75, "trimmed strokes span only {}x{}", width, height);
+    }
+
+    // Blank white first frame, as signature apps export, then the drawing
+    fn blank_then_content_gif() -> Vec<u8> {
+        use image::{Delay, Frame, RgbaImage};
+        let blank = RgbaImage::from_pixel(60, 40, image::Rgba([255, 255, 255, 255]));
+        let content = RgbaImage::from_fn(60, 40, |x, _| if x < 30 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 255, 255, 255]) });
+        let mut data = Vec::new();
+        let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+        let delay = Delay::from_numer_denom_ms(100, 1);
+        encoder.encode_frames([Frame::from_parts(blank, 0, 0, delay), Frame::from_parts(content, 0, 0, delay)]).unwrap();
+        drop(encoder);
+        data
+    }
+
+    #[test]
+    fn animated_gifs_use_the_frame_with_content() {
+        let gif = blank_then_content_gif();
+        let (img, frames) = decode_gif(&gif, 50, true).unwrap();
+        assert_eq!(frames, 2);
+        let rgb = img.to_rgb8();
+        assert!(rgb.get_pixel(10, 20)[0] < 30 && rgb.get_pixel(50, 20)[0] > 225);
+
+        let config = ExamConfig { allowed_formats: vec!["image/gif".to_string()], ..exam(spec("PNG", 60, 40, 50)) };
+        let output = run(&config, &gif, "signature").unwrap();
+        assert_eq!(output.frames_detected, 2);
+        assert!(image::load_from_memory(&output.data).unwrap().to_luma8().get_pixel(10, 20)[0] < 30);
+
+        let still_only = ExamConfig { allow_animated: false, ..config };
+        assert!(matches!(run(&still_only, &gif, "signature"), Err(FormatError::UnsupportedInput(_))));
+    }
+
+    // Solid CMYK JPEG of `ink` (0 = none, 255 = full). jpeg-encoder writes the
+    // Adobe convention, inverted samples plus an APP14 marker; without the
+    // marker the samples are stored as plain inks, as some scanners do
+    fn cmyk_jpeg(ink: [u8; 4], adobe: bool) -> Vec<u8> {
+        let stored = if adobe { ink } else { ink.map(|c| 255 - c) };
+        let pixels: Vec<u8> = (0..32 * 32).flat_map(|_| stored).collect();
+        let mut data = Vec::new();
+        jpeg_encoder::Encoder::new(&mut data, 95).encode(&pixels, 32, 32, jpeg_encoder::ColorType::Cmyk).unwrap();
+        if adobe {
+            return data;
+        }
+        let mut pos = 2;
+        while data[pos + 1] != 0xEE {
+            pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        }
+        let end = pos + 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        [&data[..pos], &data[end..]].concat()
+    }
+
+    #[test]
+    fn cmyk_jpegs_decode_to_their_true_colors() {
+        // Magenta plus yellow ink is red; half cyan plus a little black is a teal
+        for (ink, expected) in [([0, 255, 255, 0], [255, 0, 0]), ([128, 0, 0, 51], [102, 204, 204])] {
+            for adobe in [true, false] {
+                let data = cmyk_jpeg(ink, adobe);
+                assert_eq!(has_adobe_marker(&data), adobe);
+                let pixel = decode_image(&data, 50).unwrap().to_rgb8().get_pixel(16, 16).0;
+                assert!(pixel.iter().zip(expected).all(|(&c, e)| c.abs_diff(e) <= 4), "{:?} for {:?}, adobe {}", pixel, ink, adobe);
+            }
+        }
+        // `image` alone renders the plain file as a negative
+        let plain = cmyk_jpeg([0, 255, 255, 0], false);
+        assert!(image::load_from_memory(&plain).unwrap().to_rgb8().get_pixel(16, 16)[0] < 30);
+    }
+
+    #[test]
+    fn size_margin_lowers_the_effective_target() {
+        let upload = png(&noise(160, 160));
+        let plain = spec("JPEG", 160, 160, 20);
+        let unmargined = run(&exam(plain.clone()), &upload, "photo").unwrap();
+
+        let margined = DocumentFormat { size_margin_pct: 25.0, ..plain };
+        assert_eq!(with_size_margin(20 * 1024, &margined), 15 * 1024);
+        let output = run(&exam(margined), &upload, "photo").unwrap();
+        assert!(output.data.len() <= 15 * 1024, "{} bytes", output.data.len());
+        // Only the margin kept it this small
+        assert!(unmargined.data.len() > 15 * 1024, "{} bytes without a margin", unmargined.data.len());
+        assert!(output.quality_used < unmargined.quality_used);
+    }
+
+    #[test]
+    fn premultiplied_edges_flatten_without_a_dark_halo() {
+        // A white disc whose edge fades out over 10px, stored premultiplied:
+        // each color sample is already scaled by its alpha
+        let disc = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |x, y| {
+            let distance = ((x as f32 - 32.0).powi(2) + (y as f32 - 32.0).powi(2)).sqrt();
+            let alpha = ((30.0 - distance) / 10.0).clamp(0.0, 1.0);
+            let a = (alpha * 255.0).round() as u8;
+            image::Rgba([a, a, a, a])
+        }));
+        let darkest = |data: &[u8]| image::load_from_memory(data).unwrap().to_luma8().pixels().map(|p| p[0]).min().unwrap();
+
+        // Also when resampled on the way to the box
+        for side in [64, 40] {
+            let premultiplied = DocumentFormat { assume_premultiplied: true, ..spec("JPEG", side, side, 50) };
+            let output = run(&exam(premultiplied), &png(&disc), "photo").unwrap();
+            assert!(darkest(&output.data) >= 245, "darkest pixel {} at {}px", darkest(&output.data), side);
+        }
+
+        // Read as straight alpha the same file grows a gray ring
+        let output = run(&exam(spec("JPEG", 64, 64, 50)), &png(&disc), "photo").unwrap();
+        assert!(darkest(&output.data) < 215, "darkest pixel {}", darkest(&output.data));
+    }
+
+    #[test]
+    fn auto_format_keeps_the_upload_format_where_it_can() {
+        let auto = spec("AUTO", 80, 60, 50);
+        let config = exam(auto.clone());
+        let img = photo(80, 60);
+        for (upload, format) in [(png(&img), "PNG"), (jpeg(&img, 90), "JPEG")] {
+            assert_eq!(resolve_auto_format(&config, &auto, &upload).format, format);
+            assert_eq!(run(&config, &upload, "photo").unwrap().format, format);
+        }
+
+        // GIF uploads are accepted but cannot be written, so the image decides
+        let config = ExamConfig { allowed_formats: vec!["image/gif".to_string(), "image/png".to_string(), "image/jpeg".to_string()], ..config };
+        let gif_photo = encode(&img, image::ImageOutputFormat::Gif);
+        assert_eq!(resolve_auto_format(&config, &auto, &gif_photo).format, "JPEG");
+        let flat_ui = DynamicImage::ImageRgb8(RgbImage::from_fn(80, 60, |x, _| if x < 20 { image::Rgb([30, 60, 200]) } else { image::Rgb([250, 250, 250]) }));
+        assert_eq!(resolve_auto_format(&config, &auto, &encode(&flat_ui, image::ImageOutputFormat::Gif)).format, "PNG");
+        // With neither suited format allowed, the first writable one is used
+        let jpeg_only = ExamConfig { allowed_formats: vec!["image/gif".to_string(), "image/jpeg".to_string()], ..config };
+        assert_eq!(resolve_auto_format(&jpeg_only, &auto, &encode(&flat_ui, image::ImageOutputFormat::Gif)).format, "JPEG");
+        assert_eq!(run(&jpeg_only, &gif_photo, "photo").unwrap().format, "JPEG");
+    }
+
+    #[test]
+    fn compress_iterations_are_capped_and_reported() {
+        // No quality gets 160x160 of noise under 3KB, so it also has to shrink
+        let upload = png(&noise(160, 160));
+        let capped = |limit: u32| DocumentFormat { max_compress_iterations: limit, ..spec("JPEG", 160, 160, 3) };
+
+        let output = run(&exam(capped(8)), &upload, "photo").unwrap();
+        assert_eq!(output.compress_iterations, 8);
+        assert!(output.hit_iteration_limit && output.downscaled);
+        assert!(output.data.len() <= 3 * 1024);
+
+        let output = run(&exam(capped(50)), &upload, "photo").unwrap();
+        assert!(output.compress_iterations < 50 && !output.hit_iteration_limit);
+
+        // Too few encodes to reach any size that fits
+        assert!(matches!(run(&exam(capped(3)), &upload, "photo"), Err(FormatError::CannotMeetSize { target_kb: 3 })));
+    }
+
+    #[test]
+    fn auto_background_pads_with_the_paper_color() {
+        // Cream sheet with a dark block of text in the middle
+        let cream = image::Rgb([243, 235, 210]);
+        let scan = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 200, |x, y| {
+            if (60..140).contains(&x) && (60..140).contains(&y) { image::Rgb([30, 30, 30]) } else { cream }
+        }));
+        let format = DocumentFormat { fit_mode: "contain".to_string(), bg_color: "auto".to_string(), ..spec("PNG", 400, 200, 500) };
+        let output = image::load_from_memory(&run(&exam(format.clone()), &png(&scan), "documents").unwrap().data).unwrap().to_rgb8();
+        assert_eq!((output.width(), output.height()), (400, 200));
+        for (x, y) in [(5, 100), (394, 10), (50, 190)] {
+            assert_eq!(*output.get_pixel(x, y), cream, "padding at {},{}", x, y);
+        }
+
+        // Edges split between three colors have no paper to match
+        let stripes = [image::Rgb([200, 40, 40]), image::Rgb([40, 200, 40]), image::Rgb([40, 40, 200])];
+        let mixed = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 200, |x, _| stripes[(x * 3 / 200) as usize]));
+        let output = image::load_from_memory(&run(&exam(format), &png(&mixed), "documents").unwrap().data).unwrap().to_rgb8();
+        assert_eq!(*output.get_pixel(5, 100), image::Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn png_over_the_cap_falls_back_to_an_allowed_jpeg() {
+        let detailed = png(&noise(200, 200));
+        let format = spec("PNG", 200, 200, 30);
+        assert!(detailed.len() > 30 * 1024, "fixture PNG is only {} bytes", detailed.len());
+        let lossless = run(&exam(format.clone()), &detailed, "documents");
+        assert!(!matches!(&lossless, Ok(output) if output.format == "JPEG"));
+
+        let fallback = DocumentFormat { allow_format_fallback: true, ..format };
+        let output = run(&exam(fallback.clone()), &detailed, "documents").unwrap();
+        assert_eq!(output.format, "JPEG");
+        assert!(output.byte_size <= 30 * 1024, "{} bytes", output.byte_size);
+        assert_eq!(&output.data[..2], &[0xFF, 0xD8]);
+        assert!(output.transforms.contains(&"fallback jpeg".to_string()), "{:?}", output.transforms);
+
+        // Only formats the exam accepts are tried
+        let png_only = ExamConfig { allowed_formats: vec!["image/png".to_string()], ..exam(fallback) };
+        assert!(!matches!(run(&png_only, &detailed, "documents"), Ok(output) if output.format == "JPEG"));
+    }
+
+    #[test]
+    fn transforms_are_listed_in_the_order_they_ran() {
+        // A sideways phone shot: stored landscape, tagged to turn 90 degrees
+        let upload = with_exif(jpeg(&photo(320, 240), 90), &[orientation(6)]);
+        let output = run(&exam(spec("JPEG", 120, 160, 100)), &upload, "photo").unwrap();
+        assert_eq!(output.transforms, ["exif-rotate-90", "resize 120x160 stretch lanczos3", "jpeg q85", "dpi 200"]);
+
+        // Options switched on later in the pipeline show up after the resize
+        let styled = DocumentFormat { grayscale: true, sharpen: 0.5, ..spec("JPEG", 120, 160, 100) };
+        let output = run(&exam(styled), &upload, "photo").unwrap();
+        assert_eq!(output.transforms, ["exif-rotate-90", "resize 120x160 stretch lanczos3", "sharpen 0.5", "grayscale", "jpeg q85", "dpi 200"]);
+    }
+
+    #[test]
+    fn target_ssim_finds_the_lowest_quality_that_looks_right() {
+        let upload = png(&photo(200, 200));
+        let at = |threshold: f32| {
+            let format = DocumentFormat { target_ssim: Some(threshold), ..spec("JPEG", 200, 200, 200) };
+            run(&exam(format), &upload, "photo").unwrap()
+        };
+        let strict = at(0.98);
+        let loose = at(0.85);
+        for (output, threshold) in [(&strict, 0.98), (&loose, 0.85)] {
+            let ssim = output.ssim.unwrap();
+            assert!(ssim >= threshold, "ssim {} under {}", ssim, threshold);
+        }
+        assert!(loose.quality_used < strict.quality_used);
+        assert!(loose.byte_size < strict.byte_size, "{} vs {} bytes", loose.byte_size, strict.byte_size);
+
+        // Unset, quality stays fixed and no SSIM is measured
+        assert_eq!(run(&exam(spec("JPEG", 200, 200, 200)), &upload, "photo").unwrap().ssim, None);
+    }
+
+    #[test]
+    fn native_runs_are_deterministic() {
+        // Straight through `format`, with no JsValue or wasm-bindgen type in sight
+        let upload = png(&photo(240, 320));
+        let format_once = || format(&upload, &spec("JPEG", 120, 160, 100), "photo").unwrap();
+        let (first, second) = (format_once(), format_once());
+        assert_eq!((first.width, first.height, first.format.as_str()), (120, 160, "JPEG"));
+        assert!(image::load_from_memory(&first.data).is_ok());
+
+        // Same bytes, and the stepping test clock gives the same timings
+        assert_eq!(first.data, second.data);
+        assert_eq!(serde_json::to_value(first.timings).unwrap(), serde_json::to_value(second.timings).unwrap());
+        assert_eq!(first.timings.total_ms.fract(), 0.0);
+    }
+
+    #[test]
+    fn roi_optimize_keeps_the_face_sharper_than_the_background() {
+        // Textured skin-toned oval on a textured gray backdrop
+        let mut seed = 3u32;
+        let portrait = DynamicImage::ImageRgb8(image::RgbImage::from_fn(300, 400, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let grain = ((seed >> 16) % 24) as u8;
+            let (dx, dy) = ((x as f32 - 150.0) / 70.0, (y as f32 - 170.0) / 90.0);
+            if dx * dx + dy * dy <= 1.0 { image::Rgb([212 + grain, 160 + grain, 128 + grain]) } else { image::Rgb([90 + grain, 110 + grain, 130 + grain]) }
+        }));
+        let format = DocumentFormat { roi_optimize: true, ..spec("PNG", 300, 400, 1000) };
+        let output = run(&exam(format), &png(&portrait), "photo").unwrap();
+        let area = output.roi_box.expect("face area reported");
+        assert!(area.x < 90 && area.x + area.width > 210, "{:?}", area);
+
+        let result = image::load_from_memory(&output.data).unwrap();
+        let energy = |x, y, w, h| gradient_energy(&result.crop_imm(x, y, w, h));
+        let (face, background) = (energy(120, 140, 60, 60), energy(0, 340, 60, 60));
+        assert!(face > background * 2.0, "face {} vs background {}", face, background);
+
+        // Without the option both regions keep their grain
+        let uniform = image::load_from_memory(&run(&exam(spec("PNG", 300, 400, 1000)), &png(&portrait), "photo").unwrap().data).unwrap();
+        let background_before = gradient_energy(&uniform.crop_imm(0, 340, 60, 60));
+        assert!(background_before > background * 2.0, "{} vs {}", background_before, background);
+    }
+}
@@ -0,0 +1,816 @@
+// Pixel-level cleanup passes applied between decoding and encoding.
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, Rgb, Rgba, RgbaImage};
+use std::collections::HashMap;
+
+// Edge ring sampled for the background color, as a share of the short side
+const EDGE_RING: f32 = 0.02;
+// Bits dropped per channel so near-identical shades land in one bucket
+const COLOR_BUCKET_SHIFT: u8 = 3;
+
+// How far a channel may stray from the paper color and still count as paper
+// when trimming, and the margin kept around the strokes (share of their extent)
+const TRIM_TOLERANCE: i32 = 48;
+const TRIM_MARGIN: f32 = 0.05;
+
+// Share of the width either side of center searched for a booklet gutter,
+// and how much darker than the band's typical column the gutter must be
+const GUTTER_SEARCH_SPAN: f32 = 0.1;
+const GUTTER_MIN_CONTRAST: f32 = 24.0;
+
+// Width of the gray ramp kept around the ink threshold so strokes stay smooth
+const SIGNATURE_RAMP: f32 = 24.0;
+
+// Luminance distance from the most common value still counted as "the same"
+const BLANK_DELTA: i32 = 10;
+// Thumbnail edge used for blank detection
+const BLANK_EDGE: u32 = 256;
+
+// Share of pixels clipped at each end of the histogram by auto-contrast
+const CONTRAST_CLIP: f32 = 0.01;
+// Channels whose clipped range already spans this much are left alone
+const CONTRAST_SKIP_SPAN: u8 = 230;
+
+// Blur radius of the unsharp mask; small, since it runs on the final pixels
+const SHARPEN_SIGMA: f32 = 1.0;
+
+// Blur applied around the face by `roi_optimize`, and the width of the fade
+// from sharp to soft as a share of the short side
+const ROI_BLUR_SIGMA: f32 = 1.5;
+const ROI_FEATHER: f32 = 0.1;
+
+// Both images are compared in grayscale at this size, in overlapping windows
+const SSIM_EDGE: u32 = 128;
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STRIDE: u32 = 4;
+
+// Threshold ranks for ordered dithering
+const BAYER_4X4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+// Otsu's method: the threshold that maximises between-class variance
+pub fn otsu_threshold(gray: &GrayImage) -> u8 {
+    let mut histogram = [0u64; 256];
+    for pixel in gray.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+
+    let total = gray.pixels().len() as f64;
+    let weighted_total: f64 = histogram.iter().enumerate().map(|(v, &n)| v as f64 * n as f64).sum();
+
+    let (mut background_count, mut background_sum) = (0.0, 0.0);
+    let (mut best_threshold, mut best_variance) = (0u8, 0.0);
+    for (value, &count) in histogram.iter().enumerate() {
+        background_count += count as f64;
+        if background_count == 0.0 {
+            continue;
+        }
+        let foreground_count = total - background_count;
+        if foreground_count == 0.0 {
+            break;
+        }
+
+        background_sum += value as f64 * count as f64;
+        let background_mean = background_sum / background_count;
+        let foreground_mean = (weighted_total - background_sum) / foreground_count;
+        let variance = background_count * foreground_count * (background_mean - foreground_mean).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best_threshold = value as u8;
+        }
+    }
+    best_threshold
+}
+
+// Reduce to pure black and white around `threshold`. "floyd-steinberg"
+// spreads each pixel's rounding error onto the neighbours not yet visited and
+// "ordered" varies the threshold over a Bayer tile; both trade crisp edges for
+// smoother-looking tones. "none" thresholds plainly
+pub fn dither_bilevel(gray: &GrayImage, threshold: u8, method: &str) -> GrayImage {
+    let (width, height) = (gray.width(), gray.height());
+    match method {
+        "floyd-steinberg" => {
+            let mut values: Vec<f32> = gray.pixels().map(|p| p[0] as f32).collect();
+            let mut out = GrayImage::new(width, height);
+            let (w, h) = (width as usize, height as usize);
+            for y in 0..h {
+                for x in 0..w {
+                    let old = values[y * w + x];
+                    let new = if old >= threshold as f32 { 255.0 } else { 0.0 };
+                    out.put_pixel(x as u32, y as u32, Luma([new as u8]));
+                    let error = old - new;
+                    let mut spread = |dx: isize, dy: usize, weight: f32| {
+                        let nx = x as isize + dx;
+                        if (0..w as isize).contains(&nx) && y + dy < h {
+                            values[(y + dy) * w + nx as usize] += error * weight;
+                        }
+                    };
+                    spread(1, 0, 7.0 / 16.0);
+                    spread(-1, 1, 3.0 / 16.0);
+                    spread(0, 1, 5.0 / 16.0);
+                    spread(1, 1, 1.0 / 16.0);
+                }
+            }
+            out
+        }
+        "ordered" => ImageBuffer::from_fn(width, height, |x, y| {
+            let rank = BAYER_4X4[(y % 4) as usize][(x % 4) as usize] as f32;
+            let local = threshold as f32 + ((rank + 0.5) / 16.0 - 0.5) * 255.0;
+            Luma([if gray.get_pixel(x, y)[0] as f32 >= local { 255 } else { 0 }])
+        }),
+        _ => ImageBuffer::from_fn(width, height, |x, y| {
+            Luma([if gray.get_pixel(x, y)[0] >= threshold { 255 } else { 0 }])
+        }),
+    }
+}
+
+// Push paper to pure white and ink to black, keeping a short gray ramp at the
+// threshold so anti-aliased stroke edges survive downscaling
+pub fn clean_signature(img: &DynamicImage) -> DynamicImage {
+    let mut gray = img.to_luma8();
+    let threshold = otsu_threshold(&gray) as f32;
+    // Otsu's threshold is the brightest ink value, so the ramp starts just above it
+    let (low, high) = (threshold, threshold + SIGNATURE_RAMP);
+
+    for pixel in gray.pixels_mut() {
+        let value = pixel[0] as f32;
+        let cleaned = if value <= low {
+            0.0
+        } else if value >= high {
+            255.0
+        } else {
+            (value - low) / (high - low) * 255.0
+        };
+        *pixel = Luma([cleaned.round() as u8]);
+    }
+    DynamicImage::ImageLuma8(gray)
+}
+
+// Largest skew we will correct; beyond this a detection is more likely wrong
+pub const MAX_DESKEW_DEGREES: f32 = 15.0;
+// Long edge of the thumbnail used for skew detection
+const DESKEW_EDGE: u32 = 600;
+
+// Estimate the clockwise tilt of text lines in degrees. Dark pixels are
+// projected onto the vertical axis at each candidate angle; the angle whose
+// row histogram has the highest variance is the one where lines line up.
+pub fn detect_skew(img: &DynamicImage) -> f32 {
+    let gray = img.thumbnail(DESKEW_EDGE, DESKEW_EDGE).to_luma8();
+    let threshold = otsu_threshold(&gray);
+    let ink: Vec<(f32, f32)> = gray
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p[0] <= threshold)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect();
+    if ink.is_empty() {
+        return 0.0;
+    }
+
+    let rows = (gray.width() + gray.height()) as usize * 2;
+    let offset = rows as f32 / 2.0;
+    let score = |degrees: f32| -> f64 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let mut histogram = vec![0u32; rows];
+        for &(x, y) in &ink {
+            let row = (-x * sin + y * cos + offset) as usize;
+            histogram[row.min(rows - 1)] += 1;
+        }
+        let mean = ink.len() as f64 / rows as f64;
+        histogram.iter().map(|&n| (n as f64 - mean).powi(2)).sum()
+    };
+
+    // Coarse sweep in whole degrees, then refine around the best candidate
+    let best_of = |candidates: &mut dyn Iterator<Item = f32>| {
+        candidates
+            .map(|angle| (angle, score(angle)))
+            .fold((0.0, f64::MIN), |best, current| if current.1 > best.1 { current } else { best })
+            .0
+    };
+    let range = MAX_DESKEW_DEGREES as i32;
+    let coarse = best_of(&mut (-range..=range).map(|d| d as f32));
+    let fine = best_of(&mut (-10..=10).map(|step| coarse + step as f32 * 0.1));
+    fine.clamp(-MAX_DESKEW_DEGREES, MAX_DESKEW_DEGREES)
+}
+
+// Rotate clockwise by an arbitrary angle with bilinear sampling. With `expand`
+// the canvas grows to hold the whole rotated image; otherwise it keeps its size
+// and the corners are cropped. Uncovered areas are filled with `fill`.
+pub fn rotate(img: &DynamicImage, degrees: f32, fill: Rgba<u8>, expand: bool) -> DynamicImage {
+    let source = img.to_rgba8();
+    let (src_w, src_h) = source.dimensions();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+
+    let (out_w, out_h) = if expand {
+        (
+            (src_w as f32 * cos.abs() + src_h as f32 * sin.abs()).ceil() as u32,
+            (src_w as f32 * sin.abs() + src_h as f32 * cos.abs()).ceil() as u32,
+        )
+    } else {
+        (src_w, src_h)
+    };
+
+    let (src_cx, src_cy) = (src_w as f32 / 2.0, src_h as f32 / 2.0);
+    let (out_cx, out_cy) = (out_w as f32 / 2.0, out_h as f32 / 2.0);
+    let output = RgbaImage::from_fn(out_w, out_h, |x, y| {
+        // Map each output pixel back into the source with the inverse rotation
+        let (dx, dy) = (x as f32 + 0.5 - out_cx, y as f32 + 0.5 - out_cy);
+        let sx = dx * cos + dy * sin + src_cx - 0.5;
+        let sy = -dx * sin + dy * cos + src_cy - 0.5;
+        sample_bilinear(&source, sx, sy).unwrap_or(fill)
+    });
+    DynamicImage::ImageRgba8(output)
+}
+
+fn sample_bilinear(img: &RgbaImage, x: f32, y: f32) -> Option<Rgba<u8>> {
+    let (w, h) = img.dimensions();
+    if x < -0.5 || y < -0.5 || x > w as f32 - 0.5 || y > h as f32 - 0.5 {
+        return None;
+    }
+
+    let (x, y) = (x.clamp(0.0, (w - 1) as f32), y.clamp(0.0, (h - 1) as f32));
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let (p00, p10) = (img.get_pixel(x0, y0), img.get_pixel(x1, y0));
+    let (p01, p11) = (img.get_pixel(x0, y1), img.get_pixel(x1, y1));
+    let mut out = [0u8; 4];
+    for (c, value) in out.iter_mut().enumerate() {
+        let top = p00[c] as f32 * (1.0 - fx) + p10[c] as f32 * fx;
+        let bottom = p01[c] as f32 * (1.0 - fx) + p11[c] as f32 * fx;
+        *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    Some(Rgba(out))
+}
+
+// Frame the image with a solid band of `width` pixels on every side
+pub fn add_border(img: &DynamicImage, width: u32, color: Rgba<u8>) -> DynamicImage {
+    if width == 0 {
+        return img.clone();
+    }
+
+    let mut canvas = RgbaImage::from_pixel(img.width() + width * 2, img.height() + width * 2, color);
+    image::imageops::overlay(&mut canvas, &img.to_rgba8(), width as i64, width as i64);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// Pad the shorter axis with `color` until the image is exactly
+// `ratio_w:ratio_h`, keeping the source pixels centred and unscaled
+pub fn pad_to_aspect(img: &DynamicImage, ratio_w: u32, ratio_h: u32, color: Rgba<u8>) -> DynamicImage {
+    let (w, h) = (img.width() as u64, img.height() as u64);
+    let (rw, rh) = (ratio_w as u64, ratio_h as u64);
+    let (new_w, new_h) = if w * rh < h * rw {
+        (((h * rw).div_ceil(rh)) as u32, h as u32)
+    } else {
+        (w as u32, ((w * rh).div_ceil(rw)) as u32)
+    };
+    if (new_w, new_h) == (img.width(), img.height()) {
+        return img.clone();
+    }
+
+    let mut canvas = RgbaImage::from_pixel(new_w, new_h, color);
+    let x = (new_w - img.width()) / 2;
+    let y = (new_h - img.height()) / 2;
+    image::imageops::overlay(&mut canvas, &img.to_rgba8(), x as i64, y as i64);
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// `resize_exact` that resamples straight alpha premultiplied, so the color
+// under fully transparent pixels (often black) doesn't bleed into soft edges.
+// Already-premultiplied sources resample correctly as they are
+pub fn resize_exact(img: &DynamicImage, width: u32, height: u32, filter: FilterType, premultiplied: bool) -> DynamicImage {
+    if premultiplied || !img.color().has_alpha() {
+        return img.resize_exact(width, height, filter);
+    }
+
+    let mut buf = img.to_rgba32f();
+    for pixel in buf.pixels_mut() {
+        let alpha = pixel[3];
+        pixel.0[..3].iter_mut().for_each(|channel| *channel *= alpha);
+    }
+    let mut buf = image::imageops::resize(&buf, width, height, filter);
+    for pixel in buf.pixels_mut() {
+        let alpha = pixel[3];
+        if alpha > 0.0 {
+            pixel.0[..3].iter_mut().for_each(|channel| *channel = (*channel / alpha).min(1.0));
+        }
+    }
+    match img {
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLumaA16(_) => {
+            DynamicImage::ImageLumaA8(DynamicImage::ImageRgba32F(buf).to_luma_alpha8())
+        }
+        _ => DynamicImage::ImageRgba8(DynamicImage::ImageRgba32F(buf).to_rgba8()),
+    }
+}
+
+// Composite onto a solid `background`, dropping the alpha channel. Grayscale
+// stays grayscale, over the background's luma. Alpha is taken as straight
+// unless `premultiplied`, in which case it is divided out first
+pub fn flatten(img: &DynamicImage, background: Rgba<u8>, premultiplied: bool) -> DynamicImage {
+    let blend = |value: u8, under: u8, alpha: u8| {
+        let value = if premultiplied && alpha > 0 {
+            ((value as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255)
+        } else {
+            value as u32
+        };
+        ((value * alpha as u32 + under as u32 * (255 - alpha as u32) + 127) / 255) as u8
+    };
+
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => {
+            let under = background.to_luma()[0];
+            let buf = img.to_luma_alpha8();
+            DynamicImage::ImageLuma8(ImageBuffer::from_fn(buf.width(), buf.height(), |x, y| {
+                let [value, alpha] = buf.get_pixel(x, y).0;
+                Luma([blend(value, under, alpha)])
+            }))
+        }
+        _ => {
+            let buf = img.to_rgba8();
+            DynamicImage::ImageRgb8(ImageBuffer::from_fn(buf.width(), buf.height(), |x, y| {
+                let [r, g, b, alpha] = buf.get_pixel(x, y).0;
+                Rgb([blend(r, background[0], alpha), blend(g, background[1], alpha), blend(b, background[2], alpha)])
+            }))
+        }
+    }
+}
+
+// Place the images one after another, each scaled so the shared side (width
+// when stacking vertically, height side by side) is `side`
+pub fn stitch(images: &[DynamicImage], vertical: bool, side: u32, gap: u32, color: Rgba<u8>) -> DynamicImage {
+    let scaled: Vec<RgbaImage> = images
+        .iter()
+        .map(|img| {
+            let (shared, other) = if vertical { (img.width(), img.height()) } else { (img.height(), img.width()) };
+            let length = ((other as u64 * side as u64 + shared as u64 / 2) / shared.max(1) as u64).max(1) as u32;
+            let (w, h) = if vertical { (side, length) } else { (length, side) };
+            if (w, h) == (img.width(), img.height()) {
+                img.to_rgba8()
+            } else {
+                img.resize_exact(w, h, image::imageops::FilterType::Lanczos3).to_rgba8()
+            }
+        })
+        .collect();
+
+    let gaps = gap * (scaled.len() as u32).saturating_sub(1);
+    let total: u32 = scaled.iter().map(|part| if vertical { part.height() } else { part.width() }).sum::<u32>() + gaps;
+    let (w, h) = if vertical { (side, total) } else { (total, side) };
+    let mut canvas = RgbaImage::from_pixel(w, h, color);
+    let mut offset = 0;
+    for part in &scaled {
+        let (x, y) = if vertical { (0, offset) } else { (offset, 0) };
+        image::imageops::overlay(&mut canvas, part, x as i64, y as i64);
+        offset += if vertical { part.height() } else { part.width() } + gap;
+    }
+    DynamicImage::ImageRgba8(canvas)
+}
+
+// Make everything outside the ellipse inscribed in the image transparent. A
+// circle uses the largest centred circle rather than the full box
+pub fn mask_ellipse(img: &DynamicImage, circle: bool) -> DynamicImage {
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let (rx, ry) = if circle { (w.min(h) / 2.0, w.min(h) / 2.0) } else { (w / 2.0, h / 2.0) };
+    let inside = |x: u32, y: u32| {
+        let dx = (x as f32 + 0.5 - w / 2.0) / rx;
+        let dy = (y as f32 + 0.5 - h / 2.0) / ry;
+        dx * dx + dy * dy <= 1.0
+    };
+
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => {
+            let mut buf = img.to_luma_alpha8();
+            for (x, y, pixel) in buf.enumerate_pixels_mut() {
+                if !inside(x, y) {
+                    pixel[1] = 0;
+                }
+            }
+            DynamicImage::ImageLumaA8(buf)
+        }
+        _ => {
+            let mut buf = img.to_rgba8();
+            for (x, y, pixel) in buf.enumerate_pixels_mut() {
+                if !inside(x, y) {
+                    pixel[3] = 0;
+                }
+            }
+            DynamicImage::ImageRgba8(buf)
+        }
+    }
+}
+
+// Blur everything outside `keep` (x, y, width, height) a little, fading in
+// over a band a tenth of the short side wide. Soft areas cost JPEG far fewer
+// bytes, so a face keeps its detail while the file shrinks
+pub fn soften_outside(img: &DynamicImage, keep: (u32, u32, u32, u32)) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(buf) => DynamicImage::ImageLuma8(blur_outside(buf, keep)),
+        DynamicImage::ImageLumaA8(buf) => DynamicImage::ImageLumaA8(blur_outside(buf, keep)),
+        DynamicImage::ImageRgb8(buf) => DynamicImage::ImageRgb8(blur_outside(buf, keep)),
+        other => DynamicImage::ImageRgba8(blur_outside(&other.to_rgba8(), keep)),
+    }
+}
+
+fn blur_outside<P>(buf: &ImageBuffer<P, Vec<u8>>, keep: (u32, u32, u32, u32)) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let blurred = image::imageops::blur(buf, ROI_BLUR_SIGMA);
+    let (left, top) = (keep.0 as f32, keep.1 as f32);
+    let (right, bottom) = (left + keep.2 as f32, top + keep.3 as f32);
+    let feather = (buf.width().min(buf.height()) as f32 * ROI_FEATHER).max(1.0);
+    let channels = P::CHANNEL_COUNT as usize;
+    let color_channels = if channels == 2 || channels == 4 { channels - 1 } else { channels };
+
+    let mut output = buf.clone();
+    for (x, y, pixel) in output.enumerate_pixels_mut() {
+        let (fx, fy) = (x as f32 + 0.5, y as f32 + 0.5);
+        // Distance outside the kept box, 0 inside it
+        let dx = (left - fx).max(fx - right).max(0.0);
+        let dy = (top - fy).max(fy - bottom).max(0.0);
+        let softness = (dx.hypot(dy) / feather).min(1.0);
+        if softness == 0.0 {
+            continue;
+        }
+        let soft = blurred.get_pixel(x, y);
+        for (value, &blur) in pixel.channels_mut().iter_mut().zip(soft.channels()).take(color_channels) {
+            *value = (*value as f32 + softness * (blur as f32 - *value as f32)).round() as u8;
+        }
+    }
+    output
+}
+
+// Unsharp mask: add back `amount` times the difference from a Gaussian blur,
+// which boosts edges that a downscale softened. Alpha is left alone
+pub fn sharpen(img: &DynamicImage, amount: f32) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(buf) => DynamicImage::ImageLuma8(unsharp_mask(buf, amount)),
+        DynamicImage::ImageLumaA8(buf) => DynamicImage::ImageLumaA8(unsharp_mask(buf, amount)),
+        DynamicImage::ImageRgb8(buf) => DynamicImage::ImageRgb8(unsharp_mask(buf, amount)),
+        other => DynamicImage::ImageRgba8(unsharp_mask(&other.to_rgba8(), amount)),
+    }
+}
+
+fn unsharp_mask<P>(buf: &ImageBuffer<P, Vec<u8>>, amount: f32) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let blurred = image::imageops::blur(buf, SHARPEN_SIGMA);
+    let channels = P::CHANNEL_COUNT as usize;
+    let has_alpha = channels == 2 || channels == 4;
+
+    let mut output = buf.clone();
+    for (i, (value, &soft)) in output.iter_mut().zip(blurred.iter()).enumerate() {
+        if has_alpha && i % channels == channels - 1 {
+            continue;
+        }
+        let original = *value as f32;
+        *value = (original + amount * (original - soft as f32)).round().clamp(0.0, 255.0) as u8;
+    }
+    output
+}
+
+// Fraction of pixels within a small luminance delta of the modal value. An
+// empty scan or all-white signature sits close to 1.0; real content pulls it down
+pub fn uniform_coverage(img: &DynamicImage) -> f32 {
+    let gray = img.thumbnail(BLANK_EDGE, BLANK_EDGE).to_luma8();
+    let mut histogram = [0u32; 256];
+    for pixel in gray.pixels() {
+        histogram[pixel[0] as usize] += 1;
+    }
+
+    let total = gray.pixels().len() as f32;
+    if total == 0.0 {
+        return 1.0;
+    }
+    let mode = (0..256).max_by_key(|&value| histogram[value]).unwrap_or(0) as i32;
+    let near_mode: u32 = ((mode - BLANK_DELTA).max(0)..=(mode + BLANK_DELTA).min(255))
+        .map(|value| histogram[value as usize])
+        .sum();
+    near_mode as f32 / total
+}
+
+// Crop to the bounding box of everything that is not background, plus a small
+// margin. The background is the edge color, or transparency when the corner is
+// transparent. None when nothing but background was found
+pub fn trim_to_content(img: &DynamicImage) -> Option<DynamicImage> {
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    if w == 0 || h == 0 {
+        return None;
+    }
+    let background = edge_color(img);
+    let transparent_background = rgba.get_pixel(0, 0)[3] < 128;
+    let is_content = |pixel: &Rgba<u8>| {
+        if pixel[3] < 128 {
+            return false;
+        }
+        transparent_background || (0..3).any(|i| (pixel[i] as i32 - background[i] as i32).abs() > TRIM_TOLERANCE)
+    };
+
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        if is_content(pixel) {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+    }
+    if left > right {
+        return None;
+    }
+
+    // Scaling the margin per axis keeps the strokes' own aspect ratio
+    let margin_x = ((right - left + 1) as f32 * TRIM_MARGIN).ceil() as u32;
+    let margin_y = ((bottom - top + 1) as f32 * TRIM_MARGIN).ceil() as u32;
+    let (left, top) = (left.saturating_sub(margin_x), top.saturating_sub(margin_y));
+    let (right, bottom) = ((right + 1 + margin_x).min(w), (bottom + 1 + margin_y).min(h));
+    Some(img.crop_imm(left, top, right - left, bottom - top))
+}
+
+// Column of the fold shadow in a scanned open booklet: the darkest column
+// near the center, if it stands out from its neighbors. None for scans
+// without a visible gutter
+pub fn find_gutter(img: &DynamicImage) -> Option<u32> {
+    let gray = img.to_luma8();
+    let (w, h) = gray.dimensions();
+    let span = (w as f32 * GUTTER_SEARCH_SPAN).round() as u32;
+    let (first, last) = ((w / 2).saturating_sub(span), (w / 2 + span).min(w.saturating_sub(1)));
+    if h == 0 || first >= last {
+        return None;
+    }
+
+    let means: Vec<(u32, f32)> = (first..=last)
+        .map(|x| (x, (0..h).map(|y| gray.get_pixel(x, y)[0] as u32).sum::<u32>() as f32 / h as f32))
+        .collect();
+    let mut sorted: Vec<f32> = means.iter().map(|&(_, mean)| mean).collect();
+    sorted.sort_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let &(x, darkest) = means.iter().min_by(|a, b| a.1.total_cmp(&b.1))?;
+    (median - darkest >= GUTTER_MIN_CONTRAST).then_some(x)
+}
+
+// Most common color around the image edge, e.g. the paper of a scan. Colors
+// are bucketed first and the winning bucket's true average is returned
+pub fn edge_color(img: &DynamicImage) -> Rgb<u8> {
+    dominant_edge_color(img).0
+}
+
+// `edge_color` along with the share of the edge ring it covers, which is low
+// when no one color dominates
+pub fn dominant_edge_color(img: &DynamicImage) -> (Rgb<u8>, f32) {
+    let rgb = img.to_rgb8();
+    let (w, h) = rgb.dimensions();
+    if w == 0 || h == 0 {
+        return (Rgb([255, 255, 255]), 1.0);
+    }
+    let ring = ((w.min(h) as f32 * EDGE_RING).ceil() as u32).max(1);
+
+    let mut buckets: HashMap<[u8; 3], (u32, [u64; 3])> = HashMap::new();
+    let mut total = 0;
+    for (x, y, pixel) in rgb.enumerate_pixels() {
+        if x >= ring && x < w.saturating_sub(ring) && y >= ring && y < h.saturating_sub(ring) {
+            continue;
+        }
+        total += 1;
+        let key = pixel.0.map(|c| c >> COLOR_BUCKET_SHIFT);
+        let (count, sums) = buckets.entry(key).or_insert((0, [0; 3]));
+        *count += 1;
+        for (sum, &c) in sums.iter_mut().zip(&pixel.0) {
+            *sum += c as u64;
+        }
+    }
+
+    let (count, sums) = buckets.into_values().max_by_key(|(count, _)| *count).unwrap_or((1, [255; 3]));
+    let color = Rgb(sums.map(|sum| (sum as f64 / count as f64).round() as u8));
+    (color, count as f32 / total.max(1) as f32)
+}
+
+// Mean structural similarity: 1.0 for identical images, near or below 0 for
+// unrelated ones. Dimensions need not match; both are resized first
+pub fn ssim(a: &DynamicImage, b: &DynamicImage) -> f32 {
+    let prepare = |img: &DynamicImage| img.resize_exact(SSIM_EDGE, SSIM_EDGE, image::imageops::FilterType::Triangle).to_luma8();
+    let (a, b) = (prepare(a), prepare(b));
+    let c1 = (0.01 * 255.0f64).powi(2);
+    let c2 = (0.03 * 255.0f64).powi(2);
+    let n = (SSIM_WINDOW * SSIM_WINDOW) as f64;
+
+    let mut total = 0.0;
+    let mut windows = 0;
+    for top in (0..=SSIM_EDGE - SSIM_WINDOW).step_by(SSIM_STRIDE as usize) {
+        for left in (0..=SSIM_EDGE - SSIM_WINDOW).step_by(SSIM_STRIDE as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in top..top + SSIM_WINDOW {
+                for x in left..left + SSIM_WINDOW {
+                    let va = a.get_pixel(x, y)[0] as f64;
+                    let vb = b.get_pixel(x, y)[0] as f64;
+                    sum_a += va;
+                    sum_b += vb;
+                    sum_aa += va * va;
+                    sum_bb += vb * vb;
+                    sum_ab += va * vb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covariance = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2))
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+        }
+    }
+    (total / windows as f64) as f32
+}
+
+// Histogram stretch for faded scans: clip the darkest and lightest 1% of each
+// color channel (luminance for grayscale) and remap the rest to the full range.
+// Well-exposed channels are skipped so good photos are not pushed further
+pub fn auto_contrast(img: &DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(buf) => DynamicImage::ImageLuma8(stretch_channels(buf)),
+        DynamicImage::ImageLumaA8(buf) => DynamicImage::ImageLumaA8(stretch_channels(buf)),
+        DynamicImage::ImageRgb8(buf) => DynamicImage::ImageRgb8(stretch_channels(buf)),
+        other => DynamicImage::ImageRgba8(stretch_channels(&other.to_rgba8())),
+    }
+}
+
+fn stretch_channels<P>(buf: &ImageBuffer<P, Vec<u8>>) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let channels = P::CHANNEL_COUNT as usize;
+    let color_channels = if channels == 2 || channels == 4 { channels - 1 } else { channels };
+
+    let mut histograms = vec![[0u32; 256]; color_channels];
+    for pixel in buf.chunks_exact(channels) {
+        for (histogram, &value) in histograms.iter_mut().zip(pixel) {
+            histogram[value as usize] += 1;
+        }
+    }
+
+    let clip = (buf.pixels().len() as f32 * CONTRAST_CLIP) as u32;
+    let lookups: Vec<Option<[u8; 256]>> = histograms
+        .iter()
+        .map(|histogram| {
+            let (low, high) = clipped_range(histogram, clip);
+            if high <= low || high - low >= CONTRAST_SKIP_SPAN {
+                return None;
+            }
+            let scale = 255.0 / (high - low) as f32;
+            Some(std::array::from_fn(|value| {
+                ((value as f32 - low as f32) * scale).round().clamp(0.0, 255.0) as u8
+            }))
+        })
+        .collect();
+
+    let mut output = buf.clone();
+    for pixel in output.chunks_exact_mut(channels) {
+        for (value, lookup) in pixel.iter_mut().zip(&lookups) {
+            if let Some(lookup) = lookup {
+                *value = lookup[*value as usize];
+            }
+        }
+    }
+    output
+}
+
+// Darkest and lightest values left after dropping `clip` pixels from each end
+fn clipped_range(histogram: &[u32; 256], clip: u32) -> (u8, u8) {
+    let mut seen = 0;
+    let low = (0..256).find(|&value| {
+        seen += histogram[value];
+        seen > clip
+    });
+    seen = 0;
+    let high = (0..256).rev().find(|&value| {
+        seen += histogram[value];
+        seen > clip
+    });
+    (low.unwrap_or(0) as u8, high.unwrap_or(255) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::gradient_energy;
+
+    // Dull gray paper (around 170) with darker strokes (around 110) in a band
+    fn faded_signature() -> DynamicImage {
+        let mut seed = 3u32;
+        DynamicImage::ImageLuma8(GrayImage::from_fn(200, 80, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let jitter = (seed >> 16) as u8 % 8;
+            let stroke = (30..170).contains(&x) && (y as i32 - 40 + ((x as i32 / 10) % 3 - 1) * 8).abs() < 4;
+            Luma([if stroke { 106 + jitter } else { 166 + jitter }])
+        }))
+    }
+
+    #[test]
+    fn signature_cleanup_splits_paper_and_ink() {
+        let cleaned = clean_signature(&faded_signature()).to_luma8();
+        let total = cleaned.pixels().len() as f32;
+        let white = cleaned.pixels().filter(|p| p[0] >= 250).count() as f32 / total;
+        let black = cleaned.pixels().filter(|p| p[0] <= 5).count() as f32 / total;
+        assert!(white > 0.8, "white share {}", white);
+        assert!(black > 0.05, "ink share {}", black);
+        assert!(white + black > 0.99, "only {} of pixels at either end", white + black);
+    }
+
+    // White page with ruled dark lines like lines of text
+    fn ruled_page() -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(400, 500, |x, y| {
+            let line = y % 25 < 4 && (40..360).contains(&x) && (40..460).contains(&y);
+            Luma([if line { 20 } else { 250 }])
+        }))
+    }
+
+    #[test]
+    fn detects_the_applied_skew() {
+        let white = Rgba([255, 255, 255, 255]);
+        assert!(detect_skew(&ruled_page()).abs() < 1.0);
+        for skew in [4.0, -7.0] {
+            let detected = detect_skew(&rotate(&ruled_page(), skew, white, false));
+            assert!((detected - skew).abs() <= 1.0, "applied {}, detected {}", skew, detected);
+        }
+    }
+
+    #[test]
+    fn sharpen_steepens_a_soft_edge() {
+        // Dark to light over an eight-pixel ramp
+        let edge = DynamicImage::ImageLuma8(GrayImage::from_fn(64, 32, |x, _| {
+            Luma([(60 + (x as i32 - 28).clamp(0, 8) * 17) as u8])
+        }));
+        let plain = gradient_energy(&edge);
+        let sharpened = gradient_energy(&sharpen(&edge, 1.0));
+        assert!(sharpened > plain * 1.1, "{} vs {}", sharpened, plain);
+        // Zero leaves the pixels alone
+        assert_eq!(sharpen(&edge, 0.0).to_luma8(), edge.to_luma8());
+    }
+
+    fn spread(img: &DynamicImage) -> (u8, u8) {
+        let gray = img.to_luma8();
+        let (min, max) = gray.pixels().fold((255, 0), |(min, max), p| (p[0].min(min), p[0].max(max)));
+        (min, max)
+    }
+
+    #[test]
+    fn auto_contrast_widens_a_faded_scan() {
+        // A gradient squeezed into 100..=155, like a washed-out photocopy
+        let faded = DynamicImage::ImageLuma8(GrayImage::from_fn(256, 64, |x, _| Luma([100 + (x * 56 / 256) as u8])));
+        assert_eq!(spread(&faded), (100, 155));
+
+        let (min, max) = spread(&auto_contrast(&faded));
+        assert!(min <= 5 && max >= 250, "stretched to {}..={}", min, max);
+
+        // A scan already using the full range is left alone
+        let full = DynamicImage::ImageLuma8(GrayImage::from_fn(256, 64, |x, _| Luma([x as u8])));
+        assert_eq!(auto_contrast(&full).to_luma8(), full.to_luma8());
+    }
+
+    #[test]
+    fn edge_color_of_a_white_bordered_image_is_white() {
+        // A dark photo that fills the middle, framed by slightly noisy white paper
+        let mut seed = 5u32;
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(200, 160, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let jitter = (seed >> 16) as u8 % 6;
+            if (30..170).contains(&x) && (30..130).contains(&y) {
+                Rgb([40 + jitter, 60, 90])
+            } else {
+                Rgb([250 + jitter, 250 + jitter, 249 + jitter])
+            }
+        }));
+        let (color, share) = dominant_edge_color(&img);
+        assert!(color.0.iter().all(|&c| c >= 248), "{:?}", color);
+        assert!(share > 0.9, "white covers {} of the edge", share);
+    }
+
+    // Horizontal black/white changes, counting each row
+    fn transitions(img: &GrayImage) -> usize {
+        img.rows().map(|row| {
+            let row: Vec<u8> = row.map(|p| p[0]).collect();
+            row.windows(2).filter(|pair| pair[0] != pair[1]).count()
+        }).sum()
+    }
+
+    #[test]
+    fn dithering_renders_a_gradient_as_patterns() {
+        let gradient = GrayImage::from_fn(256, 32, |x, _| Luma([x as u8]));
+        let plain = dither_bilevel(&gradient, 128, "none");
+        let diffused = dither_bilevel(&gradient, 128, "floyd-steinberg");
+        let ordered = dither_bilevel(&gradient, 128, "ordered");
+
+        for output in [&plain, &diffused, &ordered] {
+            assert!(output.pixels().all(|p| p[0] == 0 || p[0] == 255));
+        }
+        // A plain threshold flips once per row, at the midpoint
+        assert_eq!(transitions(&plain), 32);
+        assert!(transitions(&diffused) > 32 * 20, "{} transitions", transitions(&diffused));
+        assert!(transitions(&ordered) > 32 * 20, "{} transitions", transitions(&ordered));
+
+        // Error diffusion keeps the average tone of each band
+        let mean = |img: &GrayImage, x0: u32| (x0..x0 + 32).flat_map(|x| (0..32).map(move |y| (x, y))).map(|(x, y)| img.get_pixel(x, y)[0] as f32).sum::<f32>() / 1024.0;
+        assert!((mean(&diffused, 48) - 64.0).abs() < 24.0, "band at 64 averages {}", mean(&diffused, 48));
+    }
+}
@@ -0,0 +1,312 @@
+// Typed errors surfaced to JS as `{ code, message }` objects, so the frontend
+// can branch on `code` while `message` is shown to the user in the language
+// set through `set_language`. Logs always get the English text.
+
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use wasm_bindgen::JsValue;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Language {
+    English,
+    Hindi,
+    Tamil,
+}
+
+impl Language {
+    pub fn parse(code: &str) -> Option<Language> {
+        match code.trim().to_lowercase().as_str() {
+            "en" => Some(Language::English),
+            "hi" => Some(Language::Hindi),
+            "ta" => Some(Language::Tamil),
+            _ => None,
+        }
+    }
+}
+
+static LANGUAGE: AtomicU8 = AtomicU8::new(Language::English as u8);
+
+pub fn set_language(language: Language) {
+    LANGUAGE.store(language as u8, Ordering::Relaxed);
+}
+
+fn language() -> Language {
+    match LANGUAGE.load(Ordering::Relaxed) {
+        1 => Language::Hindi,
+        2 => Language::Tamil,
+        _ => Language::English,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    ConfigNotSet,
+    UnknownExamCode { code: String, available: Vec<String> },
+    DuplicateExamCode(String),
+    InvalidConfig(String),
+    ConfigValidation(Vec<String>),
+    InvalidInput(String),
+    UnrecognizedFormat,
+    UnsupportedInput(String),
+    FormatNotAllowed { format: String, exam: String, allowed: Vec<String> },
+    UnsupportedOutputFormat(String),
+    UnknownOutputFormat(String),
+    UnknownDocumentType { document_type: String, allowed: Vec<String> },
+    UpscaleBlocked { width: u32, height: u32, target_width: u32, target_height: u32 },
+    AspectMismatch { width: u32, height: u32, target_width: u32, target_height: u32 },
+    DecodeFailed(String),
+    Truncated,
+    EncodeFailed(String),
+    TooLarge(String),
+    CannotMeetSize { target_kb: u32 },
+}
+
+impl FormatError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            FormatError::ConfigNotSet => "CONFIG_NOT_SET",
+            FormatError::UnknownExamCode { .. } => "UNKNOWN_EXAM_CODE",
+            FormatError::DuplicateExamCode(_) => "DUPLICATE_EXAM_CODE",
+            FormatError::InvalidConfig(_) => "INVALID_CONFIG",
+            FormatError::ConfigValidation(_) => "CONFIG_VALIDATION_FAILED",
+            FormatError::InvalidInput(_) => "INVALID_INPUT",
+            FormatError::UnrecognizedFormat => "UNRECOGNIZED_FORMAT",
+            FormatError::UnsupportedInput(_) => "UNSUPPORTED_INPUT_FORMAT",
+            FormatError::FormatNotAllowed { .. } => "FORMAT_NOT_ALLOWED",
+            FormatError::UnsupportedOutputFormat(_) => "UNSUPPORTED_OUTPUT_FORMAT",
+            FormatError::UnknownOutputFormat(_) => "UNKNOWN_OUTPUT_FORMAT",
+            FormatError::UnknownDocumentType { .. } => "UNKNOWN_DOCUMENT_TYPE",
+            FormatError::UpscaleBlocked { .. } => "UPSCALE_BLOCKED",
+            FormatError::AspectMismatch { .. } => "ASPECT_MISMATCH",
+            FormatError::DecodeFailed(_) => "DECODE_FAILED",
+            FormatError::Truncated => "TRUNCATED_INPUT",
+            FormatError::EncodeFailed(_) => "ENCODE_FAILED",
+            FormatError::TooLarge(_) => "TOO_LARGE",
+            FormatError::CannotMeetSize { .. } => "CANNOT_MEET_SIZE",
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::ConfigNotSet => write!(f, "Configuration not set"),
+            FormatError::UnknownExamCode { code, available } => write!(
+                f,
+                "Unknown exam code '{}'. Available codes: {}",
+                code,
+                available.join(", ")
+            ),
+            FormatError::DuplicateExamCode(code) => write!(
+                f,
+                "Exam code '{}' is already registered; pass overwrite to replace it",
+                code
+            ),
+            FormatError::InvalidConfig(message) => write!(f, "{}", message),
+            FormatError::ConfigValidation(issues) => write!(f, "Invalid exam config: {}", issues.join("; ")),
+            FormatError::InvalidInput(message) => write!(f, "{}", message),
+            FormatError::UnrecognizedFormat => write!(f, "Unrecognized file format"),
+            FormatError::UnsupportedInput(message) => write!(f, "{}", message),
+            FormatError::FormatNotAllowed { format, exam, allowed } => write!(
+                f,
+                "Format {} not allowed for {}; allowed: {}",
+                format,
+                exam,
+                allowed.join(", ")
+            ),
+            FormatError::UnsupportedOutputFormat(message) => write!(f, "{}", message),
+            FormatError::UnknownOutputFormat(format) => write!(f, "Unknown output format '{}'", format),
+            FormatError::UnknownDocumentType { document_type, allowed } => write!(
+                f,
+                "Unknown document type '{}'; allowed: {}",
+                document_type,
+                allowed.join(", ")
+            ),
+            FormatError::UpscaleBlocked { width, height, target_width, target_height } => write!(
+                f,
+                "Source image {}x{} is smaller than the required {}x{}; please upload a larger image",
+                width, height, target_width, target_height
+            ),
+            FormatError::AspectMismatch { width, height, target_width, target_height } => write!(
+                f,
+                "Image {}x{} is a very different shape from the required {}x{}; please crop it first",
+                width, height, target_width, target_height
+            ),
+            FormatError::DecodeFailed(message) => write!(f, "Failed to load image: {}", message),
+            FormatError::Truncated => write!(f, "File appears incomplete, please re-select it"),
+            FormatError::EncodeFailed(message) => write!(f, "Failed to encode image: {}", message),
+            FormatError::TooLarge(message) => write!(f, "{}", message),
+            FormatError::CannotMeetSize { target_kb } => write!(
+                f,
+                "Could not get the output under {}KB even after lowering quality and dimensions",
+                target_kb
+            ),
+        }
+    }
+}
+
+impl FormatError {
+    // The user-facing message in the current language. Kinds without a
+    // translation, mostly config mistakes meant for developers, stay English
+    pub fn message(&self) -> String {
+        self.translated(language()).unwrap_or_else(|| self.to_string())
+    }
+
+    fn translated(&self, language: Language) -> Option<String> {
+        let text = match (language, self) {
+            (Language::English, _) => return None,
+            (Language::Hindi, FormatError::ConfigNotSet) => "कॉन्फ़िगरेशन सेट नहीं है".to_string(),
+            (Language::Hindi, FormatError::UnrecognizedFormat) => "फ़ाइल का प्रारूप पहचाना नहीं जा सका".to_string(),
+            (Language::Hindi, FormatError::FormatNotAllowed { format, exam, allowed }) => {
+                format!("{} के लिए {} प्रारूप की अनुमति नहीं है; अनुमत: {}", exam, format, allowed.join(", "))
+            }
+            (Language::Hindi, FormatError::UnknownDocumentType { document_type, allowed }) => {
+                format!("अज्ञात दस्तावेज़ प्रकार '{}'; अनुमत: {}", document_type, allowed.join(", "))
+            }
+            (Language::Hindi, FormatError::UpscaleBlocked { width, height, target_width, target_height }) => format!(
+                "स्रोत छवि {}x{} आवश्यक {}x{} से छोटी है; कृपया बड़ी छवि अपलोड करें",
+                width, height, target_width, target_height
+            ),
+            (Language::Hindi, FormatError::AspectMismatch { width, height, target_width, target_height }) => format!(
+                "छवि {}x{} का आकार आवश्यक {}x{} से बहुत अलग है; कृपया पहले इसे क्रॉप करें",
+                width, height, target_width, target_height
+            ),
+            (Language::Hindi, FormatError::DecodeFailed(_)) => "छवि लोड नहीं हो सकी".to_string(),
+            (Language::Hindi, FormatError::Truncated) => "फ़ाइल अधूरी लगती है, कृपया इसे फिर से चुनें".to_string(),
+            (Language::Hindi, FormatError::TooLarge(_)) => "फ़ाइल अनुमत आकार सीमा से बड़ी है".to_string(),
+            (Language::Hindi, FormatError::CannotMeetSize { target_kb }) => {
+                format!("गुणवत्ता और आयाम घटाने के बाद भी आउटपुट {}KB से कम नहीं हो सका", target_kb)
+            }
+            (Language::Tamil, FormatError::ConfigNotSet) => "அமைப்பு அமைக்கப்படவில்லை".to_string(),
+            (Language::Tamil, FormatError::UnrecognizedFormat) => "கோப்பு வடிவம் அடையாளம் காணப்படவில்லை".to_string(),
+            (Language::Tamil, FormatError::FormatNotAllowed { format, exam, allowed }) => {
+                format!("{} க்கு {} வடிவம் அனுமதிக்கப்படவில்லை; அனுமதிக்கப்பட்டவை: {}", exam, format, allowed.join(", "))
+            }
+            (Language::Tamil, FormatError::UnknownDocumentType { document_type, allowed }) => {
+                format!("தெரியாத ஆவண வகை '{}'; அனுமதிக்கப்பட்டவை: {}", document_type, allowed.join(", "))
+            }
+            (Language::Tamil, FormatError::UpscaleBlocked { width, height, target_width, target_height }) => format!(
+                "மூலப் படம் {}x{} தேவையான {}x{} ஐ விடச் சிறியது; பெரிய படத்தைப் பதிவேற்றவும்",
+                width, height, target_width, target_height
+            ),
+            (Language::Tamil, FormatError::AspectMismatch { width, height, target_width, target_height }) => format!(
+                "படம் {}x{} தேவையான {}x{} வடிவத்திலிருந்து மிகவும் வேறுபட்டது; முதலில் அதைச் செதுக்கவும்",
+                width, height, target_width, target_height
+            ),
+            (Language::Tamil, FormatError::DecodeFailed(_)) => "படத்தை ஏற்ற முடியவில்லை".to_string(),
+            (Language::Tamil, FormatError::Truncated) => "கோப்பு முழுமையடையவில்லை போல் தெரிகிறது, மீண்டும் தேர்ந்தெடுக்கவும்".to_string(),
+            (Language::Tamil, FormatError::TooLarge(_)) => "கோப்பு அனுமதிக்கப்பட்ட அளவை விடப் பெரியது".to_string(),
+            (Language::Tamil, FormatError::CannotMeetSize { target_kb }) => {
+                format!("தரத்தையும் பரிமாணங்களையும் குறைத்த பிறகும் வெளியீட்டை {}KB க்குக் கீழ் கொண்டுவர முடியவில்லை", target_kb)
+            }
+            _ => return None,
+        };
+        Some(text)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+#[derive(Serialize)]
+struct ErrorPayload<'a> {
+    code: &'a str,
+    message: String,
+    // Every problem found, for errors that collect more than one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issues: Option<&'a [String]>,
+}
+
+impl FormatError {
+    fn payload(&self) -> ErrorPayload<'_> {
+        ErrorPayload {
+            code: self.code(),
+            message: self.message(),
+            issues: match self {
+                FormatError::ConfigValidation(issues) => Some(issues.as_slice()),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl From<FormatError> for JsValue {
+    fn from(error: FormatError) -> JsValue {
+        crate::log_error(&error.to_string());
+        let payload = error.payload();
+        serde_wasm_bindgen::to_value(&payload).unwrap_or_else(|_| JsValue::from_str(&payload.message))
+    }
+}
+
+impl From<serde_wasm_bindgen::Error> for FormatError {
+    fn from(error: serde_wasm_bindgen::Error) -> FormatError {
+        FormatError::InvalidInput(error.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_variant_serializes_its_code() {
+        let dims = (10, 10, 300, 400);
+        let cases = [
+            (FormatError::ConfigNotSet, "CONFIG_NOT_SET"),
+            (FormatError::UnknownExamCode { code: "x".into(), available: vec![] }, "UNKNOWN_EXAM_CODE"),
+            (FormatError::DuplicateExamCode("x".into()), "DUPLICATE_EXAM_CODE"),
+            (FormatError::InvalidConfig("x".into()), "INVALID_CONFIG"),
+            (FormatError::ConfigValidation(vec!["x".into()]), "CONFIG_VALIDATION_FAILED"),
+            (FormatError::InvalidInput("x".into()), "INVALID_INPUT"),
+            (FormatError::UnrecognizedFormat, "UNRECOGNIZED_FORMAT"),
+            (FormatError::UnsupportedInput("x".into()), "UNSUPPORTED_INPUT_FORMAT"),
+            (FormatError::FormatNotAllowed { format: "x".into(), exam: "x".into(), allowed: vec![] }, "FORMAT_NOT_ALLOWED"),
+            (FormatError::UnsupportedOutputFormat("x".into()), "UNSUPPORTED_OUTPUT_FORMAT"),
+            (FormatError::UnknownOutputFormat("x".into()), "UNKNOWN_OUTPUT_FORMAT"),
+            (FormatError::UnknownDocumentType { document_type: "x".into(), allowed: vec![] }, "UNKNOWN_DOCUMENT_TYPE"),
+            (
+                FormatError::UpscaleBlocked { width: dims.0, height: dims.1, target_width: dims.2, target_height: dims.3 },
+                "UPSCALE_BLOCKED",
+            ),
+            (
+                FormatError::AspectMismatch { width: dims.0, height: dims.1, target_width: dims.2, target_height: dims.3 },
+                "ASPECT_MISMATCH",
+            ),
+            (FormatError::DecodeFailed("x".into()), "DECODE_FAILED"),
+            (FormatError::Truncated, "TRUNCATED_INPUT"),
+            (FormatError::EncodeFailed("x".into()), "ENCODE_FAILED"),
+            (FormatError::TooLarge("x".into()), "TOO_LARGE"),
+            (FormatError::CannotMeetSize { target_kb: 20 }, "CANNOT_MEET_SIZE"),
+        ];
+        for (error, code) in cases {
+            let json = serde_json::to_value(error.payload()).unwrap();
+            assert_eq!(json["code"], code);
+            assert!(json["message"].as_str().is_some_and(|message| !message.is_empty()), "{}", code);
+        }
+    }
+
+    #[test]
+    fn validation_issues_are_listed() {
+        let error = FormatError::ConfigValidation(vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(serde_json::to_value(error.payload()).unwrap()["issues"], serde_json::json!(["a", "b"]));
+        assert!(serde_json::to_value(FormatError::Truncated.payload()).unwrap().get("issues").is_none());
+    }
+
+    #[test]
+    fn messages_follow_the_selected_language() {
+        let error = FormatError::TooLarge("File is 900KB, over the 500KB limit".to_string());
+        assert_eq!(Language::parse(" HI "), Some(Language::Hindi));
+        assert_eq!(Language::parse("fr"), None);
+
+        set_language(Language::Hindi);
+        let json = serde_json::to_value(error.payload()).unwrap();
+        set_language(Language::English);
+        assert_eq!(json["code"], "TOO_LARGE");
+        assert_eq!(json["message"], "फ़ाइल अनुमत आकार सीमा से बड़ी है");
+
+        assert_eq!(error.message(), "File is 900KB, over the 500KB limit");
+        assert!(error.translated(Language::Tamil).is_some());
+        // Developer-facing kinds have no translation and stay English
+        assert_eq!(FormatError::InvalidConfig("x".into()).translated(Language::Hindi), None);
+    }
+}
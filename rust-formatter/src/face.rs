@@ -0,0 +1,191 @@
+// Lightweight face locator for passport-style photo cropping.
+//
+// Rather than shipping a Haar cascade or neural model in the WASM binary, this
+// segments skin-toned pixels in YCbCr space on a small thumbnail and treats the
+// largest connected skin region as the face. That is cheap and good enough for
+// the head-and-shoulders shots exam portals ask for, where the face dominates.
+
+use image::{DynamicImage, GenericImageView};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+// Long edge of the thumbnail the detector works on
+const DETECTION_EDGE: u32 = 160;
+// Smallest skin region, as a fraction of the thumbnail, accepted as a face
+const MIN_FACE_FRACTION: f32 = 0.01;
+// Share of the crop height the face should occupy, and the headroom above it
+const FACE_HEIGHT_SHARE: f32 = 0.5;
+const HEADROOM_SHARE: f32 = 0.2;
+
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct FaceBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// A crop rectangle in source pixel coordinates
+#[derive(Clone, Copy, Debug)]
+pub struct CropWindow {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+fn is_skin(r: u8, g: u8, b: u8) -> bool {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let cb = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
+    let cr = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
+    (77.0..=127.0).contains(&cb) && (133.0..=173.0).contains(&cr)
+}
+
+pub fn detect_largest_face(img: &DynamicImage) -> Option<FaceBox> {
+    let (src_w, src_h) = img.dimensions();
+    if src_w == 0 || src_h == 0 {
+        return None;
+    }
+
+    let thumb = img.thumbnail(DETECTION_EDGE, DETECTION_EDGE).to_rgb8();
+    let (w, h) = thumb.dimensions();
+    let mask: Vec<bool> = thumb.pixels().map(|p| is_skin(p[0], p[1], p[2])).collect();
+
+    // Flood-fill each skin region and keep the bounding box of the largest one
+    let mut visited = vec![false; mask.len()];
+    let mut best: Option<(usize, (u32, u32, u32, u32))> = None;
+    let mut queue = VecDeque::new();
+
+    for start in 0..mask.len() {
+        if !mask[start] || visited[start] {
+            continue;
+        }
+
+        visited[start] = true;
+        queue.push_back(start);
+        let mut area = 0usize;
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (u32::MAX, u32::MAX, 0, 0);
+
+        while let Some(index) = queue.pop_front() {
+            area += 1;
+            let (x, y) = ((index as u32) % w, (index as u32) / w);
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+
+            let neighbours = [
+                (x > 0).then(|| index - 1),
+                (x + 1 < w).then(|| index + 1),
+                (y > 0).then(|| index - w as usize),
+                (y + 1 < h).then(|| index + w as usize),
+            ];
+            for next in neighbours.into_iter().flatten() {
+                if mask[next] && !visited[next] {
+                    visited[next] = true;
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        if best.is_none_or(|(best_area, _)| area > best_area) {
+            best = Some((area, (min_x, min_y, max_x, max_y)));
+        }
+    }
+
+    let (area, (min_x, min_y, max_x, max_y)) = best?;
+    if (area as f32) < (w * h) as f32 * MIN_FACE_FRACTION {
+        return None;
+    }
+
+    // Map the thumbnail box back onto the source image
+    let scale_x = src_w as f32 / w as f32;
+    let scale_y = src_h as f32 / h as f32;
+    let x = (min_x as f32 * scale_x) as u32;
+    let y = (min_y as f32 * scale_y) as u32;
+    Some(FaceBox {
+        x,
+        y,
+        width: (((max_x + 1) as f32 * scale_x) as u32).min(src_w) - x,
+        height: (((max_y + 1) as f32 * scale_y) as u32).min(src_h) - y,
+    })
+}
+
+// Largest window with the target aspect that fits inside the image, centered
+pub fn center_crop_window(src_w: u32, src_h: u32, target_w: u32, target_h: u32) -> CropWindow {
+    let aspect = target_w as f32 / target_h as f32;
+    let (width, height) = if src_w as f32 / src_h as f32 > aspect {
+        (((src_h as f32 * aspect).round() as u32).clamp(1, src_w), src_h)
+    } else {
+        (src_w, ((src_w as f32 / aspect).round() as u32).clamp(1, src_h))
+    };
+
+    CropWindow {
+        x: (src_w - width) / 2,
+        y: (src_h - height) / 2,
+        width,
+        height,
+    }
+}
+
+// Window with the target aspect that centers the face horizontally and leaves
+// standard headroom above it, shrunk and shifted as needed to stay in bounds
+pub fn face_crop_window(face: &FaceBox, src_w: u32, src_h: u32, target_w: u32, target_h: u32) -> CropWindow {
+    let aspect = target_w as f32 / target_h as f32;
+    let mut height = face.height as f32 / FACE_HEIGHT_SHARE;
+    let mut width = height * aspect;
+
+    let fit = f32::min(1.0, f32::min(src_w as f32 / width, src_h as f32 / height));
+    width = (width * fit).round().clamp(1.0, src_w as f32);
+    height = (height * fit).round().clamp(1.0, src_h as f32);
+
+    let face_center_x = face.x as f32 + face.width as f32 / 2.0;
+    let x = (face_center_x - width / 2.0).clamp(0.0, src_w as f32 - width);
+    let y = (face.y as f32 - height * HEADROOM_SHARE).clamp(0.0, src_h as f32 - height);
+
+    CropWindow {
+        x: x as u32,
+        y: y as u32,
+        width: width as u32,
+        height: height as u32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    // Gray backdrop with a skin-toned oval centered at (250, 150), 80x100
+    fn portrait() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(400, 400, |x, y| {
+            let (dx, dy) = ((x as f32 + 0.5 - 250.0) / 40.0, (y as f32 + 0.5 - 150.0) / 50.0);
+            if dx * dx + dy * dy <= 1.0 { Rgb([224, 172, 140]) } else { Rgb([90, 110, 130]) }
+        }))
+    }
+
+    fn assert_near(actual: u32, expected: u32, tolerance: u32) {
+        assert!(actual.abs_diff(expected) <= tolerance, "{} is not within {} of {}", actual, tolerance, expected);
+    }
+
+    #[test]
+    fn finds_the_face_and_crops_around_it() {
+        let face = detect_largest_face(&portrait()).unwrap();
+        assert_near(face.x, 210, 4);
+        assert_near(face.y, 100, 4);
+        assert_near(face.width, 80, 6);
+        assert_near(face.height, 100, 6);
+
+        // The face fills half the crop height with a fifth of it as headroom
+        let window = face_crop_window(&face, 400, 400, 300, 400);
+        assert_near(window.height, 200, 12);
+        assert_near(window.width, 150, 9);
+        assert_near(window.x + window.width / 2, 250, 4);
+        assert_near(window.y, 60, 6);
+    }
+
+    #[test]
+    fn no_face_in_a_plain_image() {
+        assert!(detect_largest_face(&DynamicImage::ImageRgb8(RgbImage::new(100, 100))).is_none());
+    }
+}
@@ -1,32 +1,341 @@
+mod classify;
+mod color;
+pub mod core;
+mod enhance;
+mod error;
+mod face;
+mod metadata;
+mod pdf;
+mod png_optimize;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "pdf-render")]
+mod pdf_render;
+#[cfg(feature = "watermark")]
+mod watermark;
+
 use wasm_bindgen::prelude::*;
-use web_sys::console;
-use image::{ImageFormat, DynamicImage, ImageOutputFormat};
-use std::io::Cursor;
+use base64::prelude::*;
+pub use error::FormatError;
+pub use crate::core::{FormatOutput, ProgressFn, Timings};
+pub use face::FaceBox;
+use crate::core::{
+    OUTPUT_FORMATS, apply_physical_size, decode_image, detect_mime_type, file_extension, input_density,
+    input_formats, is_likely_already_compressed, needs_upscale, output_formats, output_mime_type, parse_hex_color,
+    read_exif_orientation, resolve_auto_format, resolve_format, validate_format, with_size_margin,
+};
+use pdf::{PdfImage, PdfPage};
+use image::{DynamicImage, GenericImageView, Rgba};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
-// Import the `console.log` function from the browser
+// JS must `await initThreadPool(navigator.hardwareConcurrency)` before a
+// parallel batch runs
+#[cfg(all(feature = "parallel", target_arch = "wasm32"))]
+pub use wasm_bindgen_rayon::init_thread_pool;
+
+// Import the `console.log` and `console.error` functions from the browser
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = console)]
     fn log(s: &str);
+    #[wasm_bindgen(js_namespace = console, js_name = error)]
+    fn log_error_js(s: &str);
+}
+
+// Native builds (tests, CLI tools) have no console; imported JS functions
+// would panic there, so the same lines go to stderr
+#[cfg(all(not(target_arch = "wasm32"), not(test)))]
+fn log(s: &str) {
+    eprintln!("{}", s);
+}
+
+#[cfg(all(not(target_arch = "wasm32"), not(test)))]
+fn log_error_js(s: &str) {
+    eprintln!("{}", s);
+}
+
+// Unit tests read back what their own thread logged
+#[cfg(all(not(target_arch = "wasm32"), test))]
+thread_local! {
+    static CAPTURED_LOGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(all(not(target_arch = "wasm32"), test))]
+fn log(s: &str) {
+    CAPTURED_LOGS.with(|logs| logs.borrow_mut().push(s.to_string()));
 }
 
-// Define a macro for easier console logging
+#[cfg(all(not(target_arch = "wasm32"), test))]
+fn log_error_js(s: &str) {
+    CAPTURED_LOGS.with(|logs| logs.borrow_mut().push(format!("error: {}", s)));
+}
+
+// Console verbosity set through `set_log_level`. Only errors by default, so
+// production consoles stay quiet but failures still surface
+const LOG_OFF: u8 = 0;
+const LOG_ERROR: u8 = 1;
+const LOG_INFO: u8 = 2;
+const LOG_DEBUG: u8 = 3;
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LOG_ERROR);
+
+fn log_enabled(level: u8) -> bool {
+    LOG_LEVEL.load(Ordering::Relaxed) >= level
+}
+
+// Pipeline steps
 macro_rules! console_log {
-    ($($t:tt)*) => (log(&format_args!($($t)*).to_string()))
+    ($($t:tt)*) => (if $crate::log_enabled($crate::LOG_INFO) { $crate::log(&format_args!($($t)*).to_string()) })
 }
 
-#[derive(Serialize, Deserialize)]
+// Per-attempt and per-stage detail
+macro_rules! console_debug {
+    ($($t:tt)*) => (if $crate::log_enabled($crate::LOG_DEBUG) { $crate::log(&format_args!($($t)*).to_string()) })
+}
+
+pub(crate) use {console_debug, console_log};
+
+// Called for every error handed back to JS
+pub(crate) fn log_error(message: &str) {
+    if log_enabled(LOG_ERROR) {
+        log_error_js(message);
+    }
+}
+
+#[wasm_bindgen]
+pub fn set_log_level(level: &str) -> Result<(), FormatError> {
+    let level = match level.trim().to_lowercase().as_str() {
+        "off" => LOG_OFF,
+        "error" => LOG_ERROR,
+        "info" => LOG_INFO,
+        "debug" => LOG_DEBUG,
+        other => {
+            return Err(FormatError::InvalidInput(format!(
+                "Unknown log level '{}'. Expected one of: off, error, info, debug",
+                other
+            )))
+        }
+    };
+    LOG_LEVEL.store(level, Ordering::Relaxed);
+    Ok(())
+}
+
+// Language of the `message` on errors handed to JS: "en", "hi" or "ta"
+#[wasm_bindgen]
+pub fn set_language(lang: &str) -> Result<(), FormatError> {
+    let language = error::Language::parse(lang).ok_or_else(|| {
+        FormatError::InvalidInput(format!("Unknown language '{}'. Expected one of: en, hi, ta", lang))
+    })?;
+    error::set_language(language);
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct DocumentFormat {
-    width: u32,
-    height: u32,
-    dpi: u32,
-    format: String,
-    quality: u8,
-    max_size: u32,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    pub dpi: u32,
+    pub format: String,
+    pub quality: u8,
+    pub max_size: u32,
+    #[serde(default = "default_fit_mode")]
+    pub fit_mode: String,
+    // Padding and rotation fill; "auto" matches the image's own edge color
+    #[serde(default = "default_bg_color")]
+    pub bg_color: String,
+    #[serde(default)]
+    pub face_center: bool,
+    #[serde(default)]
+    pub signature_cleanup: bool,
+    // Crop signatures to their strokes so they fill the box
+    #[serde(default)]
+    pub auto_trim: bool,
+    #[serde(default)]
+    pub grayscale: bool,
+    #[serde(default = "default_true")]
+    pub allow_upscale: bool,
+    #[serde(default)]
+    pub auto_deskew: bool,
+    #[serde(default)]
+    pub min_size: Option<u32>,
+    // Aim this many percent under the size cap, for portals whose checks are
+    // off by one at the exact limit
+    #[serde(default)]
+    pub size_margin_pct: f32,
+    #[serde(default)]
+    pub border: Option<BorderSpec>,
+    #[serde(default = "default_filter")]
+    pub filter: String,
+    #[serde(default)]
+    pub preserve_metadata: bool,
+    #[serde(default)]
+    pub sharpen: f32,
+    #[serde(default)]
+    pub auto_contrast: bool,
+    #[serde(default = "default_chroma_subsampling")]
+    pub chroma_subsampling: String,
+    #[serde(default = "default_crop_shape")]
+    pub crop_shape: String,
+    #[serde(default)]
+    pub downscale_only: bool,
+    #[serde(default = "default_png_color_type")]
+    pub png_color_type: String,
+    // How "bilevel" PNGs reduce gray to black and white: "none" (crisp but
+    // jagged), "floyd-steinberg" or "ordered"
+    #[serde(default = "default_dither")]
+    pub dither: String,
+    // Off lets legacy configs with an unknown `format` fall back to JPEG
+    #[serde(default = "default_true")]
+    pub strict_format: bool,
+    // What transparent areas become when the output format has no alpha
+    #[serde(default = "default_bg_color")]
+    pub flatten_background: String,
+    // For sources whose color values are already multiplied by alpha, which
+    // read as straight alpha leave a dark fringe along soft edges
+    #[serde(default)]
+    pub assume_premultiplied: bool,
+    // How far apart the source and target aspect ratios may be, as a factor,
+    // before "stretch" refuses (or falls back to "contain") rather than squash
+    #[serde(default = "default_max_aspect_skew")]
+    pub max_aspect_skew: f32,
+    #[serde(default)]
+    pub auto_fit_on_skew: bool,
+    // Resample inputs whose horizontal and vertical DPI differ so the output
+    // keeps the paper's proportions
+    #[serde(default)]
+    pub correct_pixel_aspect: bool,
+    // Let a PNG that cannot meet its cap come out in a lossy format the exam
+    // accepts instead
+    #[serde(default)]
+    pub allow_format_fallback: bool,
+    // Physical size in place of `width`/`height`; the pixels are derived from
+    // the DPI on load, so size checks in cm come out exact
+    #[serde(default)]
+    pub width_cm: Option<f32>,
+    #[serde(default)]
+    pub height_cm: Option<f32>,
+    // Encodes the size search may try before settling for its best so far
+    #[serde(default = "default_max_compress_iterations")]
+    pub max_compress_iterations: u32,
+    // Lowest SSIM against the resized image a lossy encode may reach; the
+    // quality then comes from the search instead of `quality`
+    #[serde(default)]
+    pub target_ssim: Option<f32>,
+    // Photos only: soften everything but the face before encoding, so JPEG
+    // spends its bytes where reviewers look
+    #[serde(default)]
+    pub roi_optimize: bool,
+    // Slow extra lossless pass over PNG output, often 10-20% smaller
+    #[serde(default)]
+    pub png_optimize: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+// Solid frame around the output. By default it grows the canvas by twice the
+// width; with `inset` the image shrinks instead so the target size is kept
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BorderSpec {
+    pub width_px: u32,
+    pub color: String,
+    #[serde(default)]
+    pub inset: bool,
+}
+
+fn default_fit_mode() -> String {
+    "stretch".to_string()
+}
+
+fn default_bg_color() -> String {
+    "#FFFFFF".to_string()
+}
+
+fn default_filter() -> String {
+    "lanczos3".to_string()
+}
+
+// 4:4:4 keeps colored detail such as stamp ink at the cost of size
+fn default_chroma_subsampling() -> String {
+    "4:2:0".to_string()
+}
+
+fn default_crop_shape() -> String {
+    "rect".to_string()
+}
+
+// "auto" picks the smallest color type that still holds every pixel exactly
+fn default_png_color_type() -> String {
+    "auto".to_string()
+}
+
+fn default_dither() -> String {
+    "none".to_string()
+}
+
+fn default_max_aspect_skew() -> f32 {
+    2.0
+}
+
+fn default_max_compress_iterations() -> u32 {
+    10
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for DocumentFormat {
+    fn default() -> Self {
+        DocumentFormat {
+            width: 0,
+            height: 0,
+            dpi: 200,
+            format: "JPEG".to_string(),
+            quality: 85,
+            max_size: 0,
+            fit_mode: default_fit_mode(),
+            bg_color: default_bg_color(),
+            face_center: false,
+            signature_cleanup: false,
+            auto_trim: false,
+            grayscale: false,
+            allow_upscale: true,
+            auto_deskew: false,
+            min_size: None,
+            size_margin_pct: 0.0,
+            border: None,
+            filter: default_filter(),
+            preserve_metadata: false,
+            sharpen: 0.0,
+            auto_contrast: false,
+            chroma_subsampling: default_chroma_subsampling(),
+            crop_shape: default_crop_shape(),
+            downscale_only: false,
+            png_color_type: default_png_color_type(),
+            dither: default_dither(),
+            strict_format: true,
+            flatten_background: default_bg_color(),
+            assume_premultiplied: false,
+            max_aspect_skew: default_max_aspect_skew(),
+            auto_fit_on_skew: false,
+            correct_pixel_aspect: false,
+            allow_format_fallback: false,
+            width_cm: None,
+            height_cm: None,
+            max_compress_iterations: default_max_compress_iterations(),
+            target_ssim: None,
+            roi_optimize: false,
+            png_optimize: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ExamConfig {
     name: String,
     code: String,
@@ -34,15 +343,355 @@ pub struct ExamConfig {
     max_file_size: u32,
     allowed_formats: Vec<String>,
     document_types: Vec<String>,
+    #[serde(default)]
+    document_type_formats: HashMap<String, DocumentFormat>,
+    #[serde(default = "default_max_megapixels")]
+    max_megapixels: u32,
+    // Upload name the portal expects, e.g. "{examCode}_{docType}.{ext}"
+    #[serde(default)]
+    filename_template: Option<String>,
+    // Off rejects animated uploads instead of picking a frame
+    #[serde(default = "default_true")]
+    allow_animated: bool,
 }
 
-#[derive(Serialize, Deserialize)]
+// Decoded pixels take four bytes each, so 50 MP is already ~200 MB of the
+// WASM heap; anything beyond that risks taking the tab down
+const DEFAULT_MAX_MEGAPIXELS: u32 = 50;
+
+fn default_max_megapixels() -> u32 {
+    DEFAULT_MAX_MEGAPIXELS
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ExamFormats {
     photo: DocumentFormat,
     signature: DocumentFormat,
     documents: DocumentFormat,
 }
 
+#[derive(Serialize)]
+pub struct ValidationReport {
+    ok: bool,
+    detected_format: Option<String>,
+    input_width: u32,
+    input_height: u32,
+    input_size_kb: u32,
+    will_upscale: bool,
+    blank: bool,
+    blank_coverage: f32,
+    // Looks like an earlier export; a hint, not an issue
+    is_likely_already_compressed: bool,
+    dpi_x: Option<u32>,
+    dpi_y: Option<u32>,
+    // Scanners sometimes write e.g. 300x200, which portals measure as distorted
+    non_square_dpi: bool,
+    issues: Vec<String>,
+}
+
+// What `probe_metadata` finds in the file headers
+#[derive(Serialize)]
+pub struct ImageMetadata {
+    width: u32,
+    height: u32,
+    format: String,
+    // Horizontal density, with both axes and whether they differ beside it
+    dpi: Option<u32>,
+    dpi_x: Option<u32>,
+    dpi_y: Option<u32>,
+    non_square_dpi: bool,
+    has_exif: bool,
+    has_alpha: bool,
+    // Bits per channel as stored
+    bit_depth: u32,
+    color_type: String,
+}
+
+#[derive(Serialize)]
+pub struct BlankReport {
+    blank: bool,
+    coverage: f32,
+}
+
+#[derive(Serialize)]
+pub struct RgbColor {
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+#[derive(Deserialize)]
+pub struct BatchItem {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+    document_type: String,
+    original_name: String,
+}
+
+#[derive(Deserialize)]
+pub struct PdfItem {
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+    #[serde(default)]
+    document_type: Option<String>,
+    #[serde(default)]
+    dpi: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct BatchResult {
+    original_name: String,
+    ok: bool,
+    result: Option<FormatOutput>,
+    error_code: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProcessingOptions {
+    document_type: String,
+    original_name: String,
+    #[serde(default)]
+    target_size_kb: Option<u32>,
+    #[serde(default)]
+    rotate_degrees: f32,
+    #[serde(skip)]
+    progress: Option<Box<core::ProgressFn>>,
+    #[serde(default)]
+    include_hash: bool,
+    #[serde(default)]
+    watermark: Option<WatermarkSpec>,
+    // Format fields replaced for this call only, e.g. for A/B tests
+    #[serde(default)]
+    overrides: Option<FormatOverrides>,
+    // Longest edge of the original's thumbnail, when one is wanted
+    #[serde(skip)]
+    preview_edge: Option<u32>,
+}
+
+// Text stamped diagonally across `documents` uploads; photos and signatures
+// are never marked
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WatermarkSpec {
+    text: String,
+    opacity: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FormatOverrides {
+    #[serde(default)]
+    quality: Option<u8>,
+    #[serde(default)]
+    filter: Option<String>,
+    #[serde(default)]
+    format: Option<String>,
+    #[serde(default)]
+    fit_mode: Option<String>,
+}
+
+impl ProcessingOptions {
+    fn new(document_type: &str, original_name: &str) -> Self {
+        ProcessingOptions {
+            document_type: document_type.to_string(),
+            original_name: original_name.to_string(),
+            target_size_kb: None,
+            rotate_degrees: 0.0,
+            progress: None,
+            include_hash: false,
+            watermark: None,
+            overrides: None,
+            preview_edge: None,
+        }
+    }
+}
+
+// Payload handed to the optional JS progress callback
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    stage: &'a str,
+    percent: f32,
+    quality: Option<u8>,
+}
+
+// Adapt a JS progress callback for the pipeline. Progress is best-effort: a
+// throwing callback never fails the job
+fn js_progress(callback: js_sys::Function) -> Box<core::ProgressFn> {
+    Box::new(move |stage, percent, quality| {
+        if let Ok(event) = serde_wasm_bindgen::to_value(&ProgressEvent { stage, percent, quality }) {
+            let _ = callback.call1(&JsValue::NULL, &event);
+        }
+    })
+}
+
+// `format_document_with_preview` output, for side-by-side review
+#[derive(Serialize)]
+struct PreviewResult {
+    formatted: FormatOutput,
+    #[serde(with = "serde_bytes")]
+    original_thumbnail: Vec<u8>,
+}
+
+// One requested output of `format_variants`
+#[derive(Deserialize)]
+struct VariantSpec {
+    suffix: String,
+    width: u32,
+    height: u32,
+    format: String,
+    quality: u8,
+}
+
+#[derive(Serialize)]
+struct VariantOutput {
+    suffix: String,
+    #[serde(with = "serde_bytes")]
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    size_kb: u32,
+}
+
+// One point of `size_curve`
+#[derive(Serialize)]
+struct SizePoint {
+    quality: u8,
+    size_kb: u32,
+}
+
+// Split a `data:<mime>;base64,<payload>` URL, or accept a bare base64 string
+fn parse_data_url(input: &str) -> Result<(Option<String>, Vec<u8>), FormatError> {
+    let input = input.trim();
+    let (mime, payload) = match input.strip_prefix("data:") {
+        Some(rest) => {
+            let (header, payload) = rest.split_once(',')
+                .ok_or_else(|| FormatError::InvalidInput("Malformed data URL: missing ',' separator".to_string()))?;
+            let mime = header.strip_suffix(";base64")
+                .ok_or_else(|| FormatError::InvalidInput("Malformed data URL: only base64 payloads are supported".to_string()))?;
+            (Some(mime.to_lowercase()).filter(|m| !m.is_empty()), payload)
+        }
+        None => (None, input),
+    };
+
+    let data = BASE64_STANDARD.decode(payload)
+        .map_err(|e| FormatError::InvalidInput(format!("Malformed base64 data: {}", e)))?;
+    Ok((mime, data))
+}
+
+// MIME type for an output format name, e.g. "png" -> "image/png", so the
+// frontend can type the Blob without its own mapping
+#[wasm_bindgen]
+pub fn output_mime(format: &str) -> Result<String, FormatError> {
+    known_output_format(format).map(|format| output_mime_type(format).to_string())
+}
+
+// File extension for an output format name, e.g. "JPEG" -> "jpg"
+#[wasm_bindgen]
+pub fn output_extension(format: &str) -> Result<String, FormatError> {
+    known_output_format(format).map(|format| file_extension(format).to_string())
+}
+
+// Case-insensitive lookup in OUTPUT_FORMATS, accepting "JPG" for JPEG
+fn known_output_format(format: &str) -> Result<&'static str, FormatError> {
+    let normalized = match format.trim().to_uppercase().as_str() {
+        "JPG" => "JPEG".to_string(),
+        other => other.to_string(),
+    };
+    OUTPUT_FORMATS
+        .iter()
+        .find(|known| **known == normalized)
+        .copied()
+        .ok_or_else(|| FormatError::UnknownOutputFormat(format.to_string()))
+}
+
+// Validate a config from outside and fill in what is derived on load
+fn checked_config(mut config: ExamConfig) -> Result<ExamConfig, FormatError> {
+    let issues = validate_config(&config);
+    if !issues.is_empty() {
+        return Err(FormatError::ConfigValidation(issues));
+    }
+
+    let formats = [&mut config.formats.photo, &mut config.formats.signature, &mut config.formats.documents];
+    for format in formats.into_iter().chain(config.document_type_formats.values_mut()) {
+        apply_physical_size(format);
+    }
+    Ok(config)
+}
+
+// Collect every problem in a config up front, so a bad admin-authored config
+// is rejected on load rather than failing halfway through a conversion
+fn validate_config(config: &ExamConfig) -> Vec<String> {
+    let mut issues = Vec::new();
+    if config.max_file_size == 0 {
+        issues.push("max_file_size must be greater than 0".to_string());
+    }
+    if config.max_megapixels == 0 {
+        issues.push("max_megapixels must be greater than 0".to_string());
+    }
+    if config.allowed_formats.is_empty() {
+        issues.push("allowed_formats must not be empty".to_string());
+    }
+
+    let mut formats = vec![
+        ("formats.photo".to_string(), &config.formats.photo),
+        ("formats.signature".to_string(), &config.formats.signature),
+        ("formats.documents".to_string(), &config.formats.documents),
+    ];
+    let mut overrides: Vec<_> = config.document_type_formats.iter().collect();
+    overrides.sort_by_key(|(document_type, _)| *document_type);
+    formats.extend(overrides.into_iter().map(|(document_type, format)| {
+        (format!("document_type_formats.{}", document_type), format)
+    }));
+
+    for (path, format) in formats {
+        validate_format(config, &path, format, &mut issues);
+    }
+    issues
+}
+
+// Lets the UI grey out formats this build was compiled without
+#[wasm_bindgen]
+pub fn supported_input_formats() -> JsValue {
+    serde_wasm_bindgen::to_value(&input_formats()).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn supported_output_formats() -> JsValue {
+    serde_wasm_bindgen::to_value(&output_formats()).unwrap()
+}
+
+#[derive(Serialize)]
+struct ModuleInfo {
+    version: String,
+    git_sha: Option<String>,
+    features: Vec<String>,
+}
+
+// Optional cargo features compiled into this build
+fn enabled_features() -> Vec<String> {
+    let features = [
+        ("webp", cfg!(feature = "webp")),
+        ("avif", cfg!(feature = "avif")),
+        ("pdf-render", cfg!(feature = "pdf-render")),
+        ("watermark", cfg!(feature = "watermark")),
+        ("parallel", cfg!(feature = "parallel")),
+    ];
+    features.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| name.to_string()).collect()
+}
+
+// Identifies the exact build in support tickets. `GIT_SHA` is picked up when
+// the build environment sets it
+#[wasm_bindgen]
+pub fn module_info() -> JsValue {
+    serde_wasm_bindgen::to_value(&build_info()).unwrap()
+}
+
+fn build_info() -> ModuleInfo {
+    ModuleInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: option_env!("GIT_SHA").map(String::from),
+        features: enabled_features(),
+    }
+}
 
 #[wasm_bindgen]
 pub fn init_panic_hook() {
@@ -59,13 +708,26 @@ impl DocumentFormatter {
     #[wasm_bindgen(constructor)]
     pub fn new() -> DocumentFormatter {
         init_panic_hook();
-        console_log!("DocumentFormatter created");
+        console_debug!("DocumentFormatter created");
         DocumentFormatter { config: None }
     }
 
     #[wasm_bindgen]
-    pub fn set_config(&mut self, config_js: &JsValue) -> Result<(), JsValue> {
+    pub fn set_config(&mut self, config_js: &JsValue) -> Result<(), FormatError> {
         let config: ExamConfig = serde_wasm_bindgen::from_value(config_js.clone())?;
+        self.apply_config(config)
+    }
+
+    #[wasm_bindgen]
+    pub fn set_config_from_json(&mut self, json: &str) -> Result<(), FormatError> {
+        let config: ExamConfig = serde_json::from_str(json)
+            .map_err(|e| FormatError::InvalidConfig(format!("Malformed config JSON: {}", e)))?;
+        self.apply_config(config)
+    }
+
+    fn apply_config(&mut self, config: ExamConfig) -> Result<(), FormatError> {
+        let config = checked_config(config)?;
+
         console_log!("Setting configuration for exam: {}", config.name);
         self.config = Some(config);
         Ok(())
@@ -77,150 +739,963 @@ impl DocumentFormatter {
         file_data: &[u8],
         document_type: &str,
         original_name: &str,
-    ) -> Result<Vec<u8>, JsValue> {
-        console_log!("Starting document formatting for type: {}", document_type);
+        progress: Option<js_sys::Function>,
+    ) -> Result<Vec<u8>, FormatError> {
+        let options = ProcessingOptions {
+            progress: progress.map(js_progress),
+            ..ProcessingOptions::new(document_type, original_name)
+        };
+        Ok(self.format_internal(file_data, &options)?.data)
+    }
+
+    #[wasm_bindgen]
+    pub fn format_document_with_options(
+        &self,
+        file_data: &[u8],
+        options_js: &JsValue,
+    ) -> Result<Vec<u8>, FormatError> {
+        let options: ProcessingOptions = serde_wasm_bindgen::from_value(options_js.clone())?;
+        if options.target_size_kb == Some(0) {
+            return Err(FormatError::InvalidInput("target_size_kb must be greater than zero".to_string()));
+        }
+        Ok(self.format_internal(file_data, &options)?.data)
+    }
+
+    // One-off conversion straight to a `DocumentFormat`, e.g. "300x400 JPEG
+    // q85 under 200KB", with no exam config; the stored one is not consulted
+    #[wasm_bindgen]
+    pub fn format_document_with_inline_config(
+        &self,
+        file_data: &[u8],
+        document_type: &str,
+        format_js: &JsValue,
+    ) -> Result<Vec<u8>, FormatError> {
+        let format: DocumentFormat = serde_wasm_bindgen::from_value(format_js.clone())?;
+        Ok(self.format_inline_internal(file_data, document_type, &format)?.data)
+    }
 
+    // Stamp `text` across document uploads after resizing, at `opacity` 0-1
+    #[wasm_bindgen]
+    pub fn format_document_watermarked(
+        &self,
+        file_data: &[u8],
+        document_type: &str,
+        original_name: &str,
+        text: &str,
+        opacity: f32,
+    ) -> Result<Vec<u8>, FormatError> {
+        let options = ProcessingOptions {
+            watermark: Some(WatermarkSpec { text: text.to_string(), opacity }),
+            ..ProcessingOptions::new(document_type, original_name)
+        };
+        Ok(self.format_internal(file_data, &options)?.data)
+    }
+
+    // Clockwise rotation chosen by the user, applied before any cropping
+    #[wasm_bindgen]
+    pub fn format_document_rotated(
+        &self,
+        file_data: &[u8],
+        document_type: &str,
+        original_name: &str,
+        degrees: f32,
+    ) -> Result<Vec<u8>, FormatError> {
+        if !degrees.is_finite() {
+            return Err(FormatError::InvalidInput("Rotation must be a finite number of degrees".to_string()));
+        }
+        let options = ProcessingOptions {
+            rotate_degrees: degrees,
+            ..ProcessingOptions::new(document_type, original_name)
+        };
+        Ok(self.format_internal(file_data, &options)?.data)
+    }
+
+    #[wasm_bindgen]
+    pub fn format_document_with_meta(
+        &self,
+        file_data: &[u8],
+        document_type: &str,
+        original_name: &str,
+        include_hash: Option<bool>,
+    ) -> Result<JsValue, FormatError> {
+        let options = ProcessingOptions {
+            include_hash: include_hash.unwrap_or(false),
+            ..ProcessingOptions::new(document_type, original_name)
+        };
+        let result = self.format_internal(file_data, &options)?;
+        Ok(serde_wasm_bindgen::to_value(&result)?)
+    }
+
+    // The formatted output plus a small JPEG of the upload, taken from the
+    // decoded source. Longest edge `thumbnail_edge`, default 256px
+    #[wasm_bindgen]
+    pub fn format_document_with_preview(
+        &self,
+        file_data: &[u8],
+        document_type: &str,
+        original_name: &str,
+        thumbnail_edge: Option<u32>,
+    ) -> Result<JsValue, FormatError> {
+        Ok(serde_wasm_bindgen::to_value(&self.preview_result(file_data, document_type, original_name, thumbnail_edge)?)?)
+    }
+
+    fn preview_result(
+        &self,
+        file_data: &[u8],
+        document_type: &str,
+        original_name: &str,
+        thumbnail_edge: Option<u32>,
+    ) -> Result<PreviewResult, FormatError> {
+        let max_edge = thumbnail_edge.unwrap_or(DEFAULT_PREVIEW_EDGE);
+        check_thumbnail_edge(max_edge)?;
+        let options = ProcessingOptions {
+            preview_edge: Some(max_edge),
+            ..ProcessingOptions::new(document_type, original_name)
+        };
+        let mut formatted = self.format_internal(file_data, &options)?;
+        // Passthrough outputs skip decoding, and PDFs have no pixels to show
+        let original_thumbnail = match formatted.original_thumbnail.take() {
+            Some(thumbnail) => thumbnail,
+            None if detect_mime_type(file_data) == Some("application/pdf") => Vec::new(),
+            None => make_thumbnail(file_data, max_edge)?,
+        };
+        Ok(PreviewResult { formatted, original_thumbnail })
+    }
+
+    #[wasm_bindgen]
+    pub fn format_document_base64(
+        &self,
+        data_url: &str,
+        document_type: &str,
+        original_name: &str,
+    ) -> Result<String, FormatError> {
         let config = self.config.as_ref()
-            .ok_or_else(|| JsValue::from_str("Configuration not set"))?;
+            .ok_or(FormatError::ConfigNotSet)?;
+
+        let (declared_mime, file_data) = parse_data_url(data_url)?;
+        if let Some(mime) = &declared_mime {
+            if !config.allowed_formats.iter().any(|allowed| allowed == mime) {
+                return Err(FormatError::FormatNotAllowed {
+                    format: mime.clone(),
+                    exam: config.name.clone(),
+                    allowed: config.allowed_formats.clone(),
+                });
+            }
+        }
+
+        let options = ProcessingOptions::new(document_type, original_name);
+        let result = self.format_internal(&file_data, &options)?;
+        Ok(format!(
+            "data:{};base64,{}",
+            output_mime_type(&result.format),
+            BASE64_STANDARD.encode(&result.data)
+        ))
+    }
+
+    #[wasm_bindgen]
+    pub fn format_batch(&self, items_js: &JsValue) -> Result<JsValue, FormatError> {
+        let items: Vec<BatchItem> = serde_wasm_bindgen::from_value(items_js.clone())?;
+        Ok(serde_wasm_bindgen::to_value(&self.format_items(&items))?)
+    }
+
+    fn format_items(&self, items: &[BatchItem]) -> Vec<BatchResult> {
+        console_log!("Formatting batch of {} documents", items.len());
+
+        // Items are independent, so with the `parallel` feature they spread over
+        // the thread pool; collecting keeps the results in input order
+        #[cfg(feature = "parallel")]
+        let results: Vec<BatchResult> = items.par_iter().map(|item| self.format_batch_item(item)).collect();
+        #[cfg(not(feature = "parallel"))]
+        let results: Vec<BatchResult> = items.iter().map(|item| self.format_batch_item(item)).collect();
+        results
+    }
+
+    // Each item stands alone, so one bad file doesn't abort the rest
+    fn format_batch_item(&self, item: &BatchItem) -> BatchResult {
+        let options = ProcessingOptions::new(&item.document_type, &item.original_name);
+        match self.format_internal(&item.data, &options) {
+            Ok(result) => BatchResult {
+                original_name: item.original_name.clone(),
+                ok: true,
+                result: Some(result),
+                error_code: None,
+                error: None,
+            },
+            Err(error) => BatchResult {
+                original_name: item.original_name.clone(),
+                ok: false,
+                result: None,
+                error_code: Some(error.code().to_string()),
+                error: Some(error.message()),
+            },
+        }
+    }
+
+    // Cheap pre-flight check: decode and inspect only, never encode
+    #[wasm_bindgen]
+    pub fn validate_document(&self, file_data: &[u8], document_type: &str) -> Result<JsValue, FormatError> {
+        Ok(serde_wasm_bindgen::to_value(&self.validation_report(file_data, document_type)?)?)
+    }
+
+    fn validation_report(&self, file_data: &[u8], document_type: &str) -> Result<ValidationReport, FormatError> {
+        let config = self.config.as_ref().ok_or(FormatError::ConfigNotSet)?;
+        let format_config = resolve_format(config, document_type)?;
+        let mut issues = Vec::new();
+
+        let input_size_kb = (file_data.len() / 1024) as u32;
+        if file_data.len() > config.max_file_size as usize * 1024 {
+            issues.push(format!(
+                "Input {}KB exceeds {} limit of {}KB",
+                input_size_kb, config.name, config.max_file_size
+            ));
+        }
+
+        let detected_format = detect_mime_type(file_data);
+        if let Err(error) = core::check_allowed_format(config, file_data) {
+            issues.push(error.to_string());
+        }
+
+        let (mut input_width, mut input_height) = (0, 0);
+        let mut blank_coverage = 0.0;
+        if detected_format != Some("application/pdf") {
+            match decode_image(file_data, config.max_megapixels) {
+                Ok(img) => {
+                    blank_coverage = enhance::uniform_coverage(&img);
+                    if blank_coverage > DEFAULT_BLANK_THRESHOLD {
+                        issues.push("This image appears blank".to_string());
+                    }
+
+                    // Report the upright dimensions the pipeline will actually see
+                    (input_width, input_height) = img.dimensions();
+                    if matches!(read_exif_orientation(file_data), Some(5..=8)) {
+                        std::mem::swap(&mut input_width, &mut input_height);
+                    }
+                }
+                Err(error) => issues.push(error.to_string()),
+            }
+        }
+
+        let will_upscale = input_width > 0 && needs_upscale((input_width, input_height), format_config);
+
+        let density = input_density(file_data);
+        let report = ValidationReport {
+            ok: issues.is_empty(),
+            detected_format: detected_format.map(str::to_string),
+            input_width,
+            input_height,
+            input_size_kb,
+            will_upscale,
+            blank: blank_coverage > DEFAULT_BLANK_THRESHOLD,
+            blank_coverage,
+            is_likely_already_compressed: is_likely_already_compressed(file_data),
+            dpi_x: density.map(|(x, _)| x),
+            dpi_y: density.map(|(_, y)| y),
+            non_square_dpi: density.is_some_and(|(x, y)| x != y),
+            issues,
+        };
+        Ok(report)
+    }
+
+    // Bundle images into one PDF, one per page at their physical print size.
+    // Items carrying a `document_type` run through the formatter first
+    #[wasm_bindgen]
+    pub fn build_pdf(&self, items_js: &JsValue, page_size: Option<String>) -> Result<Vec<u8>, FormatError> {
+        let items: Vec<PdfItem> = serde_wasm_bindgen::from_value(items_js.clone())?;
+        if items.is_empty() {
+            return Err(FormatError::InvalidInput("build_pdf needs at least one image".to_string()));
+        }
+
+        let page_size = match page_size.as_deref().map(str::to_uppercase).as_deref() {
+            None | Some("A4") => pdf::A4,
+            Some("LETTER") => pdf::LETTER,
+            Some(other) => {
+                return Err(FormatError::InvalidInput(format!(
+                    "Unknown page size '{}'. Expected A4 or LETTER",
+                    other
+                )))
+            }
+        };
+
+        let mut pages = Vec::with_capacity(items.len());
+        for (index, item) in items.iter().enumerate() {
+            let (data, dpi) = match &item.document_type {
+                Some(document_type) => {
+                    let config = self.config.as_ref().ok_or(FormatError::ConfigNotSet)?;
+                    let options = ProcessingOptions::new(document_type, "");
+                    let result = self.format_internal(&item.data, &options)?;
+                    let dpi = item.dpi.unwrap_or(resolve_format(config, document_type)?.dpi);
+                    (result.data, dpi)
+                }
+                None => (item.data.clone(), item.dpi.unwrap_or(DocumentFormat::default().dpi)),
+            };
+
+            let max_megapixels = self.config.as_ref().map_or(DEFAULT_MAX_MEGAPIXELS, |config| config.max_megapixels);
+            let img = decode_image(&data, max_megapixels).map_err(|e| match e {
+                FormatError::DecodeFailed(message) => FormatError::DecodeFailed(format!("item {}: {}", index, message)),
+                other => other,
+            })?;
+            pages.push(PdfPage { image: PdfImage::from_encoded(&data, &img), dpi });
+        }
+
+        console_log!("Building PDF with {} pages", pages.len());
+        Ok(pdf::write_pdf(&pages, page_size))
+    }
+
+    // One encode at the given quality through the normal pipeline, so a UI
+    // quality slider can preview the exact size. Rounded up to whole KB
+    #[wasm_bindgen]
+    pub fn estimate_size(&self, file_data: &[u8], document_type: &str, quality: u8) -> Result<u32, FormatError> {
+        if !(1..=100).contains(&quality) {
+            return Err(FormatError::InvalidInput(format!("Quality {} is outside 1-100", quality)));
+        }
+        let config = self.config.as_ref().ok_or(FormatError::ConfigNotSet)?;
+        let format_config = resolve_format(config, document_type)?;
+        let resolved = resolve_auto_format(config, format_config, file_data);
+        let format_config = &*resolved;
+
+        let options = ProcessingOptions::new(document_type, "");
+        let prepared = core::prepare_image(config, format_config, file_data, &options, &mut Timings::default())?;
+        let encoded = core::encode_image(&prepared.image, format_config, quality)?;
+        Ok(encoded.len().div_ceil(1024) as u32)
+    }
+
+    // Encoded size at each quality for plotting a size-vs-quality slider. The
+    // upload is decoded and resized once and shared across every encode
+    #[wasm_bindgen]
+    pub fn size_curve(&self, file_data: &[u8], document_type: &str, qualities: Vec<u8>) -> Result<JsValue, FormatError> {
+        Ok(serde_wasm_bindgen::to_value(&self.size_points(file_data, document_type, &qualities)?)?)
+    }
+
+    fn size_points(&self, file_data: &[u8], document_type: &str, qualities: &[u8]) -> Result<Vec<SizePoint>, FormatError> {
+        if let Some(quality) = qualities.iter().find(|q| !(1..=100).contains(*q)) {
+            return Err(FormatError::InvalidInput(format!("Quality {} is outside 1-100", quality)));
+        }
+        let config = self.config.as_ref().ok_or(FormatError::ConfigNotSet)?;
+        let format_config = resolve_format(config, document_type)?;
+        let resolved = resolve_auto_format(config, format_config, file_data);
+        let format_config = &*resolved;
+
+        let options = ProcessingOptions::new(document_type, "");
+        let prepared = core::prepare_image(config, format_config, file_data, &options, &mut Timings::default())?;
+        qualities
+            .iter()
+            .map(|&quality| {
+                let encoded = core::encode_image(&prepared.image, format_config, quality)?;
+                Ok(SizePoint { quality, size_kb: encoded.len().div_ceil(1024) as u32 })
+            })
+            .collect()
+    }
+
+    // Several output sizes, e.g. the formatted document plus a preview, from a
+    // single decode. Each variant keeps the document type's fit mode and passes
+    // and is compressed to its size limit like a normal conversion
+    #[wasm_bindgen]
+    pub fn format_variants(&self, file_data: &[u8], document_type: &str, variants: JsValue) -> Result<JsValue, FormatError> {
+        let variants: Vec<VariantSpec> = serde_wasm_bindgen::from_value(variants)?;
+        Ok(serde_wasm_bindgen::to_value(&self.variant_outputs(file_data, document_type, variants)?)?)
+    }
+
+    fn variant_outputs(&self, file_data: &[u8], document_type: &str, variants: Vec<VariantSpec>) -> Result<Vec<VariantOutput>, FormatError> {
+        if variants.is_empty() {
+            return Err(FormatError::InvalidInput("At least one variant is required".to_string()));
+        }
+        let config = self.config.as_ref().ok_or(FormatError::ConfigNotSet)?;
+        let format_config = resolve_format(config, document_type)?;
+        let specs = variants
+            .iter()
+            .map(|variant| {
+                if variant.width == 0 || variant.height == 0 {
+                    return Err(FormatError::InvalidInput(format!(
+                        "Variant '{}' has empty dimensions {}x{}",
+                        variant.suffix, variant.width, variant.height
+                    )));
+                }
+                Ok(DocumentFormat {
+                    width: variant.width,
+                    height: variant.height,
+                    format: parse_image_output(&variant.format, variant.quality)?,
+                    quality: variant.quality,
+                    ..format_config.clone()
+                })
+            })
+            .collect::<Result<Vec<_>, FormatError>>()?;
+
+        let options = ProcessingOptions::new(document_type, "");
+        let mut timings = Timings::default();
+        let source = core::prepare_source(config, format_config, file_data, &options, &mut timings)?;
+        let target_size =
+            with_size_margin((format_config.max_size as usize * 1024).min(config.max_file_size as usize * 1024), format_config);
+
+        let mut outputs = Vec::with_capacity(specs.len());
+        for (variant, spec) in variants.into_iter().zip(&specs) {
+            let prepared = core::finish_image(source.clone(), spec, &options, &mut timings)?;
+            let mut data = core::encode_image(&prepared.image, spec, spec.quality)?;
+            if data.len() > target_size {
+                (data, _, _) = core::compress_to_target_size(&prepared.image, target_size, spec, (spec.quality, data), None)?;
+            }
+            console_log!("Variant '{}': {}x{}, {}KB", variant.suffix, prepared.image.width(), prepared.image.height(), data.len() / 1024);
+            outputs.push(VariantOutput {
+                suffix: variant.suffix,
+                width: prepared.image.width(),
+                height: prepared.image.height(),
+                size_kb: data.len().div_ceil(1024) as u32,
+                data,
+            });
+        }
+        Ok(outputs)
+    }
+
+    // `format` is checked and its cm sizes converted like a stored profile;
+    // `self.config` is never read
+    fn format_inline_internal(&self, file_data: &[u8], document_type: &str, format: &DocumentFormat) -> Result<FormatOutput, FormatError> {
+        core::format(file_data, format, document_type)
+    }
+
+    fn format_internal(&self, file_data: &[u8], options: &ProcessingOptions) -> Result<FormatOutput, FormatError> {
+        let config = self.config.as_ref().ok_or(FormatError::ConfigNotSet)?;
+        core::format_with(config, file_data, options)
+    }
+}
+
+// Output format and quality for the standalone image utilities
+fn parse_image_output(output_format: &str, quality: u8) -> Result<String, FormatError> {
+    let format = output_format.trim().to_uppercase();
+    let format = match format.as_str() {
+        "JPG" => "JPEG".to_string(),
+        "JPEG" | "PNG" | "WEBP" | "AVIF" => format,
+        _ => {
+            return Err(FormatError::UnsupportedOutputFormat(format!(
+                "Unknown output format '{}'. Expected JPEG, PNG, WEBP or AVIF",
+                output_format
+            )))
+        }
+    };
+    if !(1..=100).contains(&quality) {
+        return Err(FormatError::InvalidInput(format!("Quality {} is outside 1-100", quality)));
+    }
+    Ok(format)
+}
+
+// Standalone crop for a manual crop step in the UI, before `format_document`.
+// Coordinates refer to the upright image, after its EXIF orientation
+#[wasm_bindgen]
+pub fn crop(
+    file_data: &[u8],
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+    output_format: &str,
+    quality: u8,
+) -> Result<Vec<u8>, FormatError> {
+    let format = parse_image_output(output_format, quality)?;
+
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    let img = core::apply_exif_orientation(img, file_data);
+
+    let (src_w, src_h) = img.dimensions();
+    let in_bounds = w > 0 && h > 0 && x as u64 + w as u64 <= src_w as u64 && y as u64 + h as u64 <= src_h as u64;
+    if !in_bounds {
+        return Err(FormatError::InvalidInput(format!(
+            "Crop rectangle {}x{} at ({}, {}) is outside the {}x{} image",
+            w, h, x, y, src_w, src_h
+        )));
+    }
+
+    console_log!("Cropping to ({}, {}) size {}x{}", x, y, w, h);
+    let cropped = img.crop_imm(x, y, w, h);
+    let spec = DocumentFormat { format, ..Default::default() };
+    core::encode_image(&cropped, &spec, quality)
+}
+
+// Split an open-booklet scan into its two pages, left then right, at the
+// fold shadow; scans without a visible gutter are cut at the midpoint.
+// Returns an array of two encoded images, each ready for `format_document`
+#[wasm_bindgen]
+pub fn split_vertical(file_data: &[u8], output_format: &str, quality: u8) -> Result<JsValue, FormatError> {
+    let pages = split_pages(file_data, None, output_format, quality)?;
+    Ok(serde_wasm_bindgen::to_value(&pages)?)
+}
+
+// `split_vertical` at a column picked by the user; `x` is the first column of
+// the right page, in upright image coordinates
+#[wasm_bindgen]
+pub fn split_at_x(file_data: &[u8], x: u32, output_format: &str, quality: u8) -> Result<JsValue, FormatError> {
+    let pages = split_pages(file_data, Some(x), output_format, quality)?;
+    Ok(serde_wasm_bindgen::to_value(&pages)?)
+}
+
+fn split_pages(
+    file_data: &[u8],
+    x: Option<u32>,
+    output_format: &str,
+    quality: u8,
+) -> Result<Vec<serde_bytes::ByteBuf>, FormatError> {
+    let format = parse_image_output(output_format, quality)?;
+
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    let img = core::apply_exif_orientation(img, file_data);
+    let (w, h) = img.dimensions();
+
+    let x = match x {
+        Some(x) => x,
+        None => enhance::find_gutter(&img).unwrap_or_else(|| {
+            console_log!("No gutter found, splitting at the midpoint");
+            w / 2
+        }),
+    };
+    if x == 0 || x >= w {
+        return Err(FormatError::InvalidInput(format!(
+            "Split column {} is outside the {}x{} image",
+            x, w, h
+        )));
+    }
+
+    console_log!("Splitting {}x{} scan at column {}", w, h, x);
+    let spec = DocumentFormat { format, ..Default::default() };
+    [img.crop_imm(0, 0, x, h), img.crop_imm(x, 0, w - x, h)]
+        .iter()
+        .map(|page| Ok(serde_bytes::ByteBuf::from(core::encode_image(page, &spec, quality)?)))
+        .collect()
+}
+
+// Pad to an exact aspect ratio for portals that check the ratio but accept a
+// range of resolutions. Unlike the fit modes nothing is scaled
+#[wasm_bindgen]
+pub fn pad_to_aspect(
+    file_data: &[u8],
+    ratio_w: u32,
+    ratio_h: u32,
+    bg_color: &str,
+    output_format: &str,
+    quality: u8,
+) -> Result<Vec<u8>, FormatError> {
+    if ratio_w == 0 || ratio_h == 0 {
+        return Err(FormatError::InvalidInput(format!(
+            "Aspect ratio {}:{} must be nonzero on both sides",
+            ratio_w, ratio_h
+        )));
+    }
+    let color = parse_hex_color(bg_color)?;
+    let format = parse_image_output(output_format, quality)?;
+
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    let img = core::apply_exif_orientation(img, file_data);
+
+    let padded = enhance::pad_to_aspect(&img, ratio_w, ratio_h, color);
+    console_debug!(
+        "Padded {}x{} to {}x{} for ratio {}:{}",
+        img.width(), img.height(), padded.width(), padded.height(), ratio_w, ratio_h
+    );
+    let spec = DocumentFormat { format, ..Default::default() };
+    core::encode_image(&padded, &spec, quality)
+}
+
+// Stack several images (front and back of an ID) into one upload, top to
+// bottom. `scale_to` is "max" or "min": the widest or narrowest width wins
+#[wasm_bindgen]
+pub fn stitch_vertical(
+    images_js: &JsValue,
+    scale_to: &str,
+    gap_px: u32,
+    bg_color: &str,
+    output_format: &str,
+    quality: u8,
+) -> Result<Vec<u8>, FormatError> {
+    stitch_images(images_js, true, scale_to, gap_px, bg_color, output_format, quality)
+}
+
+// Same as `stitch_vertical` but left to right, matching heights
+#[wasm_bindgen]
+pub fn stitch_horizontal(
+    images_js: &JsValue,
+    scale_to: &str,
+    gap_px: u32,
+    bg_color: &str,
+    output_format: &str,
+    quality: u8,
+) -> Result<Vec<u8>, FormatError> {
+    stitch_images(images_js, false, scale_to, gap_px, bg_color, output_format, quality)
+}
+
+fn stitch_images(
+    images_js: &JsValue,
+    vertical: bool,
+    scale_to: &str,
+    gap_px: u32,
+    bg_color: &str,
+    output_format: &str,
+    quality: u8,
+) -> Result<Vec<u8>, FormatError> {
+    let files: Vec<serde_bytes::ByteBuf> = serde_wasm_bindgen::from_value(images_js.clone())?;
+    let color = parse_hex_color(bg_color)?;
+    let format = parse_image_output(output_format, quality)?;
+    let use_max = match scale_to {
+        "max" => true,
+        "min" => false,
+        other => {
+            return Err(FormatError::InvalidInput(format!(
+                "Unknown scale_to '{}'. Expected max or min",
+                other
+            )))
+        }
+    };
+
+    let images = files
+        .iter()
+        .map(|file| Ok(core::apply_exif_orientation(decode_image(file, DEFAULT_MAX_MEGAPIXELS)?, file)))
+        .collect::<Result<Vec<_>, FormatError>>()?;
+    let stitched = stitch_decoded(&images, vertical, use_max, gap_px, color)?;
+    console_debug!("Stitched {} images into {}x{}", images.len(), stitched.width(), stitched.height());
+    let spec = DocumentFormat { format, ..Default::default() };
+    core::encode_image(&stitched, &spec, quality)
+}
+
+fn stitch_decoded(
+    images: &[DynamicImage],
+    vertical: bool,
+    use_max: bool,
+    gap_px: u32,
+    color: Rgba<u8>,
+) -> Result<DynamicImage, FormatError> {
+    let sides = images.iter().map(|img| if vertical { img.width() } else { img.height() });
+    let side = if use_max { sides.max() } else { sides.min() }
+        .ok_or_else(|| FormatError::InvalidInput("No images to stitch".to_string()))?;
+
+    // Check the canvas fits in memory before allocating it
+    let length: f64 = images
+        .iter()
+        .map(|img| {
+            let (shared, other) = if vertical { (img.width(), img.height()) } else { (img.height(), img.width()) };
+            other as f64 * side as f64 / shared.max(1) as f64
+        })
+        .sum::<f64>()
+        + gap_px as f64 * (images.len() - 1) as f64;
+    let megapixels = length * side as f64 / 1_000_000.0;
+    if megapixels > DEFAULT_MAX_MEGAPIXELS as f64 {
+        return Err(FormatError::TooLarge(format!(
+            "Stitched image would be {:.1} MP, above the {} MP limit",
+            megapixels, DEFAULT_MAX_MEGAPIXELS
+        )));
+    }
+    Ok(enhance::stitch(images, vertical, side, gap_px, color))
+}
+
+// Share of near-uniform pixels above which an upload counts as blank. Thin
+// signatures can come close, so callers can tune it through `is_blank`
+const DEFAULT_BLANK_THRESHOLD: f32 = 0.98;
+
+// Flag accidentally blank uploads, such as an empty scan or a white image
+// where a signature should be
+#[wasm_bindgen]
+pub fn is_blank(file_data: &[u8], threshold: f32) -> Result<JsValue, FormatError> {
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(FormatError::InvalidInput(format!("Blank threshold {} must be between 0 and 1", threshold)));
+    }
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    let coverage = enhance::uniform_coverage(&img);
+    Ok(serde_wasm_bindgen::to_value(&BlankReport { blank: coverage > threshold, coverage })?)
+}
+
+// Dimensions, density and pixel layout without converting anything. JPEG,
+// PNG, GIF and TIFF are read from their headers; other formats are decoded
+#[wasm_bindgen]
+pub fn probe_metadata(file_data: &[u8]) -> Result<JsValue, FormatError> {
+    Ok(serde_wasm_bindgen::to_value(&read_metadata(file_data)?)?)
+}
+
+fn read_metadata(file_data: &[u8]) -> Result<ImageMetadata, FormatError> {
+    use image::ImageDecoder;
+    let format = detect_mime_type(file_data).ok_or(FormatError::UnrecognizedFormat)?;
+    let decode_failed = |e: image::ImageError| FormatError::DecodeFailed(e.to_string());
+    let (width, height) = image::io::Reader::new(std::io::Cursor::new(file_data))
+        .with_guessed_format()
+        .map_err(|e| FormatError::DecodeFailed(e.to_string()))?
+        .into_dimensions()
+        .map_err(decode_failed)?;
+
+    let cursor = std::io::Cursor::new(file_data);
+    let color_type = match format {
+        "image/jpeg" => image::codecs::jpeg::JpegDecoder::new(cursor).map_err(decode_failed)?.color_type(),
+        "image/png" => image::codecs::png::PngDecoder::new(cursor).map_err(decode_failed)?.color_type(),
+        "image/gif" => image::codecs::gif::GifDecoder::new(cursor).map_err(decode_failed)?.color_type(),
+        "image/tiff" => image::codecs::tiff::TiffDecoder::new(cursor).map_err(decode_failed)?.color_type(),
+        _ => decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?.color(),
+    };
+    // The PNG decoder widens 1, 2 and 4-bit samples, so read the stored depth from IHDR
+    let bit_depth = match file_data.get(24) {
+        Some(&depth) if format == "image/png" => depth as u32,
+        _ => color_type.bits_per_pixel() as u32 / color_type.channel_count() as u32,
+    };
+
+    let density = input_density(file_data);
+    Ok(ImageMetadata {
+        width,
+        height,
+        format: format.to_string(),
+        dpi: density.map(|(x, _)| x),
+        dpi_x: density.map(|(x, _)| x),
+        dpi_y: density.map(|(_, y)| y),
+        non_square_dpi: density.is_some_and(|(x, y)| x != y),
+        has_exif: metadata::has_exif(file_data),
+        has_alpha: color_type.has_alpha(),
+        bit_depth,
+        color_type: format!("{:?}", color_type).to_lowercase(),
+    })
+}
+
+// Background color from the edges of the image, so padding and borders can
+// blend in; a scan on white paper reports (near) white
+#[wasm_bindgen]
+pub fn dominant_color(file_data: &[u8]) -> Result<JsValue, FormatError> {
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    let image::Rgb([r, g, b]) = enhance::edge_color(&img);
+    Ok(serde_wasm_bindgen::to_value(&RgbColor { r, g, b })?)
+}
+
+// Guess whether an upload is a photo, a screenshot or a document scan, e.g. to
+// suggest PNG for a screenshot before it is converted to a blurry JPEG
+#[wasm_bindgen]
+pub fn classify_image(file_data: &[u8]) -> Result<JsValue, FormatError> {
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    Ok(serde_wasm_bindgen::to_value(&classify::classify(&img))?)
+}
+
+// SSIM between two images, for QA checks that a formatted output still looks
+// like its source. Near 1.0 means similar; sizes may differ
+#[wasm_bindgen]
+pub fn perceptual_diff(a: &[u8], b: &[u8]) -> Result<f32, FormatError> {
+    let first = decode_image(a, DEFAULT_MAX_MEGAPIXELS)?;
+    let second = decode_image(b, DEFAULT_MAX_MEGAPIXELS)?;
+    Ok(enhance::ssim(&first, &second))
+}
+
+// Highest DPI `pdf_page_to_image` renders at; an A4 page is ~5000px tall there
+const MAX_RENDER_DPI: u32 = 600;
+
+// Rasterize one page of a PDF (counted from zero) to a PNG, e.g. to turn a
+// PDF-only certificate into an image before `format_document`
+#[wasm_bindgen]
+pub fn pdf_page_to_image(pdf_data: &[u8], page: u32, target_dpi: u32) -> Result<Vec<u8>, FormatError> {
+    if !(1..=MAX_RENDER_DPI).contains(&target_dpi) {
+        return Err(FormatError::InvalidInput(format!(
+            "Render DPI {} must be between 1 and {}",
+            target_dpi, MAX_RENDER_DPI
+        )));
+    }
+    if !pdf_data.starts_with(b"%PDF") {
+        return Err(FormatError::InvalidInput("Input is not a PDF".to_string()));
+    }
+    render_pdf_page(pdf_data, page, target_dpi)
+}
+
+#[cfg(feature = "pdf-render")]
+fn render_pdf_page(pdf_data: &[u8], page: u32, dpi: u32) -> Result<Vec<u8>, FormatError> {
+    console_log!("Rendering PDF page {} at {} DPI", page, dpi);
+    let img = pdf_render::render_page(pdf_data, page, dpi)?;
+    let spec = DocumentFormat { format: "PNG".to_string(), dpi, ..Default::default() };
+    core::encode_png(&img, &spec)
+}
+
+#[cfg(not(feature = "pdf-render"))]
+fn render_pdf_page(_pdf_data: &[u8], _page: u32, _dpi: u32) -> Result<Vec<u8>, FormatError> {
+    Err(FormatError::UnsupportedInput("PDF rendering is not enabled in this build".to_string()))
+}
+
+// Largest preview edge `make_thumbnail` will produce
+const MAX_THUMBNAIL_EDGE: u32 = 512;
+// Used by `format_document_with_preview` when no edge is given
+const DEFAULT_PREVIEW_EDGE: u32 = 256;
+
+// Small low-quality JPEG preview for the UI, so the DOM never has to scale the
+// full output. Images already inside the box are not enlarged
+#[wasm_bindgen]
+pub fn make_thumbnail(file_data: &[u8], max_edge: u32) -> Result<Vec<u8>, FormatError> {
+    check_thumbnail_edge(max_edge)?;
+    let img = decode_image(file_data, DEFAULT_MAX_MEGAPIXELS)?;
+    let img = core::apply_exif_orientation(img, file_data);
+    core::thumbnail_jpeg(&img, max_edge)
+}
+
+fn check_thumbnail_edge(max_edge: u32) -> Result<(), FormatError> {
+    if !(1..=MAX_THUMBNAIL_EDGE).contains(&max_edge) {
+        return Err(FormatError::InvalidInput(format!(
+            "Thumbnail edge {} must be between 1 and {}",
+            max_edge, MAX_THUMBNAIL_EDGE
+        )));
+    }
+    Ok(())
+}
+
+impl Default for DocumentFormatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Registry of every built-in exam, keyed by lowercase exam code
+fn exam_registry() -> HashMap<&'static str, fn() -> ExamConfig> {
+    let mut registry: HashMap<&'static str, fn() -> ExamConfig> = HashMap::new();
+    registry.insert("upsc", upsc_config);
+    registry.insert("neet", neet_config);
+    registry.insert("neet_pg", neet_pg_config);
+    registry.insert("jee", jee_config);
+    registry.insert("cat", cat_config);
+    registry.insert("gate", gate_config);
+    registry.insert("ssc", ssc_config);
+    registry
+}
+
+// Exams registered from JS at runtime, keyed by lowercase code. They take
+// precedence over the built-in exams of the same code
+static CUSTOM_CONFIGS: Mutex<BTreeMap<String, ExamConfig>> = Mutex::new(BTreeMap::new());
+
+fn custom_configs() -> MutexGuard<'static, BTreeMap<String, ExamConfig>> {
+    CUSTOM_CONFIGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn registered_codes() -> Vec<String> {
+    let mut codes: Vec<String> = exam_registry().keys().map(|code| code.to_string()).collect();
+    codes.extend(custom_configs().keys().cloned());
+    codes.sort();
+    codes.dedup();
+    codes
+}
+
+fn lookup_config(code: &str) -> Result<ExamConfig, FormatError> {
+    let normalized = code.trim().to_lowercase();
+    if let Some(config) = custom_configs().get(&normalized) {
+        return Ok(config.clone());
+    }
+    exam_registry()
+        .get(normalized.as_str())
+        .map(|builder| builder())
+        .ok_or_else(|| FormatError::UnknownExamCode {
+            code: code.to_string(),
+            available: registered_codes(),
+        })
+}
+
+// Store a validated config so `get_config_by_code` finds it, letting the CMS
+// add exams without a rebuild. An existing code, built-in or not, is only
+// replaced with `overwrite`
+#[wasm_bindgen]
+pub fn register_config(config_js: &JsValue, overwrite: Option<bool>) -> Result<(), FormatError> {
+    let config: ExamConfig = serde_wasm_bindgen::from_value(config_js.clone())?;
+    register_exam_config(config, overwrite.unwrap_or(false))
+}
+
+fn register_exam_config(config: ExamConfig, overwrite: bool) -> Result<(), FormatError> {
+    let config = checked_config(config)?;
+    let code = config.code.trim().to_lowercase();
+    if code.is_empty() {
+        return Err(FormatError::InvalidConfig("Exam code must not be empty".to_string()));
+    }
+    if !overwrite && registered_codes().contains(&code) {
+        return Err(FormatError::DuplicateExamCode(code));
+    }
+
+    console_log!("Registering configuration for exam code: {}", code);
+    custom_configs().insert(code, config);
+    Ok(())
+}
+
+#[wasm_bindgen]
+pub fn get_config_by_code(code: &str) -> Result<JsValue, FormatError> {
+    let config = lookup_config(code)?;
+    console_log!("Loading configuration for exam code: {}", config.code);
+    Ok(serde_wasm_bindgen::to_value(&config)?)
+}
+
+#[wasm_bindgen]
+pub fn list_exam_codes() -> JsValue {
+    serde_wasm_bindgen::to_value(&registered_codes()).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn get_upsc_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&upsc_config()).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn get_neet_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&neet_config()).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn get_neet_pg_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&neet_pg_config()).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn get_jee_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&jee_config()).unwrap()
+}
+
+#[wasm_bindgen]
+pub fn get_cat_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&cat_config()).unwrap()
+}
 
-        // Determine which format to use based on document type
-        let format_config = match document_type {
-            "photo" => &config.formats.photo,
-            "signature" => &config.formats.signature,
-            _ => &config.formats.documents,
-        };
+#[wasm_bindgen]
+pub fn get_gate_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&gate_config()).unwrap()
+}
 
-        console_log!("Using format config: {}x{} at {} DPI", 
-                    format_config.width, format_config.height, format_config.dpi);
-
-        // Handle PDF files differently
-        if original_name.to_lowercase().ends_with(".pdf") && format_config.format == "PDF" {
-            console_log!("Processing PDF file: {}", original_name);
-            return self.process_pdf(file_data, format_config);
-        }
-        // Load and process the image
-        let img = image::load_from_memory(file_data)
-            .map_err(|e| JsValue::from_str(&format!("Failed to load image: {}", e)))?;
-
-        console_log!("Original image dimensions: {}x{}", img.width(), img.height());
-        // Resize the image
-        let resized_img = img.resize_exact(
-            format_config.width,
-            format_config.height,
-            image::imageops::FilterType::Lanczos3,
-        );
-
-        console_log!("Resized image to: {}x{}", format_config.width, format_config.height);
-        // Convert to the target format and compress
-        let output_format = match format_config.format.as_str() {
-            "JPEG" => ImageOutputFormat::Jpeg(format_config.quality),
-            "PNG" => ImageOutputFormat::Png,
-            _ => ImageOutputFormat::Jpeg(format_config.quality),
-        };
+#[wasm_bindgen]
+pub fn get_ssc_config() -> JsValue {
+    serde_wasm_bindgen::to_value(&ssc_config()).unwrap()
+}
 
-        let mut output_buffer = Vec::new();
-        let mut cursor = Cursor::new(&mut output_buffer);
-        
-        resized_img.write_to(&mut cursor, output_format)
-            .map_err(|e| JsValue::from_str(&format!("Failed to encode image: {}", e)))?;
-
-        // Check if we need to compress further to meet size requirements
-        let target_size = format_config.max_size * 1024; // Convert KB to bytes
-        if output_buffer.len() > target_size as usize {
-            console_log!("File too large ({}KB), compressing further", output_buffer.len() / 1024);
-            output_buffer = self.compress_to_target_size(
-                &resized_img,
-                target_size as usize,
-                &format_config.format,
-            )?;
-        }
-
-        console_log!("Document formatted successfully. Final size: {}KB", output_buffer.len() / 1024);
-        Ok(output_buffer)
-    }
-
-    fn process_pdf(&self, file_data: &[u8], format_config: &DocumentFormat) -> Result<Vec<u8>, JsValue> {
-        console_log!("Processing PDF document");
-        
-        // For PDF files, we'll just check the size and return as-is if within limits
-        // In a more advanced implementation, you could use a PDF library to resize/compress
-        let target_size = format_config.max_size * 1024;
-        
-        if file_data.len() <= target_size as usize {
-            console_log!("PDF size is within limits: {}KB", file_data.len() / 1024);
-            Ok(file_data.to_vec())
-        } else {
-            console_log!("PDF too large: {}KB, target: {}KB", file_data.len() / 1024, format_config.max_size);
-            Err(JsValue::from_str(&format!(
-                "PDF file is too large ({}KB). Maximum allowed size is {}KB. Please compress the PDF manually.",
-                file_data.len() / 1024,
-                format_config.max_size
-            )))
-        }
+// Shared format presets so exam configs compose specs instead of repeating
+// literals
+fn jpeg_format(width: u32, height: u32, dpi: u32, quality: u8, max_size: u32) -> DocumentFormat {
+    DocumentFormat {
+        width,
+        height,
+        dpi,
+        format: "JPEG".to_string(),
+        quality,
+        max_size,
+        ..Default::default()
     }
-    fn compress_to_target_size(
-        &self,
-        img: &DynamicImage,
-        target_size: usize,
-        format: &str,
-    ) -> Result<Vec<u8>, JsValue> {
-        console_log!("Compressing image to target size: {}KB", target_size / 1024);
-        
-        let mut quality = 95u8;
-        let mut output_buffer;
-
-        loop {
-            output_buffer = Vec::new();
-            let mut cursor = Cursor::new(&mut output_buffer);
-            
-            let output_format = match format {
-                "JPEG" => ImageOutputFormat::Jpeg(quality),
-                "PNG" => ImageOutputFormat::Png,
-                _ => ImageOutputFormat::Jpeg(quality),
-            };
-
-            img.write_to(&mut cursor, output_format)
-                .map_err(|e| JsValue::from_str(&format!("Failed to encode image: {}", e)))?;
+}
 
-            console_log!("Compression attempt with quality {}: {}KB", quality, output_buffer.len() / 1024);
-            if output_buffer.len() <= target_size || quality <= 10 {
-                break;
-            }
+// The common central-government photo spec: 3.5x4.5 cm JPEG at ~200 DPI,
+// 20-50 KB
+fn gov_photo_format_20kb_to_50kb() -> DocumentFormat {
+    DocumentFormat {
+        min_size: Some(20),
+        ..jpeg_format(276, 354, 200, 85, 50)
+    }
+}
 
-            quality = (quality as f32 * 0.9) as u8;
-            if quality < 10 {
-                quality = 10;
-            }
-        }
+// Its signature counterpart: 4.0x2.0 cm JPEG at ~200 DPI, 10-20 KB
+fn gov_signature_format_10kb_to_20kb() -> DocumentFormat {
+    DocumentFormat {
+        min_size: Some(10),
+        ..jpeg_format(315, 157, 200, 85, 20)
+    }
+}
 
-        console_log!("Final compressed size: {}KB with quality: {}", output_buffer.len() / 1024, quality);
-        Ok(output_buffer)
+// CBSE and state board marksheets: 1000px on the long (A4 portrait) edge
+// keeps the printed marks legible within 300 KB. Landscape scans are padded
+// rather than squashed
+fn board_marksheet_format() -> DocumentFormat {
+    DocumentFormat {
+        fit_mode: "contain".to_string(),
+        ..jpeg_format(707, 1000, 150, 85, 300)
     }
 }
 
 // Utility functions for different exam types
-#[wasm_bindgen]
-pub fn get_upsc_config() -> JsValue {
-    let config = ExamConfig {
+fn upsc_config() -> ExamConfig {
+    ExamConfig {
         name: "UPSC".to_string(),
         code: "upsc".to_string(),
         formats: ExamFormats {
-            photo: DocumentFormat {
-                width: 300,
-                height: 400,
-                dpi: 300,
-                format: "JPEG".to_string(),
-                quality: 85,
-                max_size: 200,
-            },
-            signature: DocumentFormat {
-                width: 300,
-                height: 100,
-                dpi: 300,
-                format: "JPEG".to_string(),
-                quality: 85,
-                max_size: 50,
-            },
+            photo: jpeg_format(300, 400, 300, 85, 200),
+            signature: jpeg_format(300, 100, 300, 85, 50),
             documents: DocumentFormat {
                 width: 600,
                 height: 800,
@@ -228,10 +1703,11 @@ pub fn get_upsc_config() -> JsValue {
                 format: "PDF".to_string(),
                 quality: 80,
                 max_size: 500,
+                ..Default::default()
             },
         },
         max_file_size: 2048,
-        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string()],
+        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string(), "image/gif".to_string(), "image/tiff".to_string()],
         document_types: vec![
             "photo".to_string(),
             "signature".to_string(),
@@ -241,44 +1717,24 @@ pub fn get_upsc_config() -> JsValue {
             "caste_certificate".to_string(),
             "income_certificate".to_string(),
         ],
-    };
-
-    serde_wasm_bindgen::to_value(&config).unwrap()
+        document_type_formats: HashMap::new(),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
 }
 
-#[wasm_bindgen]
-pub fn get_neet_config() -> JsValue {
-    let config = ExamConfig {
+fn neet_config() -> ExamConfig {
+    ExamConfig {
         name: "NEET".to_string(),
         code: "neet".to_string(),
         formats: ExamFormats {
-            photo: DocumentFormat {
-                width: 200,
-                height: 230,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 80,
-                max_size: 100,
-            },
-            signature: DocumentFormat {
-                width: 200,
-                height: 80,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 80,
-                max_size: 30,
-            },
-            documents: DocumentFormat {
-                width: 600,
-                height: 800,
-                dpi: 150,
-                format: "JPEG".to_string(),
-                quality: 75,
-                max_size: 300,
-            },
+            photo: jpeg_format(200, 230, 200, 80, 100),
+            signature: jpeg_format(200, 80, 200, 80, 30),
+            documents: jpeg_format(600, 800, 150, 75, 300),
         },
         max_file_size: 1024,
-        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string()],
+        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string(), "image/gif".to_string(), "image/tiff".to_string()],
         document_types: vec![
             "photo".to_string(),
             "signature".to_string(),
@@ -286,75 +1742,92 @@ pub fn get_neet_config() -> JsValue {
             "class12_marksheet".to_string(),
             "aadhaar".to_string(),
         ],
+        document_type_formats: HashMap::from([
+            ("class10_marksheet".to_string(), board_marksheet_format()),
+            ("class12_marksheet".to_string(), board_marksheet_format()),
+        ]),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
+}
+
+// NEET-PG counselling (MCC) wants JPEG only, with a left thumb impression
+// cleaned up like the signature
+fn neet_pg_config() -> ExamConfig {
+    let ink = |width, height, min_size, max_size| DocumentFormat {
+        min_size: Some(min_size),
+        signature_cleanup: true,
+        ..jpeg_format(width, height, 200, 85, max_size)
     };
 
-#[wasm_bindgen]
-pub fn get_jee_config() -> JsValue {
-    let config = ExamConfig {
-        name: "JEE".to_string(),
+    ExamConfig {
+        name: "NEET-PG".to_string(),
+        code: "neet_pg".to_string(),
+        formats: ExamFormats {
+            photo: DocumentFormat {
+                min_size: Some(10),
+                ..jpeg_format(276, 354, 200, 85, 200)
+            },
+            signature: ink(276, 118, 10, 100),
+            documents: jpeg_format(600, 800, 150, 75, 300),
+        },
+        max_file_size: 1024,
+        allowed_formats: vec!["image/jpeg".to_string()],
+        document_types: vec![
+            "photo".to_string(),
+            "signature".to_string(),
+            "thumb_impression".to_string(),
+            "neet_pg_scorecard".to_string(),
+            "mbbs_degree".to_string(),
+            "internship_certificate".to_string(),
+        ],
+        document_type_formats: HashMap::from([("thumb_impression".to_string(), ink(236, 236, 10, 100))]),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
+}
+
+// JEE Main specs published by NTA: a 3.5x4.5 cm photo and 3.5x1.5 cm
+// signature at ~200 DPI, JPEG only
+fn jee_config() -> ExamConfig {
+    ExamConfig {
+        name: "JEE Main".to_string(),
         code: "jee".to_string(),
         formats: ExamFormats {
             photo: DocumentFormat {
-                width: 240,
-                height: 320,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 80,
-                max_size: 150,
+                min_size: Some(10),
+                ..jpeg_format(276, 354, 200, 85, 200)
             },
             signature: DocumentFormat {
-                width: 240,
-                height: 80,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 80,
-                max_size: 40,
-            },
-            documents: DocumentFormat {
-                width: 600,
-                height: 800,
-                dpi: 150,
-                format: "JPEG".to_string(),
-                quality: 75,
-                max_size: 400,
+                min_size: Some(4),
+                ..jpeg_format(276, 118, 200, 85, 30)
             },
+            documents: jpeg_format(600, 800, 150, 75, 300),
         },
         max_file_size: 1536,
-        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string()],
+        allowed_formats: vec!["image/jpeg".to_string()],
         document_types: vec![
             "photo".to_string(),
             "signature".to_string(),
-            "class10_certificate".to_string(),
-            "class12_certificate".to_string(),
-            "aadhaar".to_string(),
+            "category_certificate".to_string(),
+            "pwd_certificate".to_string(),
         ],
-    };
-
-    serde_wasm_bindgen::to_value(&config).unwrap()
+        document_type_formats: HashMap::new(),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
 }
 
-#[wasm_bindgen]
-pub fn get_cat_config() -> JsValue {
-    let config = ExamConfig {
+fn cat_config() -> ExamConfig {
+    ExamConfig {
         name: "CAT".to_string(),
         code: "cat".to_string(),
         formats: ExamFormats {
-            photo: DocumentFormat {
-                width: 200,
-                height: 240,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 85,
-                max_size: 120,
-            },
-            signature: DocumentFormat {
-                width: 200,
-                height: 60,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 85,
-                max_size: 25,
-            },
+            photo: jpeg_format(200, 240, 200, 85, 120),
+            signature: jpeg_format(200, 60, 200, 85, 25),
             documents: DocumentFormat {
                 width: 700,
                 height: 900,
@@ -362,10 +1835,11 @@ pub fn get_cat_config() -> JsValue {
                 format: "PDF".to_string(),
                 quality: 80,
                 max_size: 600,
+                ..Default::default()
             },
         },
         max_file_size: 2048,
-        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string(), "application/pdf".to_string()],
+        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string(), "image/gif".to_string(), "image/tiff".to_string(), "application/pdf".to_string()],
         document_types: vec![
             "photo".to_string(),
             "signature".to_string(),
@@ -373,53 +1847,699 @@ pub fn get_cat_config() -> JsValue {
             "aadhaar".to_string(),
             "category_certificate".to_string(),
         ],
-    };
-
-    serde_wasm_bindgen::to_value(&config).unwrap()
+        document_type_formats: HashMap::new(),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
 }
 
-#[wasm_bindgen]
-pub fn get_gate_config() -> JsValue {
-    let config = ExamConfig {
+// GATE applications go through GOAPS, which accepts certificates as either a
+// PDF or an image, so those get their own per-type profiles
+fn gate_config() -> ExamConfig {
+    let certificate = || DocumentFormat {
+        width: 600,
+        height: 800,
+        dpi: 150,
+        format: "PDF".to_string(),
+        quality: 80,
+        max_size: 300,
+        ..Default::default()
+    };
+
+    ExamConfig {
         name: "GATE".to_string(),
         code: "gate".to_string(),
         formats: ExamFormats {
-            photo: DocumentFormat {
-                width: 240,
-                height: 320,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 80,
-                max_size: 100,
-            },
-            signature: DocumentFormat {
-                width: 240,
-                height: 80,
-                dpi: 200,
-                format: "JPEG".to_string(),
-                quality: 80,
-                max_size: 30,
-            },
-            documents: DocumentFormat {
-                width: 600,
-                height: 800,
-                dpi: 150,
-                format: "JPEG".to_string(),
-                quality: 75,
-                max_size: 350,
-            },
+            photo: jpeg_format(480, 640, 200, 85, 200),
+            signature: jpeg_format(350, 150, 200, 85, 30),
+            documents: jpeg_format(600, 800, 150, 75, 300),
         },
         max_file_size: 1024,
-        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string()],
+        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string(), "image/gif".to_string(), "image/tiff".to_string(), "application/pdf".to_string()],
         document_types: vec![
             "photo".to_string(),
             "signature".to_string(),
-            "graduation_certificate".to_string(),
-            "aadhaar".to_string(),
+            "category_certificate".to_string(),
+            "pwd_certificate".to_string(),
         ],
-    };
+        document_type_formats: HashMap::from([
+            ("category_certificate".to_string(), certificate()),
+            ("pwd_certificate".to_string(), certificate()),
+        ]),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
+}
+
+// SSC (Staff Selection Commission) follows the central-government photo and
+// signature presets
+fn ssc_config() -> ExamConfig {
+    ExamConfig {
+        name: "SSC".to_string(),
+        code: "ssc".to_string(),
+        formats: ExamFormats {
+            photo: gov_photo_format_20kb_to_50kb(),
+            signature: gov_signature_format_10kb_to_20kb(),
+            documents: jpeg_format(600, 800, 150, 75, 300),
+        },
+        max_file_size: 1024,
+        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string(), "image/gif".to_string(), "image/tiff".to_string()],
+        document_types: vec![
+            "photo".to_string(),
+            "signature".to_string(),
+            "category_certificate".to_string(),
+            "ex_serviceman_certificate".to_string(),
+        ],
+        document_type_formats: HashMap::new(),
+        max_megapixels: DEFAULT_MAX_MEGAPIXELS,
+        filename_template: None,
+        allow_animated: true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{encode_image, resolve_format};
+    use crate::test_support::{encode, exam, jpeg, noise, photo, png, run, spec};
+
+    #[test]
+    fn parses_data_urls_and_bare_base64() {
+        assert_eq!(parse_data_url("data:image/PNG;base64,aGVsbG8="), Ok((Some("image/png".to_string()), b"hello".to_vec())));
+        assert_eq!(parse_data_url("  aGVsbG8=\n"), Ok((None, b"hello".to_vec())));
+    }
+
+    #[test]
+    fn rejects_malformed_data_urls() {
+        for input in ["data:image/png;base64", "data:image/png,aGVsbG8=", "data:image/png;base64,not base64!"] {
+            assert!(matches!(parse_data_url(input), Err(FormatError::InvalidInput(_))), "{}", input);
+        }
+    }
+
+    fn item(data: Vec<u8>, original_name: &str) -> BatchItem {
+        BatchItem { data, document_type: "photo".to_string(), original_name: original_name.to_string() }
+    }
+
+    #[test]
+    fn batch_reports_a_corrupt_item_without_failing_the_rest() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 120, 160, 100))) };
+        let good = png(&photo(240, 320));
+        let corrupt = good[..good.len() / 2].to_vec();
 
-    serde_wasm_bindgen::to_value(&config).unwrap()
+        let results = formatter.format_items(&[item(good.clone(), "a.png"), item(corrupt, "b.png"), item(good, "c.png")]);
+        let names: Vec<_> = results.iter().map(|result| result.original_name.as_str()).collect();
+        assert_eq!(names, ["a.png", "b.png", "c.png"]);
+        assert!(results[0].ok && results[2].ok);
+        assert_eq!(results[0].result.as_ref().map(|output| (output.width, output.height)), Some((120, 160)));
+
+        assert!(!results[1].ok && results[1].result.is_none());
+        assert_eq!(results[1].error_code.as_deref(), Some("TRUNCATED_INPUT"));
+        assert!(results[1].error.is_some());
+    }
+
+    #[test]
+    fn validation_flags_upscaling_without_issues() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 300, 400, 100))) };
+        let report = formatter.validation_report(&png(&photo(150, 200)), "photo").unwrap();
+        assert!(report.will_upscale);
+        assert!(report.ok, "{:?}", report.issues);
+        assert_eq!((report.input_width, report.input_height), (150, 200));
+    }
+
+    #[test]
+    fn validation_lists_an_oversized_input() {
+        let config = ExamConfig { max_file_size: 2, ..exam(spec("JPEG", 100, 100, 100)) };
+        let formatter = DocumentFormatter { config: Some(config) };
+        let report = formatter.validation_report(&png(&photo(400, 400)), "photo").unwrap();
+        assert!(!report.ok && !report.will_upscale);
+        assert!(report.issues.iter().any(|issue| issue.contains("exceeds Test limit of 2KB")), "{:?}", report.issues);
+    }
+
+    fn round_trip(config: &ExamConfig) -> ExamConfig {
+        serde_json::from_str(&serde_json::to_string(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn jee_config_round_trips_through_serde() {
+        let config = round_trip(&lookup_config("JEE").unwrap());
+        assert_eq!((config.name.as_str(), config.code.as_str()), ("JEE Main", "jee"));
+        assert_eq!(config.allowed_formats, ["image/jpeg"]);
+        assert_eq!(config.max_file_size, 1536);
+
+        let (photo, signature) = (&config.formats.photo, &config.formats.signature);
+        assert_eq!((photo.width, photo.height, photo.dpi, photo.format.as_str()), (276, 354, 200, "JPEG"));
+        assert_eq!((photo.min_size, photo.max_size), (Some(10), 200));
+        assert_eq!((signature.width, signature.height, signature.min_size, signature.max_size), (276, 118, Some(4), 30));
+        assert!(validate_config(&config).is_empty());
+    }
+
+    #[test]
+    fn crops_the_requested_rectangle() {
+        let source = photo(100, 80);
+        let cropped = image::load_from_memory(&crop(&png(&source), 10, 20, 30, 40, "png", 90).unwrap()).unwrap();
+        assert_eq!(cropped.dimensions(), (30, 40));
+        assert_eq!(cropped.get_pixel(0, 0), source.get_pixel(10, 20));
+        assert_eq!(cropped.get_pixel(29, 39), source.get_pixel(39, 59));
+    }
+
+    #[test]
+    fn rejects_crops_outside_the_image() {
+        let input = png(&photo(100, 80));
+        for (x, y, w, h) in [(80, 0, 30, 10), (0, 0, 0, 10), (1, 1, u32::MAX, 10)] {
+            assert!(matches!(crop(&input, x, y, w, h, "jpeg", 90), Err(FormatError::InvalidInput(_))), "{}x{} at {},{}", w, h, x, y);
+        }
+    }
+
+    #[test]
+    fn invalid_configs_list_every_issue() {
+        let with_photo = |photo: DocumentFormat| {
+            let mut config = exam(spec("JPEG", 300, 400, 100));
+            config.formats.photo = photo;
+            config
+        };
+        let cases = [
+            (
+                ExamConfig { max_file_size: 0, allowed_formats: vec![], ..exam(spec("JPEG", 300, 400, 100)) },
+                vec![
+                    "max_file_size must be greater than 0",
+                    "allowed_formats must not be empty",
+                    "formats.photo.max_size 100KB exceeds max_file_size 0KB",
+                    "formats.signature.max_size 100KB exceeds max_file_size 0KB",
+                    "formats.documents.max_size 100KB exceeds max_file_size 0KB",
+                ],
+            ),
+            (
+                with_photo(DocumentFormat { quality: 0, format: "BMP".to_string(), ..spec("JPEG", 0, 400, 100) }),
+                vec![
+                    "formats.photo: dimensions 0x400 must be nonzero",
+                    "formats.photo.quality 0 out of range 1-100",
+                    "formats.photo.format 'BMP' unsupported",
+                ],
+            ),
+            (
+                with_photo(DocumentFormat { min_size: Some(200), fit_mode: "fill".to_string(), ..spec("JPEG", 300, 400, 100) }),
+                vec!["formats.photo.min_size 200KB exceeds max_size 100KB", "formats.photo.fit_mode 'fill' unsupported"],
+            ),
+            (
+                with_photo(DocumentFormat { min_size: Some(10), ..spec("WEBP", 300, 400, 100) }),
+                vec!["formats.photo.min_size cannot be met for WEBP output; only JPEG and PNG are padded"],
+            ),
+        ];
+        for (config, expected) in cases {
+            assert_eq!(validate_config(&config), expected);
+            assert!(matches!(checked_config(config), Err(FormatError::ConfigValidation(issues)) if issues == expected));
+        }
+    }
+
+    #[test]
+    fn per_type_formats_are_validated_by_path() {
+        let mut config = exam(spec("JPEG", 300, 400, 100));
+        config.document_type_formats.insert("marksheet".to_string(), DocumentFormat { dpi: 0, ..spec("JPEG", 700, 1000, 100) });
+        assert_eq!(validate_config(&config), ["document_type_formats.marksheet.dpi must be greater than 0"]);
+    }
+
+    #[test]
+    fn malformed_config_json_is_rejected() {
+        let mut formatter = DocumentFormatter { config: None };
+        assert!(matches!(formatter.set_config_from_json("{\"name\": "), Err(FormatError::InvalidConfig(_))));
+        let valid = serde_json::to_string(&lookup_config("upsc").unwrap()).unwrap();
+        assert!(formatter.set_config_from_json(&valid).is_ok());
+        assert!(formatter.config.is_some());
+    }
+
+    #[test]
+    fn estimated_size_grows_with_quality() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 200, 200, 100))) };
+        let input = png(&photo(300, 300));
+        let low = formatter.estimate_size(&input, "photo", 30).unwrap();
+        let high = formatter.estimate_size(&input, "photo", 90).unwrap();
+        assert!(low > 0 && high >= low, "q30 {}KB, q90 {}KB", low, high);
+        assert!(matches!(formatter.estimate_size(&input, "photo", 0), Err(FormatError::InvalidInput(_))));
+    }
+
+    // With `--features parallel` the items run on the rayon pool and finish
+    // in any order; results must still line up with their inputs
+    #[test]
+    fn batch_results_keep_input_order() {
+        let contain = |format| DocumentFormat { fit_mode: "contain".to_string(), ..format };
+        let mut config = exam(contain(spec("JPEG", 120, 160, 100)));
+        config.formats.signature = contain(spec("PNG", 150, 50, 100));
+        let formatter = DocumentFormatter { config: Some(config) };
+        let items: Vec<BatchItem> = (0..8u32)
+            .map(|i| BatchItem {
+                // Larger inputs take longer, so completion order differs from input order
+                data: png(&photo(80 + (8 - i) * 40, 80 + i * 20)),
+                document_type: if i % 2 == 0 { "photo" } else { "signature" }.to_string(),
+                original_name: format!("item{}.png", i),
+            })
+            .collect();
+
+        let results = formatter.format_items(&items);
+        assert_eq!(results.len(), 8);
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.original_name, format!("item{}.png", i));
+            let output = result.result.as_ref().unwrap_or_else(|| panic!("item {}: {:?}", i, result.error));
+            let expected = if i % 2 == 0 { (120, 160, "JPEG") } else { (150, 50, "PNG") };
+            assert_eq!((output.width, output.height, output.format.as_str()), expected, "item {}", i);
+        }
+    }
+
+    #[test]
+    fn thumbnails_fit_the_edge_and_keep_aspect() {
+        for (width, height) in [(640, 480), (300, 900), (100, 60)] {
+            let thumb = image::load_from_memory(&make_thumbnail(&png(&photo(width, height)), 128).unwrap()).unwrap();
+            let (w, h) = thumb.dimensions();
+            assert!(w.max(h) <= 128, "{}x{} became {}x{}", width, height, w, h);
+            assert!(width.max(height) <= 128 || w.max(h) == 128);
+            let expected_h = w as f32 * height as f32 / width as f32;
+            assert!((h as f32 - expected_h).abs() <= 1.0, "{}x{} became {}x{}", width, height, w, h);
+        }
+        assert!(matches!(make_thumbnail(&png(&photo(10, 10)), 0), Err(FormatError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn blank_uploads_are_flagged() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 300, 100, 100))) };
+        let white = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(300, 100, image::Rgb([250, 250, 250])));
+        let report = formatter.validation_report(&png(&white), "signature").unwrap();
+        assert!(report.blank && report.blank_coverage > DEFAULT_BLANK_THRESHOLD);
+        assert!(report.issues.contains(&"This image appears blank".to_string()));
+
+        // A looping stroke across a tenth of the canvas
+        let signature = DynamicImage::ImageRgb8(image::RgbImage::from_fn(300, 100, |x, y| {
+            let stroke = (30..270).contains(&x) && (y as f32 - 50.0 - 25.0 * (x as f32 / 20.0).sin()).abs() < 4.0;
+            if stroke { image::Rgb([20, 20, 60]) } else { image::Rgb([250, 250, 250]) }
+        }));
+        let report = formatter.validation_report(&png(&signature), "signature").unwrap();
+        assert!(!report.blank, "coverage {}", report.blank_coverage);
+        assert!(report.ok, "{:?}", report.issues);
+    }
+
+    #[test]
+    fn gate_config_round_trips_and_is_registered() {
+        let config = lookup_config(" GATE ").unwrap();
+        assert_eq!(config.code, "gate");
+        let config = round_trip(&config);
+        assert_eq!((config.formats.photo.width, config.formats.photo.height), (480, 640));
+        assert_eq!((config.formats.signature.width, config.formats.signature.height), (350, 150));
+        for certificate in ["category_certificate", "pwd_certificate"] {
+            let format = resolve_format(&config, certificate).unwrap();
+            assert_eq!((format.format.as_str(), format.max_size), ("PDF", 300), "{}", certificate);
+        }
+        assert!(validate_config(&config).is_empty());
+        assert!(registered_codes().contains(&"gate".to_string()));
+    }
+
+    #[test]
+    fn gov_presets_match_the_documented_specs() {
+        let photo = gov_photo_format_20kb_to_50kb();
+        assert_eq!((photo.width, photo.height, photo.dpi, photo.format.as_str()), (276, 354, 200, "JPEG"));
+        assert_eq!((photo.min_size, photo.max_size), (Some(20), 50));
+        let signature = gov_signature_format_10kb_to_20kb();
+        assert_eq!((signature.width, signature.height, signature.dpi), (315, 157, 200));
+        assert_eq!((signature.min_size, signature.max_size), (Some(10), 20));
+    }
+
+    #[test]
+    fn ssc_config_uses_the_gov_presets() {
+        let config = round_trip(&lookup_config("ssc").unwrap());
+        assert_eq!((config.name.as_str(), config.code.as_str()), ("SSC", "ssc"));
+        let json = |format: &DocumentFormat| serde_json::to_value(format).unwrap();
+        assert_eq!(json(&config.formats.photo), json(&gov_photo_format_20kb_to_50kb()));
+        assert_eq!(json(&config.formats.signature), json(&gov_signature_format_10kb_to_20kb()));
+        assert_eq!((config.formats.documents.width, config.formats.documents.height), (600, 800));
+        assert!(config.document_types.contains(&"ex_serviceman_certificate".to_string()));
+        assert!(validate_config(&config).is_empty());
+    }
+
+    #[test]
+    fn pads_to_the_requested_aspect_ratio() {
+        let source = photo(301, 200);
+        for (ratio_w, ratio_h) in [(3, 4), (1, 1), (16, 9), (7, 2)] {
+            let padded = pad_to_aspect(&png(&source), ratio_w, ratio_h, "#FFFFFF", "PNG", 85).unwrap();
+            let padded = image::load_from_memory(&padded).unwrap();
+            let (w, h) = (padded.width() as f32, padded.height() as f32);
+            // The image is padded, never shrunk, and off by at most one pixel
+            assert!(padded.width() >= 301 && padded.height() >= 200);
+            assert!((w - h * ratio_w as f32 / ratio_h as f32).abs() <= 1.0, "{}x{} for {}:{}", w, h, ratio_w, ratio_h);
+        }
+
+        let padded = image::load_from_memory(&pad_to_aspect(&png(&source), 1, 1, "#FF0000", "PNG", 85).unwrap()).unwrap();
+        assert_eq!((padded.width(), padded.height()), (301, 301));
+        assert_eq!(padded.to_rgb8().get_pixel(150, 0), &image::Rgb([255, 0, 0]));
+        assert!(matches!(pad_to_aspect(&png(&source), 0, 4, "#FFFFFF", "PNG", 85), Err(FormatError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn size_curve_grows_with_quality() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 600, 800, 500))) };
+        let qualities: Vec<u8> = (10..=100).step_by(10).collect();
+        let points = formatter.size_points(&png(&photo(600, 800)), "photo", &qualities).unwrap();
+        assert_eq!(points.iter().map(|point| point.quality).collect::<Vec<_>>(), qualities);
+        assert!(points.windows(2).all(|pair| pair[0].size_kb <= pair[1].size_kb), "sizes {:?}", points.iter().map(|p| p.size_kb).collect::<Vec<_>>());
+        assert!(points[0].size_kb < points[points.len() - 1].size_kb);
+
+        assert!(matches!(formatter.size_points(&png(&photo(60, 80)), "photo", &[0]), Err(FormatError::InvalidInput(_))));
+    }
+
+    // Messages logged on this thread since the last call
+    fn take_logs() -> Vec<String> {
+        CAPTURED_LOGS.with(|logs| logs.take())
+    }
+
+    #[test]
+    fn log_level_filters_what_is_emitted() {
+        let emit_all = || {
+            console_log!("step");
+            console_debug!("detail");
+            log_error("failed");
+        };
+        // The only test that changes the level, so others never see it move
+        for (level, expected) in [
+            ("off", vec![]),
+            ("error", vec!["error: failed"]),
+            (" INFO ", vec!["step", "error: failed"]),
+            ("debug", vec!["step", "detail", "error: failed"]),
+        ] {
+            set_log_level(level).unwrap();
+            take_logs();
+            emit_all();
+            assert_eq!(take_logs(), expected, "at level {:?}", level);
+        }
+
+        assert!(matches!(set_log_level("verbose"), Err(FormatError::InvalidInput(_))));
+        set_log_level("error").unwrap();
+    }
+
+    fn variant(suffix: &str, width: u32, height: u32, format: &str) -> VariantSpec {
+        VariantSpec { suffix: suffix.to_string(), width, height, format: format.to_string(), quality: 85 }
+    }
+
+    #[test]
+    fn variants_come_out_at_their_own_sizes() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 300, 400, 200))) };
+        let variants = vec![variant("full", 300, 400, "JPEG"), variant("thumb", 75, 100, "PNG")];
+        let outputs = formatter.variant_outputs(&png(&photo(600, 800)), "photo", variants).unwrap();
+
+        assert_eq!(outputs.iter().map(|o| o.suffix.as_str()).collect::<Vec<_>>(), ["full", "thumb"]);
+        for (output, (width, height, format)) in outputs.iter().zip([(300, 400, image::ImageFormat::Jpeg), (75, 100, image::ImageFormat::Png)]) {
+            assert_eq!((output.width, output.height), (width, height));
+            assert_eq!(image::guess_format(&output.data).unwrap(), format);
+            let decoded = image::load_from_memory(&output.data).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (width, height));
+        }
+
+        let empty = formatter.variant_outputs(&png(&photo(60, 80)), "photo", vec![variant("none", 0, 100, "JPEG")]);
+        assert!(matches!(empty, Err(FormatError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn suggests_the_templated_filename() {
+        let upload = png(&photo(600, 800));
+        let options = ProcessingOptions::new("photo", "IMG_2041.png");
+        let config = ExamConfig { filename_template: Some("{examCode}_{docType}.{ext}".to_string()), ..upsc_config() };
+        let output = core::format_with(&config, &upload, &options).unwrap();
+        assert_eq!(output.format, "JPEG");
+        assert_eq!(output.suggested_filename.as_deref(), Some("upsc_photo.jpg"));
+
+        assert_eq!(core::format_with(&upsc_config(), &upload, &options).unwrap().suggested_filename, None);
+    }
+
+    #[test]
+    fn perceptual_diff_scores_similarity() {
+        let img = photo(160, 120);
+        let same = perceptual_diff(&png(&img), &png(&img)).unwrap();
+        assert!((same - 1.0).abs() < 1e-4, "identical images scored {}", same);
+
+        let detailed = noise(160, 120);
+        let mut inverse = detailed.clone();
+        inverse.invert();
+        let inverted = perceptual_diff(&png(&detailed), &png(&inverse)).unwrap();
+        assert!(inverted < 0.2, "an image and its inverse scored {}", inverted);
+
+        // Sizes may differ; a resized copy still scores high
+        let resized = perceptual_diff(&png(&img), &png(&img.resize_exact(80, 60, image::imageops::FilterType::Triangle))).unwrap();
+        assert!(resized > 0.8, "a half-size copy scored {}", resized);
+    }
+
+    #[test]
+    fn build_info_reports_the_crate_version_and_features() {
+        let info = build_info();
+        assert!(!info.version.is_empty());
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.features.contains(&"webp".to_string()), cfg!(feature = "webp"));
+        assert_eq!(info.features.contains(&"parallel".to_string()), cfg!(feature = "parallel"));
+    }
+
+    #[test]
+    fn stitching_adds_up_the_scaled_sides() {
+        let white = Rgba([255, 255, 255, 255]);
+        let (front, back) = (photo(400, 250), photo(200, 150));
+
+        // Widest wins: the back is scaled 2x to 400x300
+        let tall = stitch_decoded(&[front.clone(), back.clone()], true, true, 10, white).unwrap();
+        assert_eq!((tall.width(), tall.height()), (400, 250 + 10 + 300));
+        // Narrowest wins: the front is halved to 200x125
+        let tall = stitch_decoded(&[front.clone(), back.clone()], true, false, 0, white).unwrap();
+        assert_eq!((tall.width(), tall.height()), (200, 125 + 150));
+        // Side by side the heights are matched instead
+        let wide = stitch_decoded(&[front, back], false, false, 4, white).unwrap();
+        assert_eq!((wide.width(), wide.height()), (240 + 4 + 200, 150));
+
+        assert!(matches!(stitch_decoded(&[], true, true, 0, white), Err(FormatError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn registers_custom_exams_at_runtime() {
+        // Codes unique to this test, since the registry is shared
+        let config = ExamConfig { name: "State PSC".to_string(), code: " TNPSC-Test ".to_string(), ..exam(spec("JPEG", 300, 400, 100)) };
+        register_exam_config(config.clone(), false).unwrap();
+        let found = lookup_config("tnpsc-test").unwrap();
+        assert_eq!(found.name, "State PSC");
+        assert!(registered_codes().contains(&"tnpsc-test".to_string()));
+
+        match register_exam_config(config.clone(), false) {
+            Err(FormatError::DuplicateExamCode(code)) => assert_eq!(code, "tnpsc-test"),
+            _ => panic!("re-registering without overwrite should fail"),
+        }
+        // Built-in codes are protected the same way
+        let upsc = ExamConfig { code: "upsc".to_string(), ..config.clone() };
+        assert!(matches!(register_exam_config(upsc, false), Err(FormatError::DuplicateExamCode(_))));
+        assert_eq!(lookup_config("upsc").unwrap().name, "UPSC");
+
+        let renamed = ExamConfig { name: "State PSC 2026".to_string(), ..config.clone() };
+        register_exam_config(renamed, true).unwrap();
+        assert_eq!(lookup_config("tnpsc-test").unwrap().name, "State PSC 2026");
+
+        let broken = ExamConfig { code: "broken-test".to_string(), ..exam(spec("JPEG", 0, 400, 0)) };
+        assert!(matches!(register_exam_config(broken, false), Err(FormatError::ConfigValidation(_))));
+        assert!(lookup_config("broken-test").is_err());
+    }
+
+    #[test]
+    fn previews_carry_the_output_and_a_small_original() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 300, 400, 200))) };
+        let upload = png(&photo(600, 800));
+
+        let preview = formatter.preview_result(&upload, "photo", "photo.png", Some(120)).unwrap();
+        assert_eq!((preview.formatted.width, preview.formatted.height), (300, 400));
+        assert!(image::load_from_memory(&preview.formatted.data).is_ok());
+        let thumbnail = image::load_from_memory(&preview.original_thumbnail).unwrap();
+        assert_eq!(image::guess_format(&preview.original_thumbnail).unwrap(), image::ImageFormat::Jpeg);
+        assert_eq!((thumbnail.width(), thumbnail.height()), (90, 120));
+
+        let preview = formatter.preview_result(&upload, "photo", "photo.png", None).unwrap();
+        let thumbnail = image::load_from_memory(&preview.original_thumbnail).unwrap();
+        assert_eq!(thumbnail.height(), DEFAULT_PREVIEW_EDGE);
+    }
+
+    #[test]
+    fn neet_pg_config_round_trips_with_thumb_impressions() {
+        let config = round_trip(&lookup_config("neet_pg").unwrap());
+        assert_eq!(config.name, "NEET-PG");
+        assert!(config.document_types.contains(&"thumb_impression".to_string()));
+        // The portal takes JPEG and nothing else
+        assert_eq!(config.allowed_formats, ["image/jpeg"]);
+
+        let thumb = resolve_format(&config, "thumb_impression").unwrap();
+        assert_eq!((thumb.width, thumb.height, thumb.min_size, thumb.max_size), (236, 236, Some(10), 100));
+        assert!(thumb.signature_cleanup);
+        assert_eq!((config.formats.photo.width, config.formats.photo.height), (276, 354));
+        assert!(validate_config(&config).is_empty());
+    }
+
+    #[test]
+    fn small_existing_jpegs_are_flagged_as_already_compressed() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 300, 400, 200))) };
+        let flagged = |data: &[u8]| formatter.validation_report(data, "photo").unwrap().is_likely_already_compressed;
+
+        // An earlier portal export: small and saved at quality 75
+        assert!(flagged(&jpeg(&photo(300, 400), 75)));
+        // A camera original at high quality, or a lossless PNG, is fine to format
+        assert!(!flagged(&jpeg(&photo(300, 400), 97)));
+        assert!(!flagged(&png(&photo(300, 400))));
+    }
+
+    #[test]
+    fn metadata_reads_the_declared_density() {
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(40, 30, image::Rgba([10, 20, 30, 200])));
+        let at = |format: &str, dpi: u32| DocumentFormat { dpi, ..spec(format, 40, 30, 100) };
+
+        let jfif = encode_image(&img, &at("JPEG", 300), 80).unwrap();
+        let meta = read_metadata(&jfif).unwrap();
+        assert_eq!((meta.width, meta.height, meta.format.as_str()), (40, 30, "image/jpeg"));
+        assert_eq!((meta.dpi, meta.dpi_y, meta.non_square_dpi), (Some(300), Some(300), false));
+        assert!(!meta.has_alpha && !meta.has_exif);
+        assert_eq!((meta.bit_depth, meta.color_type.as_str()), (8, "rgb8"));
+
+        let phys = encode_image(&img, &at("PNG", 150), 80).unwrap();
+        let meta = read_metadata(&phys).unwrap();
+        assert_eq!((meta.format.as_str(), meta.dpi), ("image/png", Some(150)));
+        assert!(meta.has_alpha);
+        assert_eq!((meta.bit_depth, meta.color_type.as_str()), (8, "rgba8"));
+
+        // The image crate writes no pHYs chunk, so nothing is declared
+        assert_eq!(read_metadata(&png(&img)).unwrap().dpi, None);
+    }
+
+    #[test]
+    fn non_square_dpi_is_flagged_and_corrected() {
+        // A scanner's 300x200 DPI written into the JFIF header
+        let mut scan = jpeg(&photo(150, 100), 90);
+        assert_eq!(&scan[6..11], b"JFIF\0");
+        scan[13] = 1;
+        scan[14..18].copy_from_slice(&[0x01, 0x2C, 0x00, 0xC8]);
+
+        let format = DocumentFormat { downscale_only: true, ..spec("JPEG", 400, 400, 100) };
+        let formatter = DocumentFormatter { config: Some(exam(format.clone())) };
+        let report = formatter.validation_report(&scan, "photo").unwrap();
+        assert_eq!((report.dpi_x, report.dpi_y, report.non_square_dpi), (Some(300), Some(200), true));
+        let meta = read_metadata(&scan).unwrap();
+        assert_eq!((meta.dpi_x, meta.dpi_y, meta.non_square_dpi), (Some(300), Some(200), true));
+
+        let output = run(&exam(format.clone()), &scan, "photo").unwrap();
+        assert_eq!((output.width, output.height), (150, 100));
+
+        // Each row covers 1/200 inch, so the height stretches by 3/2
+        let corrected = DocumentFormat { correct_pixel_aspect: true, ..format };
+        let output = run(&exam(corrected), &scan, "photo").unwrap();
+        assert_eq!((output.width, output.height), (150, 150));
+    }
+
+    #[test]
+    fn overrides_apply_to_one_call_only() {
+        let formatter = DocumentFormatter { config: Some(exam(spec("JPEG", 120, 160, 100))) };
+        let upload = png(&photo(240, 320));
+        let with = |overrides: serde_json::Value| {
+            let options: ProcessingOptions =
+                serde_json::from_value(serde_json::json!({ "document_type": "photo", "original_name": "a.png", "overrides": overrides })).unwrap();
+            formatter.format_internal(&upload, &options)
+        };
+
+        let output = with(serde_json::json!({ "quality": 40, "filter": "nearest" })).unwrap();
+        assert_eq!(output.quality_used, 40);
+        let stored = &formatter.config.as_ref().unwrap().formats.photo;
+        assert_eq!((stored.quality, stored.filter.as_str()), (85, spec("JPEG", 1, 1, 1).filter.as_str()));
+        assert_eq!(formatter.format_internal(&upload, &ProcessingOptions::new("photo", "a.png")).unwrap().quality_used, 85);
+
+        // Overrides are checked like a stored config
+        for bad in [serde_json::json!({ "quality": 0 }), serde_json::json!({ "filter": "sinc" })] {
+            assert!(matches!(with(bad), Err(FormatError::InvalidInput(_))));
+        }
+    }
+
+    #[test]
+    fn neet_marksheets_use_the_board_preset() {
+        let neet = neet_config();
+        for document_type in ["class10_marksheet", "class12_marksheet"] {
+            let format = resolve_format(&neet, document_type).unwrap();
+            assert_eq!((format.width, format.height, format.quality, format.max_size), (707, 1000, 85, 300), "{}", document_type);
+            assert_eq!((format.format.as_str(), format.fit_mode.as_str()), ("JPEG", "contain"));
+        }
+        // Other documents keep the generic profile
+        let documents = resolve_format(&neet, "aadhaar").unwrap();
+        assert_eq!((documents.width, documents.height), (600, 800));
+
+        let scan = run(&neet, &jpeg(&photo(827, 1169), 90), "class12_marksheet").unwrap();
+        assert_eq!((scan.width, scan.height), (707, 1000));
+        assert!(scan.byte_size <= 300 * 1024);
+    }
+
+    #[test]
+    fn spreads_split_at_the_gutter() {
+        // Two pale pages with the fold shadow a little left of center
+        let spread = image::DynamicImage::ImageLuma8(image::GrayImage::from_fn(500, 300, |x, _| {
+            image::Luma([if (228..232).contains(&x) { 60 } else { 235 }])
+        }));
+        let sizes = |pages: Vec<serde_bytes::ByteBuf>| -> Vec<(u32, u32)> {
+            pages.iter().map(|page| image::load_from_memory(page).unwrap().dimensions()).collect()
+        };
+
+        let pages = split_pages(&png(&spread), None, "png", 90).unwrap();
+        let found = sizes(pages);
+        assert_eq!(found.len(), 2);
+        assert!((228..232).contains(&found[0].0), "split at {}", found[0].0);
+        assert_eq!((found[0].0 + found[1].0, found[0].1, found[1].1), (500, 300, 300));
+
+        // No shadow: the midpoint. A manual column wins over either
+        let plain = image::DynamicImage::ImageLuma8(image::GrayImage::from_pixel(500, 300, image::Luma([235])));
+        assert_eq!(sizes(split_pages(&png(&plain), None, "jpeg", 90).unwrap()), [(250, 300), (250, 300)]);
+        assert_eq!(sizes(split_pages(&png(&spread), Some(100), "jpeg", 90).unwrap()), [(100, 300), (400, 300)]);
+        assert!(matches!(split_pages(&png(&spread), Some(500), "jpeg", 90), Err(FormatError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn inline_formats_need_no_stored_config() {
+        let formatter = DocumentFormatter { config: None };
+        let upload = png(&photo(600, 800));
+        assert!(matches!(formatter.format_document(&upload, "photo", "a.png", None), Err(FormatError::ConfigNotSet)));
+
+        // The same JSON shape as one entry of an exam config's formats
+        let inline = |json: serde_json::Value| serde_json::from_value::<DocumentFormat>(json).unwrap();
+        let format = inline(serde_json::json!({ "width": 300, "height": 400, "dpi": 200, "format": "JPEG", "quality": 85, "max_size": 200 }));
+        let output = formatter.format_inline_internal(&upload, "photo", &format).unwrap();
+        assert_eq!((output.width, output.height, output.format.as_str()), (300, 400, "JPEG"));
+        assert!(output.byte_size <= 200 * 1024);
+        assert!(formatter.config.is_none());
+        assert!(serde_json::from_value::<DocumentFormat>(serde_json::json!({ "width": 300, "height": 400 })).is_err());
+
+        // Inputs no exam lists are still accepted
+        let gif = encode(&photo(150, 200), image::ImageOutputFormat::Gif);
+        assert_eq!(formatter.format_inline_internal(&gif, "photo", &format).unwrap().format, "JPEG");
+
+        // 3.5x4.5 cm at 200 DPI
+        let physical = inline(serde_json::json!({
+            "width": 0, "height": 0, "width_cm": 3.5, "height_cm": 4.5, "dpi": 200, "format": "JPEG", "quality": 85, "max_size": 200
+        }));
+        let output = formatter.format_inline_internal(&upload, "photo", &physical).unwrap();
+        assert_eq!((output.width, output.height), (276, 354));
+
+        let invalid = DocumentFormat { quality: 0, ..format };
+        assert!(matches!(
+            formatter.format_inline_internal(&upload, "photo", &invalid),
+            Err(FormatError::ConfigValidation(issues)) if issues == ["photo.quality 0 out of range 1-100"]
+        ));
+    }
+
+    #[test]
+    fn output_formats_map_to_mime_and_extension() {
+        let cases = [
+            ("JPEG", "image/jpeg", "jpg"),
+            ("jpg", "image/jpeg", "jpg"),
+            ("png", "image/png", "png"),
+            ("WebP", "image/webp", "webp"),
+            ("AVIF", "image/avif", "avif"),
+            (" PDF ", "application/pdf", "pdf"),
+        ];
+        for (format, mime, extension) in cases {
+            assert_eq!(output_mime(format).unwrap(), mime, "{}", format);
+            assert_eq!(output_extension(format).unwrap(), extension, "{}", format);
+        }
+        for unknown in ["BMP", ""] {
+            assert_eq!(output_mime(unknown), Err(FormatError::UnknownOutputFormat(unknown.to_string())));
+            assert!(matches!(output_extension(unknown), Err(FormatError::UnknownOutputFormat(_))));
+        }
+    }
 }
-    serde_wasm_bindgen::to_value(&config).unwrap()
-}
\ No newline at end of file
@@ -0,0 +1,127 @@
+// Opt-in EXIF carry-over for `preserve_metadata`.
+//
+// Output is metadata-free by default: the pipeline decodes to raw pixels and
+// the encoders only write the density we set. When a caller does want the
+// source EXIF kept, it is re-serialized from the input with the fields that
+// would now be wrong (orientation, pixel dimensions, the thumbnail) left out.
+
+use exif::experimental::Writer;
+use exif::{In, Tag, Value};
+use std::io::Cursor;
+
+// Tags describing the source pixels rather than the scene
+const DROPPED_TAGS: [Tag; 3] = [Tag::Orientation, Tag::PixelXDimension, Tag::PixelYDimension];
+
+// Primary-image EXIF from the input as a bare TIFF structure
+fn source_exif(input: &[u8]) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(input)).ok()?;
+    let fields: Vec<_> = exif
+        .fields()
+        .filter(|field| field.ifd_num == In::PRIMARY && !DROPPED_TAGS.contains(&field.tag))
+        .collect();
+    if fields.is_empty() {
+        return None;
+    }
+
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, exif.little_endian()).ok()?;
+    Some(tiff.into_inner())
+}
+
+// Whether the input carries any EXIF at all
+pub fn has_exif(input: &[u8]) -> bool {
+    exif::Reader::new().read_from_container(&mut Cursor::new(input)).is_ok()
+}
+
+// Density declared by a JPEG's JFIF header or a PNG's pHYs chunk, in DPI,
+// when it is the same both ways
+pub fn declared_dpi(input: &[u8]) -> Option<u32> {
+    declared_density(input).filter(|(x, y)| x == y).map(|(x, _)| x)
+}
+
+// Horizontal and vertical density from the JFIF header or pHYs chunk, in DPI.
+// Some scanners write different values, e.g. 300x200
+pub fn declared_density(input: &[u8]) -> Option<(u32, u32)> {
+    if let Some([0xFF, 0xD8, 0xFF, 0xE0, _, _, b'J', b'F', b'I', b'F', 0, _, _, unit, xh, xl, yh, yl]) = input.get(..18) {
+        let (x, y) = (u16::from_be_bytes([*xh, *xl]) as f64, u16::from_be_bytes([*yh, *yl]) as f64);
+        return match unit {
+            1 => Some((x as u32, y as u32)),
+            2 => Some(((x * 2.54).round() as u32, (y * 2.54).round() as u32)),
+            _ => None,
+        };
+    }
+
+    // pHYs must come before IDAT, so walk the chunks up to there
+    let mut pos = 8;
+    while let Some(header) = input.get(pos..pos + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        match &header[4..8] {
+            b"pHYs" => {
+                let body = input.get(pos + 8..pos + 17)?;
+                let dpi = |at: usize| (u32::from_be_bytes([body[at], body[at + 1], body[at + 2], body[at + 3]]) as f64 * 0.0254).round() as u32;
+                return (body[8] == 1).then(|| (dpi(0), dpi(4)));
+            }
+            b"IDAT" => return None,
+            _ => pos += 12 + length,
+        }
+    }
+    None
+}
+
+// Density from the EXIF resolution tags, which cameras write in place of JFIF
+pub fn exif_density(input: &[u8]) -> Option<(u32, u32)> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(input)).ok()?;
+    let resolution = |tag: Tag| match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => values.first().map(|value| value.to_f64()),
+        _ => None,
+    };
+    let x = resolution(Tag::XResolution)?;
+    let y = resolution(Tag::YResolution).unwrap_or(x);
+    // TIFF defaults the unit to inches
+    let unit = exif
+        .get_field(Tag::ResolutionUnit, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(2);
+    let to_dpi = |value: f64| match unit {
+        2 => Some(value.round() as u32),
+        3 => Some((value * 2.54).round() as u32),
+        _ => None,
+    };
+    Some((to_dpi(x)?, to_dpi(y)?)).filter(|&(x, y)| x > 0 && y > 0)
+}
+
+// Copy the input's EXIF into encoded JPEG or PNG output; other formats and
+// inputs without EXIF come back unchanged
+pub fn carry_over_exif(input: &[u8], output: Vec<u8>, format: &str) -> Vec<u8> {
+    let Some(tiff) = source_exif(input) else {
+        return output;
+    };
+
+    match format {
+        // APP1 goes straight after SOI and the JFIF APP0 segment
+        "JPEG" if output.starts_with(&[0xFF, 0xD8]) && tiff.len() + 8 <= u16::MAX as usize => {
+            let insert_at = match output.get(2..6) {
+                Some([0xFF, 0xE0, hi, lo]) => 4 + u16::from_be_bytes([*hi, *lo]) as usize,
+                _ => 2,
+            };
+            let mut segment = vec![0xFF, 0xE1];
+            segment.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+            segment.extend_from_slice(b"Exif\0\0");
+            segment.extend_from_slice(&tiff);
+            [&output[..insert_at], &segment, &output[insert_at..]].concat()
+        }
+        // eXIf chunk right after the signature and IHDR
+        "PNG" if output.len() > 33 => {
+            let mut chunk = (tiff.len() as u32).to_be_bytes().to_vec();
+            chunk.extend_from_slice(b"eXIf");
+            chunk.extend_from_slice(&tiff);
+            chunk.extend_from_slice(&crate::core::crc32(&chunk[4..]).to_be_bytes());
+            [&output[..33], &chunk, &output[33..]].concat()
+        }
+        _ => output,
+    }
+}
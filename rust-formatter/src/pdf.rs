@@ -0,0 +1,187 @@
+// Minimal PDF writer: one image per page, placed at its physical size.
+//
+// JPEG images are embedded untouched (DCTDecode) so there is no generational
+// loss; everything else is stored as deflated 8-bit samples (FlateDecode).
+
+use image::{DynamicImage, GenericImageView};
+
+// PDF user space is 72 points per inch
+const POINTS_PER_INCH: f32 = 72.0;
+
+pub const A4: (f32, f32) = (595.28, 841.89);
+pub const LETTER: (f32, f32) = (612.0, 792.0);
+
+pub enum PdfImage {
+    Jpeg { data: Vec<u8>, width: u32, height: u32, components: u8 },
+    Raw { samples: Vec<u8>, width: u32, height: u32, gray: bool },
+}
+
+pub struct PdfPage {
+    pub image: PdfImage,
+    pub dpi: u32,
+}
+
+impl PdfImage {
+    // Reuse JPEG bytes directly when the viewer can decode them as-is
+    pub fn from_encoded(data: &[u8], img: &DynamicImage) -> PdfImage {
+        let (width, height) = img.dimensions();
+        if let Some(components @ (1 | 3)) = jpeg_components(data) {
+            return PdfImage::Jpeg { data: data.to_vec(), width, height, components };
+        }
+
+        let gray = matches!(img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_));
+        let samples = if gray { img.to_luma8().into_raw() } else { img.to_rgb8().into_raw() };
+        PdfImage::Raw { samples, width, height, gray }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            PdfImage::Jpeg { width, height, .. } | PdfImage::Raw { width, height, .. } => (*width, *height),
+        }
+    }
+}
+
+// Component count from the first start-of-frame marker of a JPEG stream
+pub fn jpeg_components(data: &[u8]) -> Option<u8> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        let length = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let is_sof = matches!(marker, 0xC0..=0xCF) && !matches!(marker, 0xC4 | 0xC8 | 0xCC);
+        if is_sof {
+            return data.get(pos + 9).copied();
+        }
+        pos += 2 + length;
+    }
+    None
+}
+
+pub fn write_pdf(pages: &[PdfPage], page_size: (f32, f32)) -> Vec<u8> {
+    let (page_w, page_h) = page_size;
+    let mut out: Vec<u8> = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets: Vec<usize> = Vec::new();
+
+    // Objects 1 and 2 are the catalog and page tree; each page then takes three
+    // consecutive objects: the page, its content stream and its image
+    let page_ids: Vec<usize> = (0..pages.len()).map(|i| 3 + i * 3).collect();
+
+    start_object(&mut out, &mut offsets);
+    out.extend_from_slice(b"<< /Type /Catalog /Pages 2 0 R >>\nendobj\n");
+
+    start_object(&mut out, &mut offsets);
+    let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+    out.extend_from_slice(
+        format!("<< /Type /Pages /Kids [{}] /Count {} >>\nendobj\n", kids.join(" "), pages.len()).as_bytes(),
+    );
+
+    for (page, &page_id) in pages.iter().zip(&page_ids) {
+        let (content_id, image_id) = (page_id + 1, page_id + 2);
+
+        // Natural print size at the page DPI, shrunk to fit the page if needed
+        let (px_w, px_h) = page.image.dimensions();
+        let dpi = page.dpi.max(1) as f32;
+        let mut draw_w = px_w as f32 / dpi * POINTS_PER_INCH;
+        let mut draw_h = px_h as f32 / dpi * POINTS_PER_INCH;
+        let fit = f32::min(1.0, f32::min(page_w / draw_w, page_h / draw_h));
+        draw_w *= fit;
+        draw_h *= fit;
+        let (x, y) = ((page_w - draw_w) / 2.0, (page_h - draw_h) / 2.0);
+
+        start_object(&mut out, &mut offsets);
+        out.extend_from_slice(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] /Resources << /XObject << /Im0 {} 0 R >> >> /Contents {} 0 R >>\nendobj\n",
+                page_w, page_h, image_id, content_id
+            )
+            .as_bytes(),
+        );
+
+        let content = format!("q {:.2} 0 0 {:.2} {:.2} {:.2} cm /Im0 Do Q", draw_w, draw_h, x, y);
+        start_object(&mut out, &mut offsets);
+        write_stream(&mut out, &format!("<< /Length {} >>", content.len()), content.as_bytes());
+
+        start_object(&mut out, &mut offsets);
+        match &page.image {
+            PdfImage::Jpeg { data, width, height, components } => {
+                let color_space = if *components == 1 { "/DeviceGray" } else { "/DeviceRGB" };
+                let dict = format!(
+                    "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>",
+                    width, height, color_space, data.len()
+                );
+                write_stream(&mut out, &dict, data);
+            }
+            PdfImage::Raw { samples, width, height, gray } => {
+                let color_space = if *gray { "/DeviceGray" } else { "/DeviceRGB" };
+                let compressed = miniz_oxide::deflate::compress_to_vec_zlib(samples, 6);
+                let dict = format!(
+                    "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8 /Filter /FlateDecode /Length {} >>",
+                    width, height, color_space, compressed.len()
+                );
+                write_stream(&mut out, &dict, &compressed);
+            }
+        }
+    }
+
+    let xref_offset = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes());
+    for offset in &offsets {
+        out.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+            offsets.len() + 1,
+            xref_offset
+        )
+        .as_bytes(),
+    );
+    out
+}
+
+// Objects are numbered in the order they are written, starting at 1
+fn start_object(out: &mut Vec<u8>, offsets: &mut Vec<usize>) {
+    offsets.push(out.len());
+    out.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+}
+
+fn write_stream(out: &mut Vec<u8>, dict: &str, data: &[u8]) {
+    out.extend_from_slice(dict.as_bytes());
+    out.extend_from_slice(b"\nstream\n");
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\nendstream\nendobj\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{jpeg, photo, png};
+
+    fn count(haystack: &[u8], needle: &[u8]) -> usize {
+        haystack.windows(needle.len()).filter(|window| *window == needle).count()
+    }
+
+    #[test]
+    fn writes_one_page_object_per_image() {
+        let img = photo(60, 80);
+        let pages: Vec<PdfPage> = [jpeg(&img, 80), png(&img), png(&img.grayscale())]
+            .iter()
+            .map(|data| PdfPage { image: PdfImage::from_encoded(data, &image::load_from_memory(data).unwrap()), dpi: 200 })
+            .collect();
+
+        let pdf = write_pdf(&pages, A4);
+        assert!(pdf.starts_with(b"%PDF"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        assert_eq!(count(&pdf, b"/Type /Page "), 3);
+        assert_eq!(count(&pdf, b"/Count 3"), 1);
+        // The JPEG is embedded as-is, the PNGs as deflated samples
+        assert_eq!(count(&pdf, b"/DCTDecode"), 1);
+        assert_eq!(count(&pdf, b"/FlateDecode"), 2);
+    }
+}
@@ -0,0 +1,74 @@
+// PDF page rasterization for the `pdf-render` feature.
+//
+// hayro is a pure-Rust renderer, so it builds for WASM without a C toolchain.
+// It is still a large dependency (it embeds the standard PDF fonts), which is
+// why it stays out of the default build.
+
+use crate::FormatError;
+use hayro::hayro_interpret::InterpreterSettings;
+use hayro::hayro_syntax::Pdf;
+use hayro::vello_cpu::color::palette::css::WHITE;
+use hayro::{PixmapSettings, RenderCache, RenderSettings};
+use image::{DynamicImage, RgbaImage};
+
+// PDF user space is 72 points per inch
+const POINTS_PER_INCH: f32 = 72.0;
+
+// Render one page, counted from zero, onto white at the given DPI
+pub fn render_page(pdf_data: &[u8], page: u32, dpi: u32) -> Result<DynamicImage, FormatError> {
+    let pdf = Pdf::new(pdf_data.to_vec())
+        .map_err(|e| FormatError::DecodeFailed(format!("Unreadable PDF: {:?}", e)))?;
+    let pages = pdf.pages();
+    let page = pages.get(page as usize).ok_or_else(|| {
+        FormatError::InvalidInput(format!("Page {} is out of range; the PDF has {} pages", page, pages.len()))
+    })?;
+
+    let scale = dpi as f32 / POINTS_PER_INCH;
+    let (page_w, page_h) = page.render_dimensions();
+    if page_w * scale > u16::MAX as f32 || page_h * scale > u16::MAX as f32 {
+        return Err(FormatError::InvalidInput(format!(
+            "Page is too large to render at {} DPI",
+            dpi
+        )));
+    }
+
+    let pixmap = hayro::render(
+        page,
+        &RenderCache::new(),
+        &InterpreterSettings::default(),
+        &RenderSettings::default(),
+        &PixmapSettings { x_scale: scale, y_scale: scale, bg_color: WHITE },
+    );
+
+    // The background is opaque, so the premultiplied samples are plain RGBA
+    let (width, height) = (pixmap.width() as u32, pixmap.height() as u32);
+    let rgba = RgbaImage::from_raw(width, height, pixmap.data_as_u8_slice().to_vec())
+        .ok_or_else(|| FormatError::DecodeFailed("Rendered page has an unexpected size".to_string()))?;
+    Ok(DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(rgba).to_rgb8()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pdf::{self, PdfImage, PdfPage};
+    use crate::test_support::png;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn renders_a_one_page_pdf() {
+        // A black 2-inch square on an otherwise empty A4 page
+        let square = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 400, Rgb([0, 0, 0])));
+        let page = PdfPage { image: PdfImage::from_encoded(&png(&square), &square), dpi: 200 };
+        let data = pdf::write_pdf(&[page], pdf::A4);
+
+        let rendered = render_page(&data, 0, 72).unwrap().to_rgb8();
+        assert_eq!(rendered.width(), pdf::A4.0 as u32);
+        assert_eq!(rendered.height(), pdf::A4.1 as u32);
+        let dark = rendered.pixels().filter(|p| p.0.iter().all(|&c| c < 64)).count();
+        // 2 inches at 72 DPI is 144 px on a side
+        assert!((dark as i64 - 144 * 144).abs() < 144 * 8, "{} dark pixels", dark);
+        assert_eq!(rendered.get_pixel(0, 0), &Rgb([255, 255, 255]));
+
+        assert!(matches!(render_page(&data, 1, 72), Err(FormatError::InvalidInput(_))));
+    }
+}
@@ -0,0 +1,210 @@
+// Opt-in lossless squeeze of encoded PNGs for `png_optimize`.
+//
+// The png crate favors speed, so the image data is unfiltered, refiltered
+// with each row-filter strategy and deflated at miniz's strongest level; the
+// smallest stream wins. Everything is pure Rust and runs in WASM, but it is
+// several encodes' worth of work, hence off by default.
+
+use miniz_oxide::deflate::compress_to_vec_zlib;
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+
+// miniz's "uber" level, beyond zlib's 9
+const DEFLATE_LEVEL: u8 = 10;
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+// Per-row filter choices tried: each fixed filter, then a per-row pick
+// with the smallest sum of absolute differences
+const FIXED_FILTERS: [u8; 5] = [0, 1, 2, 3, 4];
+
+// The same PNG with its image data recompressed as small as we can get it.
+// Interlaced or malformed files, and files we cannot improve, come back as is
+pub fn optimize(data: &[u8]) -> Vec<u8> {
+    try_optimize(data).filter(|optimized| optimized.len() < data.len()).unwrap_or_else(|| data.to_vec())
+}
+
+fn try_optimize(data: &[u8]) -> Option<Vec<u8>> {
+    let chunks = chunks(data)?;
+    let (_, header) = chunks.first().filter(|(kind, body)| kind == b"IHDR" && body.len() == 13)?.clone();
+    let width = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let height = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let (depth, color_type, interlace) = (header[8] as usize, header[9], header[12]);
+    if interlace != 0 {
+        return None;
+    }
+    let channels = match color_type {
+        0 | 3 => 1,
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => return None,
+    };
+    let bits = width * channels * depth;
+    let stride = bits.div_ceil(8);
+    // Filters look back one whole pixel, or one byte below 8 bits
+    let bpp = (channels * depth).div_ceil(8).max(1);
+
+    let compressed: Vec<u8> = chunks.iter().filter(|(kind, _)| kind == b"IDAT").flat_map(|(_, body)| body.iter().copied()).collect();
+    let filtered = decompress_to_vec_zlib(&compressed).ok()?;
+    if filtered.len() != height * (stride + 1) {
+        return None;
+    }
+    let rows = unfilter(&filtered, stride, bpp)?;
+
+    let best = FIXED_FILTERS
+        .iter()
+        .map(|&filter| refilter(&rows, stride, bpp, Some(filter)))
+        .chain(std::iter::once(refilter(&rows, stride, bpp, None)))
+        .map(|stream| compress_to_vec_zlib(&stream, DEFLATE_LEVEL))
+        .min_by_key(Vec::len)?;
+
+    // Everything before the image data stays in place; the data goes in one chunk
+    let mut output = SIGNATURE.to_vec();
+    let mut wrote_data = false;
+    for (kind, body) in &chunks {
+        if kind == b"IDAT" {
+            if !wrote_data {
+                write_chunk(&mut output, b"IDAT", &best);
+                wrote_data = true;
+            }
+        } else {
+            write_chunk(&mut output, kind, body);
+        }
+    }
+    Some(output)
+}
+
+fn chunks(data: &[u8]) -> Option<Vec<([u8; 4], Vec<u8>)>> {
+    let mut pos = SIGNATURE.len();
+    if !data.starts_with(SIGNATURE) {
+        return None;
+    }
+    let mut chunks = Vec::new();
+    while let Some(header) = data.get(pos..pos + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let body = data.get(pos + 8..pos + 8 + length)?;
+        chunks.push((kind, body.to_vec()));
+        pos += 12 + length;
+        if &kind == b"IEND" {
+            return Some(chunks);
+        }
+    }
+    None
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    output.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = output.len();
+    output.extend_from_slice(kind);
+    output.extend_from_slice(body);
+    let crc = crate::core::crc32(&output[start..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Raw scanlines from the filtered stream, each `stride` bytes
+fn unfilter(filtered: &[u8], stride: usize, bpp: usize) -> Option<Vec<u8>> {
+    let height = filtered.len() / (stride + 1);
+    let mut rows = vec![0u8; height * stride];
+    for y in 0..height {
+        let filter = filtered[y * (stride + 1)];
+        let line = &filtered[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, rest) = rows.split_at_mut(y * stride);
+        let previous = (y > 0).then(|| &done[(y - 1) * stride..]);
+        let row = &mut rest[..stride];
+        for x in 0..stride {
+            let left = if x >= bpp { row[x - bpp] } else { 0 };
+            let up = previous.map_or(0, |p| p[x]);
+            let up_left = if x >= bpp { previous.map_or(0, |p| p[x - bpp]) } else { 0 };
+            row[x] = match filter {
+                0 => line[x],
+                1 => line[x].wrapping_add(left),
+                2 => line[x].wrapping_add(up),
+                3 => line[x].wrapping_add(((left as u16 + up as u16) / 2) as u8),
+                4 => line[x].wrapping_add(paeth(left, up, up_left)),
+                _ => return None,
+            };
+        }
+    }
+    Some(rows)
+}
+
+// Filter every row with `filter`, or with its cheapest filter when None
+fn refilter(rows: &[u8], stride: usize, bpp: usize, filter: Option<u8>) -> Vec<u8> {
+    let height = rows.len() / stride;
+    let mut stream = Vec::with_capacity(height * (stride + 1));
+    let mut line = vec![0u8; stride];
+    for y in 0..height {
+        let row = &rows[y * stride..(y + 1) * stride];
+        let previous = (y > 0).then(|| &rows[(y - 1) * stride..y * stride]);
+        let chosen = match filter {
+            Some(filter) => filter,
+            None => *FIXED_FILTERS
+                .iter()
+                .min_by_key(|&&candidate| {
+                    apply_filter(row, previous, bpp, candidate, &mut line);
+                    line.iter().map(|&b| (b as i8).unsigned_abs() as u32).sum::<u32>()
+                })
+                .unwrap_or(&0),
+        };
+        apply_filter(row, previous, bpp, chosen, &mut line);
+        stream.push(chosen);
+        stream.extend_from_slice(&line);
+    }
+    stream
+}
+
+fn apply_filter(row: &[u8], previous: Option<&[u8]>, bpp: usize, filter: u8, line: &mut [u8]) {
+    for x in 0..row.len() {
+        let left = if x >= bpp { row[x - bpp] } else { 0 };
+        let up = previous.map_or(0, |p| p[x]);
+        let up_left = if x >= bpp { previous.map_or(0, |p| p[x - bpp]) } else { 0 };
+        line[x] = match filter {
+            1 => row[x].wrapping_sub(left),
+            2 => row[x].wrapping_sub(up),
+            3 => row[x].wrapping_sub(((left as u16 + up as u16) / 2) as u8),
+            4 => row[x].wrapping_sub(paeth(left, up, up_left)),
+            _ => row[x],
+        };
+    }
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (to_left, to_up, to_up_left) =
+        ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if to_left <= to_up && to_left <= to_up_left {
+        left
+    } else if to_up <= to_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::encode_image;
+    use crate::test_support::{photo, spec};
+    use crate::DocumentFormat;
+
+    #[test]
+    fn optimized_pngs_are_smaller_and_decode_the_same() {
+        let img = photo(200, 150);
+        let plain = encode_image(&img, &spec("PNG", 200, 150, 500), 85).unwrap();
+        let optimized = encode_image(&img, &DocumentFormat { png_optimize: true, ..spec("PNG", 200, 150, 500) }, 85).unwrap();
+        assert!(optimized.len() < plain.len(), "{} vs {} bytes", optimized.len(), plain.len());
+
+        let (before, after) = (image::load_from_memory(&plain).unwrap(), image::load_from_memory(&optimized).unwrap());
+        assert_eq!(before.color(), after.color());
+        assert_eq!(before.as_bytes(), after.as_bytes());
+    }
+
+    #[test]
+    fn files_it_cannot_read_come_back_unchanged() {
+        assert_eq!(optimize(b"not a png"), b"not a png");
+        let truncated = &encode_image(&photo(40, 30), &spec("PNG", 40, 30, 500), 85).unwrap()[..60];
+        assert_eq!(optimize(truncated), truncated);
+    }
+}
@@ -0,0 +1,97 @@
+// Fixture builders shared by the unit tests. Images are generated rather
+// than checked in, so each test shows exactly what it feeds the pipeline.
+
+use crate::core::{self, FormatOutput};
+use crate::{DocumentFormat, ExamConfig, ExamFormats, FormatError, ProcessingOptions};
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+pub fn encode(img: &DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+    let mut data = Vec::new();
+    img.write_to(&mut Cursor::new(&mut data), format).unwrap();
+    data
+}
+
+pub fn png(img: &DynamicImage) -> Vec<u8> {
+    encode(img, ImageOutputFormat::Png)
+}
+
+pub fn jpeg(img: &DynamicImage, quality: u8) -> Vec<u8> {
+    encode(img, ImageOutputFormat::Jpeg(quality))
+}
+
+// `jpeg` with an EXIF APP1 segment holding `fields` right after SOI
+pub fn with_exif(jpeg: Vec<u8>, fields: &[exif::Field]) -> Vec<u8> {
+    let mut writer = exif::experimental::Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).unwrap();
+    let tiff = tiff.into_inner();
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+    segment.extend_from_slice(b"Exif\0\0");
+    segment.extend_from_slice(&tiff);
+    [&jpeg[..2], &segment, &jpeg[2..]].concat()
+}
+
+pub fn orientation(value: u16) -> exif::Field {
+    exif::Field { tag: exif::Tag::Orientation, ifd_num: exif::In::PRIMARY, value: exif::Value::Short(vec![value]) }
+}
+
+// Smooth gradient with a little deterministic noise, like a photo
+pub fn photo(width: u32, height: u32) -> DynamicImage {
+    let mut seed = 1u32;
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let noise = (seed >> 16) as u8 % 16;
+        Rgb([(x * 200 / width) as u8 + noise, (y * 200 / height) as u8 + noise, 120 + noise])
+    }))
+}
+
+// Every pixel random, so no encoder finds anything to save
+pub fn noise(width: u32, height: u32) -> DynamicImage {
+    let mut seed = 7u32;
+    DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |_, _| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let [r, g, b, _] = seed.to_be_bytes();
+        Rgb([r, g, b])
+    }))
+}
+
+// Mean absolute luma step between horizontal neighbors, a rough sharpness measure
+pub fn gradient_energy(img: &DynamicImage) -> f32 {
+    let gray = img.to_luma8();
+    let steps: u64 = gray.rows().map(|row| {
+        let row: Vec<i32> = row.map(|p| p[0] as i32).collect();
+        row.windows(2).map(|pair| pair[0].abs_diff(pair[1]) as u64).sum::<u64>()
+    }).sum();
+    steps as f32 / (gray.width().saturating_sub(1) * gray.height()).max(1) as f32
+}
+
+pub fn spec(format: &str, width: u32, height: u32, max_size: u32) -> DocumentFormat {
+    DocumentFormat { width, height, dpi: 200, quality: 85, max_size, format: format.to_string(), ..Default::default() }
+}
+
+// Exam using `format` for every document type, with room to spare on size
+pub fn exam(format: DocumentFormat) -> ExamConfig {
+    ExamConfig {
+        name: "Test".to_string(),
+        code: "test".to_string(),
+        formats: ExamFormats { photo: format.clone(), signature: format.clone(), documents: format },
+        max_file_size: 10_000,
+        allowed_formats: vec!["image/jpeg".to_string(), "image/png".to_string()],
+        document_types: vec!["photo".to_string(), "signature".to_string(), "documents".to_string()],
+        document_type_formats: HashMap::new(),
+        max_megapixels: 50,
+        filename_template: None,
+        allow_animated: true,
+    }
+}
+
+pub fn run(config: &ExamConfig, data: &[u8], document_type: &str) -> Result<FormatOutput, FormatError> {
+    core::format_with(config, data, &ProcessingOptions::new(document_type, "upload"))
+}
@@ -0,0 +1,116 @@
+// Diagonal text stamp ("For <Exam> Application Only") across document copies.
+//
+// The font is compiled in so stamping works offline; it is only built with the
+// `watermark` feature because it adds ~700KB to the WASM binary.
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{DynamicImage, Rgba, RgbaImage};
+
+const FONT: &[u8] = include_bytes!("fonts/DejaVuSans-Bold.ttf");
+
+// The text line spans this share of the image diagonal
+const DIAGONAL_SPAN: f32 = 0.7;
+// ...but is never taller than this share of it, so short text stays legible
+const MAX_LINE_HEIGHT: f32 = 0.12;
+const INK: [u8; 3] = [96, 96, 96];
+
+// Lay the text out in gray at `opacity` along the bottom-left to top-right
+// diagonal. Pixels away from the text are left exactly as they were
+pub fn stamp(img: &DynamicImage, text: &str, opacity: f32) -> DynamicImage {
+    let font = FontRef::try_from_slice(FONT).expect("embedded font is valid");
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let diagonal = w.hypot(h);
+
+    // Measure at a reference size, then scale to the target span
+    let reference = 100.0;
+    let line_width = measure(&font, text, reference);
+    if line_width <= 0.0 {
+        return img.clone();
+    }
+    let size = f32::min(reference * diagonal * DIAGONAL_SPAN / line_width, diagonal * MAX_LINE_HEIGHT);
+
+    let mask = render_line(&font, text, size, opacity);
+    let angle = h.atan2(w).to_degrees();
+    let rotated = crate::enhance::rotate(&DynamicImage::ImageRgba8(mask), -angle, Rgba([INK[0], INK[1], INK[2], 0]), true).to_rgba8();
+
+    let mut canvas = img.to_rgba8();
+    let x = (canvas.width() as i64 - rotated.width() as i64) / 2;
+    let y = (canvas.height() as i64 - rotated.height() as i64) / 2;
+    image::imageops::overlay(&mut canvas, &rotated, x, y);
+
+    match img {
+        DynamicImage::ImageLuma8(_) => DynamicImage::ImageLuma8(DynamicImage::ImageRgba8(canvas).to_luma8()),
+        _ => DynamicImage::ImageRgba8(canvas),
+    }
+}
+
+fn measure(font: &FontRef, text: &str, size: f32) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            width += scaled.kern(previous, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+// One line of text as ink-colored pixels whose alpha is the glyph coverage
+fn render_line(font: &FontRef, text: &str, size: f32, opacity: f32) -> RgbaImage {
+    let scaled = font.as_scaled(PxScale::from(size));
+    let width = measure(font, text, size).ceil() as u32 + 2;
+    let height = scaled.height().ceil() as u32 + 2;
+    let mut line = RgbaImage::from_pixel(width, height, Rgba([INK[0], INK[1], INK[2], 0]));
+
+    let mut caret = 1.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            caret += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(size, point(caret, 1.0 + scaled.ascent()));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let x = bounds.min.x as i64 + gx as i64;
+            let y = bounds.min.y as i64 + gy as i64;
+            if (0..width as i64).contains(&x) && (0..height as i64).contains(&y) {
+                let pixel = line.get_pixel_mut(x as u32, y as u32);
+                let alpha = (coverage.min(1.0) * opacity * 255.0).round() as u8;
+                pixel[3] = pixel[3].max(alpha);
+            }
+        });
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn stamps_the_diagonal_and_leaves_the_corners() {
+        let page = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 300, Rgb([255, 255, 255])));
+        let marked = stamp(&page, "VERIFIED COPY", 0.5).to_rgb8();
+        assert_eq!(marked.dimensions(), (400, 300));
+
+        let changed = |x0: u32, y0: u32, x1: u32, y1: u32| {
+            (y0..y1).flat_map(|y| (x0..x1).map(move |x| (x, y))).filter(|&(x, y)| marked.get_pixel(x, y) != &Rgb([255, 255, 255])).count()
+        };
+        assert!(changed(150, 100, 250, 200) > 200, "the middle of the diagonal should carry text");
+        // The text runs bottom-left to top-right, well clear of these corners
+        assert_eq!(changed(0, 0, 60, 60), 0);
+        assert_eq!(changed(340, 240, 400, 300), 0);
+    }
+}
@@ -0,0 +1,82 @@
+// Drives the pipeline through `core` as a native caller would: no JS, no
+// wasm-bindgen glue, only the public Rust API.
+
+use document_formatter::core;
+use document_formatter::{DocumentFormat, FormatError};
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::rc::Rc;
+
+fn png_bytes(img: RgbImage) -> Vec<u8> {
+    let mut data = Vec::new();
+    DynamicImage::ImageRgb8(img).write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png).unwrap();
+    data
+}
+
+// Smooth gradient with a little deterministic noise, like a photo
+fn photo(width: u32, height: u32) -> Vec<u8> {
+    let mut seed = 1u32;
+    png_bytes(RgbImage::from_fn(width, height, |x, y| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let noise = (seed >> 16) as u8 % 16;
+        Rgb([(x * 200 / width) as u8 + noise, (y * 200 / height) as u8 + noise, 120 + noise])
+    }))
+}
+
+fn jpeg_spec(width: u32, height: u32, max_size: u32) -> DocumentFormat {
+    DocumentFormat { width, height, dpi: 200, quality: 85, max_size, format: "JPEG".to_string(), ..Default::default() }
+}
+
+#[test]
+fn formats_png_to_sized_jpeg() {
+    let output = core::format(&photo(600, 800), &jpeg_spec(300, 400, 200), "photo").unwrap();
+    assert_eq!((output.width, output.height), (300, 400));
+    assert_eq!(output.format, "JPEG");
+    assert!(output.data.starts_with(&[0xFF, 0xD8]));
+    assert_eq!(output.byte_size as usize, output.data.len());
+    let decoded = image::load_from_memory(&output.data).unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (300, 400));
+}
+
+#[test]
+fn meets_a_tight_size_cap() {
+    let output = core::format(&photo(1200, 1600), &jpeg_spec(600, 800, 20), "photo").unwrap();
+    assert!(output.data.len() <= 20 * 1024, "{} bytes", output.data.len());
+    assert!(output.was_compressed);
+    assert!(output.quality_used < 85);
+}
+
+#[test]
+fn converts_physical_size_to_pixels() {
+    let spec = DocumentFormat { width: 0, height: 0, width_cm: Some(3.5), height_cm: Some(4.5), ..jpeg_spec(0, 0, 200) };
+    let output = core::format(&photo(600, 800), &spec, "photo").unwrap();
+    assert_eq!((output.width, output.height), (276, 354));
+}
+
+#[test]
+fn rejects_an_invalid_spec() {
+    let spec = DocumentFormat { format: "BMP".to_string(), ..jpeg_spec(300, 400, 200) };
+    assert!(matches!(core::format(&photo(60, 80), &spec, "photo"), Err(FormatError::ConfigValidation(_))));
+}
+
+#[test]
+fn rejects_undecodable_input() {
+    let result = core::format(b"definitely not an image", &jpeg_spec(300, 400, 200), "photo");
+    assert!(matches!(result, Err(FormatError::UnrecognizedFormat)));
+}
+
+#[test]
+fn reports_progress_to_a_rust_callback() {
+    let stages = Rc::new(RefCell::new(Vec::new()));
+    let seen = Rc::clone(&stages);
+    let progress = Box::new(move |stage: &str, percent: f32, _quality: Option<u8>| {
+        seen.borrow_mut().push((stage.to_string(), percent));
+    });
+    core::format_with_progress(&photo(600, 800), &jpeg_spec(300, 400, 200), "photo", progress).unwrap();
+
+    let stages = stages.borrow();
+    assert_eq!(stages.first().map(|(stage, _)| stage.as_str()), Some("decoding"));
+    assert_eq!(stages.last().map(|(stage, _)| stage.as_str()), Some("done"));
+    assert!(stages.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+}